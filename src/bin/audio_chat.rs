@@ -1,17 +1,19 @@
 use anyhow::Context;
-use async_openai::{types::CreateTranscriptionRequestArgs, Client};
+use async_openai::types::CreateTranscriptionRequestArgs;
 use chatty::{
-    chat_manager,
+    chat_manager::{self, TitleGenerator},
     configuration::AppConfig,
+    models::ModelRegistry,
     mqtt::start_mqtt_service,
-    utils::{
-        generate_system_instructions, QUESTION_MARK_EMOJI, ROBOT_EMOJI,
-        VOICE_TO_TEXT_TRANSCRIBE_MODEL,
-    },
+    personas::{PersonaRegistry, DEFAULT_PERSONA_NAME},
+    retry::{openai_client, transcribe, RetryPolicy, RetryingChatBackend},
+    usage_ledger::UsageLedger,
+    utils::{QUESTION_MARK_EMOJI, ROBOT_EMOJI, VOICE_TO_TEXT_TRANSCRIBE_MODEL},
 };
 use clap::Parser;
 use dialoguer::console::Term;
 use rumqttc::QoS;
+use std::sync::Arc;
 
 #[derive(Parser, Debug)]
 #[command()]
@@ -48,7 +50,7 @@ async fn main() -> anyhow::Result<()> {
         // this is a meh way to do this
         let config_new = AppConfig {
             open_ai_api_key: String::from("EMPTY_TOKEN"),
-            mqtt: None,
+            ..Default::default()
         };
         config_new.save_user_config()?;
         return Ok(());
@@ -63,13 +65,25 @@ async fn main() -> anyhow::Result<()> {
 
     let config = AppConfig::load_user_config()?;
 
-    let client = Client::new().with_api_key(&config.open_ai_api_key);
+    let client = openai_client(&config.open_ai_api_key);
+    let retry_policy = RetryPolicy::new(config.retry);
+    let backend = RetryingChatBackend::new(client.clone(), retry_policy);
+    let model_registry = ModelRegistry::from_config(&config);
+    let model = model_registry.default_model()?.clone();
+    let title_model = model_registry.title_model()?.cloned();
 
     let mqtt_client = start_mqtt_service(&config.mqtt.context("mqtt config missing")?)?;
 
-    let system_messages = generate_system_instructions();
+    let system_message = PersonaRegistry::load_default()?
+        .get(DEFAULT_PERSONA_NAME)?
+        .system_message(&model)?;
 
-    let mut chat_manager = chat_manager::ChatHistory::new(&system_messages["joi"])?;
+    let usage_ledger = UsageLedger::open_default()?;
+
+    let mut chat_manager = chat_manager::ChatHistory::new(&system_message)?;
+    chat_manager.set_model(model);
+    chat_manager.set_usage_ledger(usage_ledger.clone());
+    chat_manager.set_title_generator(TitleGenerator::new(Arc::new(backend.clone()), title_model));
 
     let term = Term::stdout();
 
@@ -80,25 +94,25 @@ async fn main() -> anyhow::Result<()> {
         term.write_line("Transcribing\n")?;
 
         let request = CreateTranscriptionRequestArgs::default()
-            .file(audio_path)
+            .file(audio_path.clone())
             .model(VOICE_TO_TEXT_TRANSCRIBE_MODEL)
             .build()?;
 
-        let response = client.audio().transcribe(request).await?;
-        let user_question = response.text;
+        let user_question = transcribe(&client, &retry_policy, request).await?;
+        usage_ledger.record_transcription(VOICE_TO_TEXT_TRANSCRIBE_MODEL, &audio_path);
 
         term.write_line(&format!("{QUESTION_MARK_EMOJI} Question:\n{user_question}"))?;
 
         term.write_line(&format!("\n{ROBOT_EMOJI} ChatGPT:\n"))?;
 
         let response = if cli.disable_streaming {
-            let response = chat_manager.next_message(&user_question, &client).await?;
+            let response = chat_manager.next_message(&user_question, &backend).await?;
             term.write_line(&response)?;
             term.write_line("")?;
             response
         } else {
             chat_manager
-                .next_message_stream_stdout(&user_question, &client, &term, None)
+                .next_message_stream_stdout(&user_question, &backend, &term, None)
                 .await?
         };
 
@@ -109,6 +123,7 @@ async fn main() -> anyhow::Result<()> {
         }
 
         if !cli.no_save {
+            chat_manager.poll_title();
             chat_manager.save_to_file()?;
         }
 
@@ -1,29 +1,38 @@
 use std::path::PathBuf;
 
 use anyhow::Context;
-use async_openai::{types::CreateTranscriptionRequestArgs, Client};
+use async_openai::types::CreateTranscriptionRequestArgs;
 use base64::{engine::general_purpose, Engine};
 use chatty::{
-    chat_manager::{self, MqttChatStreamDisplay},
-    configuration::AppConfig,
-    mqtt::start_mqtt_service_with_subs,
+    chat_manager::{self, TitleGenerator},
+    configuration::{AppConfig, RetentionConfig},
+    conversation_files::ConversationLocation,
+    conversation_storage::ConversationStorage,
+    models::ModelRegistry,
+    mqtt::{start_mqtt_service_with_subs, MqttChatStreamDisplay, MqttMessageDisplay},
+    retry::{openai_client, transcribe, RetryPolicy, RetryingChatBackend},
+    stream_display::{ChatStreamSinks, SentenceChunker},
+    tools::Tool,
+    usage_ledger::UsageLedger,
     utils::{
-        now_rfc3339, CHAT_GPT_MODEL_TOKEN_LIMIT, INCREASING_TREND_EMOJI, QUESTION_MARK_EMOJI,
-        ROBOT_EMOJI, VOICE_TO_TEXT_TRANSCRIBE_MODEL,
-        VOICE_TO_TEXT_TRANSCRIBE_MODEL_ENGLISH_LANGUAGE,
+        now_rfc3339, INCREASING_TREND_EMOJI, QUESTION_MARK_EMOJI, ROBOT_EMOJI,
+        VOICE_TO_TEXT_TRANSCRIBE_MODEL, VOICE_TO_TEXT_TRANSCRIBE_MODEL_ENGLISH_LANGUAGE,
     },
 };
 use clap::Parser;
 use dialoguer::console::{style, Term};
-use rumqttc::QoS;
-use schemars::{schema_for, JsonSchema};
+use rumqttc::{AsyncClient, QoS};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use tempdir::TempDir;
+use tokio::sync::Mutex;
 
 const SMART_HOME_MQTT_TOPIC: &str = "chatty/home_state/simple/v2";
 const SMART_HOME_VOICE_COMMAND: &str = "chatty/audio_command/simple";
 const SMART_HOME_RESET_CHAT_MANAGER_COMMAND: &str = "chatty/audio_command/reset_chat_manager";
 const SMART_HOME_TEXT_OUTPUT_TOPIC: &str = "chatty/audio_command/response/transcript";
+const SMART_HOME_SPEAK_TOPIC: &str = "home_speak/say/cheerful";
 
 #[derive(Parser, Debug)]
 #[command()]
@@ -63,7 +72,7 @@ async fn main() -> anyhow::Result<()> {
         // this is a meh way to do this
         let config_new = AppConfig {
             open_ai_api_key: String::from("EMPTY_TOKEN"),
-            mqtt: None,
+            ..Default::default()
         };
         config_new.save_user_config()?;
         return Ok(());
@@ -82,7 +91,18 @@ async fn main() -> anyhow::Result<()> {
         AppConfig::load_user_config()?
     };
 
-    let client = Client::new().with_api_key(&config.open_ai_api_key);
+    let client = openai_client(&config.open_ai_api_key);
+    let retry_policy = RetryPolicy::new(config.retry);
+    let backend = RetryingChatBackend::new(client.clone(), retry_policy);
+
+    let model_registry = ModelRegistry::from_config(&config);
+    let model = model_registry.default_model()?.clone();
+    let title_generator = TitleGenerator::new(
+        Arc::new(backend.clone()),
+        model_registry.title_model()?.cloned(),
+    );
+
+    let retention = config.retention.clone();
 
     let mut mqtt_config = config.mqtt.context("mqtt config missing")?.clone();
     mqtt_config.client_id = String::from("smart_home_mqtt_server");
@@ -97,33 +117,66 @@ async fn main() -> anyhow::Result<()> {
     )
     .await?;
 
-    let smart_home_state_schema = schema_for!(SmartHomeState);
-    let smart_home_state_schema_json = serde_json::to_string(&smart_home_state_schema)?;
-
-    let system_messages = format!(
+    let system_messages = String::from(
         "You are an AI assistant who can answer knowledge questions and is in charge of a smart home. Each message will start with
 json of the current home status followed by a user request.
-Respond with minified json of the updated smart home state followed by a message for the user.
-Schema for smart home state is {smart_home_state_schema_json}.
-Message for user should be prefaced with a line that says \"MESSAGE:\""
+Use the set_smart_home_state tool to change the smart home state and then respond with a short message for the user.",
     );
 
-    let mut chat_manager = chat_manager::ChatHistory::new(&system_messages)?;
+    let smart_home_state = Arc::new(Mutex::new(SmartHomeState::default()));
+    let set_state_tool = set_smart_home_state_tool(smart_home_state.clone(), mqtt_client.clone());
+
+    let usage_ledger = UsageLedger::open_default()?;
+
+    // deterministic tool calls unless config says otherwise
+    let mut sampling = config.sampling.clone();
+    sampling.temperature.get_or_insert(0.0);
+
+    let create_chat_manager = || -> anyhow::Result<chat_manager::ChatHistory> {
+        let mut chat_manager = chat_manager::ChatHistory::new(&system_messages)?;
+        chat_manager.set_model(model.clone());
+        chat_manager.set_context_window_config(config.context_window);
+        chat_manager.set_sampling_parameters(sampling.clone())?;
+        chat_manager.set_usage_ledger(usage_ledger.clone());
+        chat_manager.set_title_generator(title_generator.clone());
+        chat_manager.register_tool(set_state_tool.clone());
+        Ok(chat_manager)
+    };
+
+    let mut chat_manager = create_chat_manager()?;
 
     let term = Term::stdout();
 
+    let mut sinks = ChatStreamSinks::new().with(MqttChatStreamDisplay::new(
+        SMART_HOME_TEXT_OUTPUT_TOPIC,
+        mqtt_client.clone(),
+    ));
+    if !cli.mute {
+        // speak every sentence as soon as it is complete
+        sinks.add(SentenceChunker::new(MqttMessageDisplay::new(
+            SMART_HOME_SPEAK_TOPIC,
+            mqtt_client.clone(),
+        )));
+    }
+
     term.write_line(&system_messages)?;
 
-    let mut smart_home_state = SmartHomeState::default();
+    let mut storage = ConversationStorage::open(config.storage)?;
+    if !cli.no_save {
+        prune_saved_conversations(&mut storage, &retention, None, &term)?;
+    }
 
     while let Some(message) = message_receiver.recv().await {
         match message.topic.as_ref() {
             SMART_HOME_MQTT_TOPIC => {
-                smart_home_state = SmartHomeState::from_json_slice(&message.payload)?;
+                *smart_home_state.lock().await = SmartHomeState::from_json_slice(&message.payload)?;
             }
             SMART_HOME_RESET_CHAT_MANAGER_COMMAND => {
                 term.write_line("Resetting chat manager")?;
-                chat_manager = chat_manager::ChatHistory::new(&system_messages)?;
+                if !cli.no_save && chat_manager.wait_for_title().await.is_some() {
+                    storage.save(&mut chat_manager)?;
+                }
+                chat_manager = create_chat_manager()?;
             }
             SMART_HOME_VOICE_COMMAND => {
                 let message: AudioMessage =
@@ -136,19 +189,32 @@ Message for user should be prefaced with a line that says \"MESSAGE:\""
                 std::fs::write(&temp_auido_file, &decoded_file)?;
 
                 let request = CreateTranscriptionRequestArgs::default()
-                    .file(temp_auido_file)
+                    .file(temp_auido_file.clone())
                     .model(VOICE_TO_TEXT_TRANSCRIBE_MODEL)
                     .language(VOICE_TO_TEXT_TRANSCRIBE_MODEL_ENGLISH_LANGUAGE)
                     .prompt("This is a command for an ai controlling a smart home.")
                     .build()?;
 
                 term.write_line("Transcribing\n")?;
-                let response = client.audio().transcribe(request).await?;
+                let user_question = match transcribe(&client, &retry_policy, request).await {
+                    Ok(user_question) => user_question,
+                    Err(error) => {
+                        tracing::error!("Failed to transcribe voice command {:?}", error);
+                        continue;
+                    }
+                };
+                if message.format == "wav" {
+                    usage_ledger
+                        .record_transcription(VOICE_TO_TEXT_TRANSCRIBE_MODEL, &temp_auido_file);
+                } else {
+                    tracing::warn!(
+                        "Can't measure length of {} audio for usage ledger",
+                        message.format
+                    );
+                }
                 term.write_line("Talking to OpenAI API\n")?;
 
-                let user_question = response.text;
-
-                let smart_home_state_json = smart_home_state.to_json()?;
+                let smart_home_state_json = smart_home_state.lock().await.to_json()?;
 
                 let current_date_time = now_rfc3339();
                 let message = format!(
@@ -160,91 +226,67 @@ Message for user should be prefaced with a line that says \"MESSAGE:\""
                 term.write_line(&format!("\n{ROBOT_EMOJI} ChatGPT:\n"))?;
 
                 let response = if cli.disable_streaming {
-                    let response = chat_manager.next_message(&message, &client).await?;
-                    term.write_line(&response)?;
-                    term.write_line("")?;
+                    let response = chat_manager.next_message(&message, &backend).await;
+                    if let Ok(response) = &response {
+                        term.write_line(response)?;
+                        term.write_line("")?;
+                    }
                     response
                 } else {
-                    let mut mqtt_streamer = MqttChatStreamDisplay::new(
-                        SMART_HOME_TEXT_OUTPUT_TOPIC,
-                        mqtt_client.clone(),
-                    );
-                    chat_manager
-                        .next_message_stream_stdout(
-                            &message,
-                            &client,
-                            &term,
-                            Some(&mut mqtt_streamer),
-                        )
-                        .await?
+                    let response = chat_manager
+                        .next_message_stream_stdout(&message, &backend, &term, Some(&sinks))
+                        .await;
+                    // final message below has to arrive after the streamed one
+                    sinks.flush().await;
+                    response
                 };
-
-                let response_message = ResponseMessage::new(response.clone());
-
-                match response_message.extract_json() {
-                    Ok(Some(message)) => {
-                        smart_home_state = message;
-                        term.write_line(&format!(
-                            "{}",
-                            style(smart_home_state.to_json_pretty()?).green()
-                        ))?;
-
-                        // ugly
-                        mqtt_client
-                            .publish(
-                                SMART_HOME_MQTT_TOPIC,
-                                QoS::AtMostOnce,
-                                true,
-                                smart_home_state.to_json()?,
-                            )
-                            .await?;
-
-                        if let Ok(formatted_message) =
-                            response_message.message_with_formatted_json()
-                        {
-                            let updated_message = format!(
-                                "{}\n\n{} Estimated usage {}/{} tokens used",
-                                formatted_message,
-                                INCREASING_TREND_EMOJI,
-                                chat_manager.count_tokens(),
-                                CHAT_GPT_MODEL_TOKEN_LIMIT
-                            );
-
-                            mqtt_client
-                                .publish(
-                                    SMART_HOME_TEXT_OUTPUT_TOPIC,
-                                    QoS::AtMostOnce,
-                                    true,
-                                    updated_message,
-                                )
-                                .await?;
-                        }
-                    }
+                let response = match response {
+                    Ok(response) => response,
                     Err(error) => {
-                        term.write_line(&format!("Failed to parse json {:?}", error))?;
+                        // unanswered question would be sent again with the next command
+                        chat_manager.undo_last_question();
+                        tracing::error!("Failed to answer voice command {:?}", error);
+                        continue;
                     }
-                    _ => (),
-                }
+                };
 
-                let user_message_start = response.find("MESSAGE:");
-
-                if let Some(user_message) = response.get(user_message_start.unwrap_or(0)..) {
-                    let user_message_trimmed =
-                        user_message.replace("MESSAGE:", "").trim().to_owned();
-                    if !cli.mute {
-                        mqtt_client
-                            .publish(
-                                "home_speak/say/cheerful",
-                                QoS::AtMostOnce,
-                                false,
-                                user_message_trimmed,
-                            )
-                            .await?;
-                    }
+                term.write_line(&format!(
+                    "{}",
+                    style(smart_home_state.lock().await.to_json_pretty()?).green()
+                ))?;
+
+                let updated_message = format!(
+                    "{}\n\n{} Estimated usage {}/{} tokens used",
+                    response,
+                    INCREASING_TREND_EMOJI,
+                    chat_manager.count_tokens()?,
+                    chat_manager.model().context_window
+                );
+
+                mqtt_client
+                    .publish(
+                        SMART_HOME_TEXT_OUTPUT_TOPIC,
+                        QoS::AtMostOnce,
+                        true,
+                        updated_message,
+                    )
+                    .await?;
+
+                // streamed responses are spoken sentence by sentence
+                if !cli.mute && cli.disable_streaming {
+                    mqtt_client
+                        .publish(
+                            SMART_HOME_SPEAK_TOPIC,
+                            QoS::AtMostOnce,
+                            false,
+                            response.trim().to_owned(),
+                        )
+                        .await?;
                 }
 
                 if !cli.no_save {
-                    chat_manager.save_to_file()?;
+                    let location = storage.save(&mut chat_manager)?;
+                    prune_saved_conversations(&mut storage, &retention, Some(&location), &term)?;
                 }
             }
             _ => (),
@@ -253,6 +295,70 @@ Message for user should be prefaced with a line that says \"MESSAGE:\""
     Ok(())
 }
 
+/// Apply retention policy, failing cleanup doesn't stop the server
+///
+/// Conversation at `current` is kept
+fn prune_saved_conversations(
+    storage: &mut ConversationStorage,
+    retention: &RetentionConfig,
+    current: Option<&ConversationLocation>,
+    term: &Term,
+) -> anyhow::Result<()> {
+    match storage.prune(retention, current, false) {
+        Ok(report) => {
+            for conversation in report.pruned {
+                term.write_line(&format!(
+                    "Removed saved conversation {} ({})",
+                    conversation.summary.location, conversation.reason
+                ))?;
+            }
+            for file in report.unreadable {
+                tracing::warn!(
+                    "Skipped unreadable conversation {} {:?}",
+                    file.path.display(),
+                    file.error
+                );
+            }
+            for failed in report.failed {
+                tracing::warn!(
+                    "Failed to remove saved conversation {} {:?}",
+                    failed.conversation.summary.location,
+                    failed.error
+                );
+            }
+        }
+        Err(error) => tracing::warn!("Failed to prune saved conversations {:?}", error),
+    }
+    Ok(())
+}
+
+/// Tool that lets the model update the smart home
+fn set_smart_home_state_tool(
+    smart_home_state: Arc<Mutex<SmartHomeState>>,
+    mqtt_client: AsyncClient,
+) -> Tool {
+    Tool::new(
+        "set_smart_home_state",
+        "Set the whole state of the smart home. Include all lights, not only the changed ones.",
+        move |new_state: SmartHomeState| {
+            let smart_home_state = smart_home_state.clone();
+            let mqtt_client = mqtt_client.clone();
+            async move {
+                mqtt_client
+                    .publish(
+                        SMART_HOME_MQTT_TOPIC,
+                        QoS::AtMostOnce,
+                        true,
+                        new_state.to_json()?,
+                    )
+                    .await?;
+                *smart_home_state.lock().await = new_state;
+                Ok(serde_json::json!({ "status": "updated" }))
+            }
+        },
+    )
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct AudioMessage {
     pub data: String,
@@ -340,48 +446,3 @@ pub enum ColorTemperature {
     Warm,
     Warmest,
 }
-
-struct ResponseMessage {
-    pub content: String,
-}
-
-impl ResponseMessage {
-    pub fn new(content: String) -> Self {
-        Self { content }
-    }
-
-    fn extract_json(&self) -> anyhow::Result<Option<SmartHomeState>> {
-        let json_range = self
-            .content
-            .find('{')
-            .and_then(|start| self.content.rfind('}').map(|end| (start, end + 1)));
-
-        if let Some((json_start, json_end)) = json_range {
-            if let Some(json) = self.content.get(json_start..json_end) {
-                match SmartHomeState::from_json(json) {
-                    Ok(parsed_state) => return Ok(Some(parsed_state)),
-                    Err(error) => {
-                        // you could try again by showing ChatGPT the error :D
-                        return Err(anyhow::anyhow!("Failed to parse json {:?}", error));
-                    }
-                }
-            }
-        }
-        Ok(None)
-    }
-
-    fn message_with_formatted_json(&self) -> anyhow::Result<String> {
-        let extracted = self.extract_json()?;
-
-        let json_range = self
-            .content
-            .find('{')
-            .and_then(|start| self.content.rfind('}').map(|end| (start..end + 1)))
-            .context("Failed to find range")?;
-
-        let formatted = extracted.context("Failed to get json")?.to_json_pretty()?;
-        let mut message = self.content.clone();
-        message.replace_range(json_range, &formatted);
-        Ok(message)
-    }
-}
@@ -1,18 +1,36 @@
-use std::path::PathBuf;
+use std::{
+    io::{IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::Context;
-use async_openai::Client;
+use async_openai::types::Role;
+#[cfg(feature = "sqlite")]
+use chatty::sqlite_store::ConversationStore;
 use chatty::{
-    chat_manager,
+    cancellation::StreamCancellation,
+    chat_backend::ChatBackend,
+    chat_manager::{self, CancelledResponse, ChatEvent, TitleGenerator},
+    chatgpt_import,
     cli_history::InMemoryHistory,
-    configuration::AppConfig,
-    utils::{
-        generate_system_instructions, CHAT_GPT_MODEL_TOKEN_LIMIT, INCREASING_TREND_EMOJI,
-        ROBOT_EMOJI,
-    },
+    configuration::{AppConfig, SamplingParameters},
+    conversation_storage::ConversationStorage,
+    export::{self, ExportFormat},
+    models::{ModelInfo, ModelRegistry},
+    personas::{Persona, PersonaRegistry, DEFAULT_PERSONA_NAME},
+    retry::{openai_client, RetryPolicy, RetryingChatBackend},
+    slash_commands::{self, ChatInput, CommandCompletion, SlashCommand},
+    stream_display::{ChatStreamSinks, FileTeeDisplay},
+    usage_ledger::{self, UsageLedger},
+    utils::{INCREASING_TREND_EMOJI, ROBOT_EMOJI, STOP_EMOJI},
 };
-use clap::Parser;
-use dialoguer::{console::Term, theme::ColorfulTheme, FuzzySelect, Input};
+use clap::{Parser, Subcommand};
+use dialoguer::{console::Term, theme::ColorfulTheme, Confirm, Editor, FuzzySelect, Input};
+use futures::StreamExt;
 
 #[derive(Parser)]
 #[command()]
@@ -23,9 +41,12 @@ struct Cli {
     /// load from file
     #[arg(long)]
     file: Option<PathBuf>,
-    /// list files
+    /// pick saved conversation to continue, rename, duplicate or delete
     #[arg(long)]
     select_file: bool,
+    /// continue the most recent conversation
+    #[arg(long = "continue")]
+    continue_last: bool,
     /// do not save conversation
     #[arg(long)]
     no_save: bool,
@@ -35,11 +56,113 @@ struct Cli {
     /// copy token from local config to user config
     #[arg(long)]
     copy_local_config: bool,
+    /// model to use
+    #[arg(long)]
+    model: Option<String>,
+    /// persona from the personas directory in the config dir
+    #[arg(long)]
+    persona: Option<String>,
+    /// drop partial response when it's cancelled with Ctrl-C
+    #[arg(long)]
+    discard_cancelled: bool,
+    /// also append streamed responses to file
+    #[arg(long)]
+    tee: Option<PathBuf>,
+    /// sampling temperature between 0 and 2
+    #[arg(long)]
+    temperature: Option<f32>,
+    /// nucleus sampling probability mass between 0 and 1
+    #[arg(long)]
+    top_p: Option<f32>,
+    /// maximum number of tokens in response
+    #[arg(long)]
+    max_tokens: Option<u16>,
+    /// sequence where the response stops, can be repeated
+    #[arg(long)]
+    stop: Vec<String>,
+    /// presence penalty between -2 and 2
+    #[arg(long, allow_hyphen_values = true)]
+    presence_penalty: Option<f32>,
+    /// frequency penalty between -2 and 2
+    #[arg(long, allow_hyphen_values = true)]
+    frequency_penalty: Option<f32>,
+    /// number of generated choices, only the first one is kept
+    #[arg(long)]
+    n: Option<u8>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// answer one question without prompts, for scripts, git hooks and editors
+    ///
+    /// Response is written to stdout, usage and progress to stderr
+    Ask {
+        /// question, anything piped to stdin is added after it
+        question: Vec<String>,
+        /// save conversation like interactive chats
+        #[arg(long)]
+        save: bool,
+    },
+    /// rewrite saved conversations in the current file format
+    Migrate,
+    /// render saved conversation for sharing
+    Export {
+        /// saved conversation file
+        file: PathBuf,
+        #[arg(long, value_enum, default_value = "markdown")]
+        format: ExportFormat,
+        /// write to file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// show API usage and cost per day and model
+    Usage {
+        /// how far back to look, for example 12h, 7d or 2w
+        #[arg(long, default_value = "7d")]
+        since: String,
+    },
+    /// remove saved conversations that exceed retention limits from the user config
+    Prune {
+        /// only list conversations that would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// import conversations.json from ChatGPT data export
+    ImportChatgpt {
+        /// path to conversations.json
+        file: PathBuf,
+    },
+    /// search saved conversations and continue the selected one
+    #[cfg(feature = "sqlite")]
+    Search {
+        /// words that have to appear in a message
+        #[arg(required = true)]
+        query: Vec<String>,
+    },
+    /// import saved YAML conversations into the SQLite database
+    #[cfg(feature = "sqlite")]
+    ImportYaml,
+}
+
+impl Cli {
+    fn sampling_parameters(&self) -> SamplingParameters {
+        SamplingParameters {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            stop: self.stop.clone(),
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            n: self.n,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let mut cli = Cli::parse();
+    let cli = Cli::parse();
 
     if cli.create_config {
         // write default config
@@ -53,127 +176,1250 @@ async fn main() -> anyhow::Result<()> {
         let local_config = AppConfig::load_dev_config()?;
         let config_new = AppConfig {
             open_ai_api_key: local_config.open_ai_api_key,
-            mqtt: None,
+            ..Default::default()
         };
         config_new.save_user_config()?;
         return Ok(());
     }
 
     let term = Term::stdout();
+
     let mut history = InMemoryHistory::default();
     let term_theme = ColorfulTheme::default();
 
-    if cli.select_file {
-        let files = chat_manager::ChatHistory::get_all_saved_conversations()?;
-        let file_names: Vec<_> = files
-            .iter()
-            .map(|path| {
-                path.file_name()
-                    .unwrap_or_default()
-                    .to_str()
-                    .unwrap_or_default()
-            })
-            .collect();
-        let selection = FuzzySelect::with_theme(&term_theme)
-            .with_prompt("Select file")
-            .items(&file_names)
-            .default(0)
-            .interact_on(&term)?;
-        cli.file = Some(
-            files
-                .get(selection)
-                .context("Selected wrong item form file list")?
-                .to_owned(),
-        );
-        // weird mutating the cli args
-    }
+    let found_conversation = match &cli.command {
+        Some(Command::Migrate) => return migrate_saved_conversations(&term),
+        Some(Command::Export {
+            file,
+            format,
+            output,
+        }) => return export_conversation(file, *format, output.as_deref()),
+        Some(Command::ImportChatgpt { file }) => return import_chatgpt_conversations(file, &term),
+        Some(Command::Usage { since }) => return print_usage(since, &term),
+        Some(Command::Prune { dry_run }) => return prune_conversations(*dry_run, &term),
+        #[cfg(feature = "sqlite")]
+        Some(Command::ImportYaml) => return import_yaml_conversations(&term),
+        #[cfg(feature = "sqlite")]
+        Some(Command::Search { query }) => {
+            match search_conversations(&query.join(" "), &term, &term_theme)? {
+                Some(conversation) => Some(conversation),
+                None => return Ok(()),
+            }
+        }
+        Some(Command::Ask { .. }) | None => None,
+    };
 
     let config = AppConfig::load_user_config()?;
 
-    let client = Client::new().with_api_key(&config.open_ai_api_key);
+    let mut storage = ConversationStorage::open(config.storage)?;
+
+    let found_conversation = if cli.continue_last {
+        let latest = storage
+            .latest()?
+            .context("No saved conversation to continue")?;
+        Some(storage.load(&latest.location)?)
+    } else if cli.select_file {
+        match pick_conversation(&mut storage, &term, &term_theme)? {
+            Some(conversation) => Some(conversation),
+            None => return Ok(()),
+        }
+    } else {
+        found_conversation
+    };
+
+    let client = RetryingChatBackend::new(
+        openai_client(&config.open_ai_api_key),
+        RetryPolicy::new(config.retry),
+    );
 
-    let system_messages = generate_system_instructions();
+    let model_registry = ModelRegistry::from_config(&config);
 
-    let mut chat_manager = if let Some(path) = cli.file {
-        chat_manager::ChatHistory::load_from_file(&path)?
+    let persona_registry = PersonaRegistry::load_default()?;
+    let persona = persona_registry.get(
+        cli.persona
+            .as_deref()
+            .or(config.default_persona.as_deref())
+            .unwrap_or(DEFAULT_PERSONA_NAME),
+    )?;
+
+    let resumed = found_conversation.is_some() || cli.file.is_some();
+    let mut chat_manager = if let Some(conversation) = found_conversation {
+        conversation
+    } else if let Some(path) = &cli.file {
+        chat_manager::ChatHistory::load_from_file(path)?
     } else {
-        chat_manager::ChatHistory::new(&system_messages["joi"])?
+        let model = choose_model(&model_registry, cli.model.as_deref(), persona)?;
+        let mut chat_manager = chat_manager::ChatHistory::new(&persona.system_message(model)?)?;
+        let mut sampling = config.sampling.clone();
+        sampling.merge(&persona.sampling);
+        chat_manager.set_sampling_parameters(sampling)?;
+        chat_manager
+    };
+    // explicitly picked persona takes over resumed conversation
+    if resumed && cli.persona.is_some() {
+        switch_persona(&mut chat_manager, persona, &model_registry)?;
+    }
+    // flags override parameters from config or loaded conversation
+    let mut sampling = chat_manager.sampling_parameters().clone();
+    sampling.merge(&cli.sampling_parameters());
+    chat_manager.set_sampling_parameters(sampling)?;
+    let model = match &cli.model {
+        // resumed conversation continues with the model it was using if we still know it
+        None if resumed => model_registry
+            .get(&chat_manager.model().name)
+            .or_else(|_| model_registry.default_model())?,
+        _ => choose_model(&model_registry, cli.model.as_deref(), persona)?,
+    };
+    chat_manager.set_model(model.clone());
+    chat_manager.set_context_window_config(config.context_window);
+    chat_manager.set_usage_ledger(UsageLedger::open_default()?);
+    chat_manager.set_title_generator(TitleGenerator::new(
+        Arc::new(client.clone()),
+        model_registry.title_model()?.cloned(),
+    ));
+
+    if let Some(Command::Ask { question, save }) = &cli.command {
+        chat_manager.set_generate_titles(*save);
+        let storage = save.then_some(&mut storage);
+        return ask(question, &mut chat_manager, &client, storage).await;
+    }
+
+    let cancellation = StreamCancellation::new();
+    let exit_requested = Arc::new(AtomicBool::new(false));
+    spawn_ctrl_c_handler(cancellation.clone(), exit_requested.clone());
+    chat_manager.set_cancellation(cancellation.clone());
+    if cli.discard_cancelled {
+        chat_manager.set_cancelled_response(CancelledResponse::Discard);
+    }
+    let sinks = cli
+        .tee
+        .as_deref()
+        .map(|path| ChatStreamSinks::new().with(FileTeeDisplay::new(path)));
+
+    let completion = CommandCompletion::new()
+        .with_arguments(
+            "model",
+            model_registry
+                .model_names()
+                .into_iter()
+                .map(|name| name.to_owned())
+                .collect(),
+        )
+        .with_arguments(
+            "persona",
+            persona_registry
+                .persona_names()
+                .into_iter()
+                .map(|name| name.to_owned())
+                .collect(),
+        );
+    let command_context = CommandContext {
+        backend: &client,
+        model_registry: &model_registry,
+        persona_registry: &persona_registry,
+        term: &term,
+        term_theme: &term_theme,
+        no_save: cli.no_save,
     };
 
     loop {
-        let mut user_question: String = Input::with_theme(&term_theme)
+        // Ctrl-C while typing arrives as interrupted read instead of a signal
+        let user_input: String = match Input::with_theme(&term_theme)
             .with_prompt("Question:")
             .history_with(&mut history)
-            .interact_text_on(&term)?;
-
-        if &user_question == "/?" {
-            let options = UserActions::all_str();
-
-            let selection = FuzzySelect::with_theme(&term_theme)
-                .with_prompt("Select action")
-                .items(&options)
-                .default(0)
-                .interact_on_opt(&term)?;
-            let selection = selection.and_then(|index| UserActions::all().get(index));
-            match selection {
-                Some(UserActions::ReturnToChat) => continue,
-                Some(UserActions::RecreateTitle) => {
-                    chat_manager.populate_title(&client).await?;
-                    continue;
-                }
-                Some(UserActions::RegenerateResponse) => {
-                    // ugly...
-                    _ = chat_manager.pop_last_message();
-                    user_question = chat_manager.pop_last_message().unwrap_or_default().content;
-                    // keep going to create new message
+            .completion_with(&completion)
+            .interact_text_on(&term)
+        {
+            Ok(user_input) => user_input,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => break,
+            Err(error) => return Err(error.into()),
+        };
+
+        let user_question = match slash_commands::parse_input(&user_input) {
+            Ok(ChatInput::Message(message)) => message,
+            Ok(ChatInput::Command(SlashCommand::Menu)) => {
+                let options = UserActions::all_str();
+
+                let selection = FuzzySelect::with_theme(&term_theme)
+                    .with_prompt("Select action")
+                    .items(&options)
+                    .default(0)
+                    .interact_on_opt(&term)?;
+                let selection = selection.and_then(|index| UserActions::all().get(index));
+                match selection {
+                    Some(UserActions::ReturnToChat) => continue,
+                    Some(UserActions::RecreateTitle) => {
+                        chat_manager.populate_title(&client).await?;
+                        continue;
+                    }
+                    Some(UserActions::RegenerateResponse) => {
+                        // previous response stays available as a branch
+                        term.write_line(&format!("\n{ROBOT_EMOJI} ChatGPT:\n"))?;
+                        let result = if cli.disable_streaming {
+                            chat_manager
+                                .regenerate_response(&client)
+                                .await
+                                .and_then(|response| Ok(term.write_line(&response)?))
+                        } else {
+                            chat_manager
+                                .regenerate_response_stream_stdout(&client, &term, sinks.as_ref())
+                                .await
+                                .map(|_| ())
+                        };
+                        if let Err(error) = result {
+                            term.write_line(&format!("\n{error:#}"))?;
+                            continue;
+                        }
+                        if !cli.no_save {
+                            storage.save(&mut chat_manager)?;
+                        }
+                        continue;
+                    }
+                    Some(UserActions::EditQuestion) => {
+                        let questions: Vec<_> = chat_manager
+                            .message_tree()
+                            .active_path()
+                            .into_iter()
+                            .filter_map(|id| chat_manager.message_tree().get(id))
+                            .filter(|node| node.message.role == Role::User)
+                            .map(|node| (node.id, node.message.content.clone()))
+                            .collect();
+                        if questions.is_empty() {
+                            term.write_line("No questions to edit")?;
+                            continue;
+                        }
+                        let selection = FuzzySelect::with_theme(&term_theme)
+                            .with_prompt("Select question")
+                            .items(
+                                &questions
+                                    .iter()
+                                    .map(|(_, content)| content)
+                                    .collect::<Vec<_>>(),
+                            )
+                            .default(questions.len() - 1)
+                            .interact_on_opt(&term)?;
+                        let Some((id, content)) = selection.and_then(|index| questions.get(index))
+                        else {
+                            continue;
+                        };
+                        let edited: String = Input::with_theme(&term_theme)
+                            .with_prompt("Question:")
+                            .with_initial_text(content)
+                            .interact_text_on(&term)?;
+                        // original question and its answers stay available as a branch
+                        chat_manager.edit_message(*id, &edited)?;
+                        term.write_line(&format!("\n{ROBOT_EMOJI} ChatGPT:\n"))?;
+                        let result = if cli.disable_streaming {
+                            chat_manager
+                                .regenerate_response(&client)
+                                .await
+                                .and_then(|response| Ok(term.write_line(&response)?))
+                        } else {
+                            chat_manager
+                                .regenerate_response_stream_stdout(&client, &term, sinks.as_ref())
+                                .await
+                                .map(|_| ())
+                        };
+                        if let Err(error) = result {
+                            term.write_line(&format!("\n{error:#}"))?;
+                            continue;
+                        }
+                        if !cli.no_save {
+                            storage.save(&mut chat_manager)?;
+                        }
+                        continue;
+                    }
+                    Some(UserActions::EditMessageInEditor) => {
+                        if !edit_message_in_editor(&mut chat_manager, &term, &term_theme)? {
+                            if !cli.no_save {
+                                storage.save(&mut chat_manager)?;
+                            }
+                            continue;
+                        }
+                        term.write_line(&format!("\n{ROBOT_EMOJI} ChatGPT:\n"))?;
+                        let result = if cli.disable_streaming {
+                            chat_manager
+                                .regenerate_response(&client)
+                                .await
+                                .and_then(|response| Ok(term.write_line(&response)?))
+                        } else {
+                            chat_manager
+                                .regenerate_response_stream_stdout(&client, &term, sinks.as_ref())
+                                .await
+                                .map(|_| ())
+                        };
+                        if let Err(error) = result {
+                            term.write_line(&format!("\n{error:#}"))?;
+                            continue;
+                        }
+                        if !cli.no_save {
+                            storage.save(&mut chat_manager)?;
+                        }
+                        continue;
+                    }
+                    Some(UserActions::SwitchBranch) => {
+                        switch_branch(&mut chat_manager, &term, &term_theme)?;
+                        continue;
+                    }
+                    Some(UserActions::PrintChatHistory) => {
+                        chat_manager.print_history(&term)?;
+                        continue;
+                    }
+                    Some(UserActions::SetSamplingParameter) => {
+                        set_sampling_parameter(&mut chat_manager, &term, &term_theme)?;
+                        continue;
+                    }
+                    Some(UserActions::SwitchPersona) => {
+                        pick_persona(
+                            &mut chat_manager,
+                            &persona_registry,
+                            &model_registry,
+                            &term,
+                            &term_theme,
+                        )?;
+                        if !cli.no_save {
+                            storage.save(&mut chat_manager)?;
+                        }
+                        continue;
+                    }
+                    Some(UserActions::SwitchModel) => {
+                        // same as /model so that both save the switch
+                        run_command(
+                            SlashCommand::Model(None),
+                            &mut chat_manager,
+                            &mut storage,
+                            &command_context,
+                        )
+                        .await?;
+                        continue;
+                    }
+                    None => continue,
                 }
-                Some(UserActions::PrintChatHistory) => {
-                    chat_manager.print_history(&term)?;
-                    continue;
+            }
+            Ok(ChatInput::Command(command)) => {
+                let result =
+                    run_command(command, &mut chat_manager, &mut storage, &command_context).await;
+                if let Err(error) = result {
+                    term.write_line(&format!("{error:#}"))?;
                 }
-                None => continue,
+                continue;
             }
-        }
+            Err(error) => {
+                term.write_line(&format!("{error:#}"))?;
+                continue;
+            }
+        };
 
         term.write_line(&format!("\n{ROBOT_EMOJI} ChatGPT:\n"))?;
 
         if cli.disable_streaming {
-            let response = chat_manager.next_message(&user_question, &client).await?;
+            let response = match chat_manager.next_message(&user_question, &client).await {
+                Ok(response) => response,
+                Err(error) => {
+                    report_failed_question(&mut chat_manager, &term, error)?;
+                    continue;
+                }
+            };
 
+            if cancellation.is_cancelled() {
+                term.write_line(&format!("{STOP_EMOJI} Response cancelled"))?;
+            }
             term.write_line(&response)?;
 
             // print usage
             if let Some(token_usage) = chat_manager.token_usage() {
                 term.write_line(&format!(
-                    "\n{INCREASING_TREND_EMOJI} Recorded usage {}/{CHAT_GPT_MODEL_TOKEN_LIMIT} tokens used",
-                    token_usage.total_tokens
+                    "\n{INCREASING_TREND_EMOJI} Recorded usage {}/{} tokens used",
+                    token_usage.total_tokens,
+                    chat_manager.model().context_window
                 ))?;
             }
 
             // print usage calculated
             term.write_line(&format!(
-                "{INCREASING_TREND_EMOJI} Estimated usage {}/{CHAT_GPT_MODEL_TOKEN_LIMIT} tokens used",
-                chat_manager.count_tokens()
+                "{INCREASING_TREND_EMOJI} Estimated usage {}/{} tokens used",
+                chat_manager.count_tokens()?,
+                chat_manager.model().context_window
             ))?;
 
             term.write_line("")?;
-        } else {
-            let _response = chat_manager
-                .next_message_stream_stdout(&user_question, &client, &term, None)
-                .await?;
+        } else if let Err(error) = chat_manager
+            .next_message_stream_stdout(&user_question, &client, &term, sinks.as_ref())
+            .await
+        {
+            report_failed_question(&mut chat_manager, &term, error)?;
+            continue;
         }
         if !cli.no_save {
-            chat_manager.save_to_file()?;
+            storage.save(&mut chat_manager)?;
+        }
+        if exit_requested.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+    if let Some(sinks) = sinks {
+        sinks.close().await;
+    }
+    // title of a short conversation might still be on its way
+    if !cli.no_save && chat_manager.wait_for_title().await.is_some() {
+        storage.save(&mut chat_manager)?;
+    }
+    Ok(())
+}
+
+/// Print error of request that failed after retries and drop the unanswered question
+///
+/// Question stays in input history so that it can be sent again
+fn report_failed_question(
+    chat_manager: &mut chat_manager::ChatHistory,
+    term: &Term,
+    error: anyhow::Error,
+) -> anyhow::Result<()> {
+    chat_manager.undo_last_question();
+    term.write_line(&format!("\n{error:#}"))?;
+    Ok(())
+}
+
+/// Answer question and write response to stdout as plain text
+///
+/// Progress and usage go to stderr so that only the response ends up in pipes
+async fn ask(
+    question: &[String],
+    chat_manager: &mut chat_manager::ChatHistory,
+    backend: &dyn ChatBackend,
+    storage: Option<&mut ConversationStorage>,
+) -> anyhow::Result<()> {
+    let question = read_question(question)?;
+    let stderr = Term::stderr();
+    stderr.write_line(&format!("Asking {}", chat_manager.model().name))?;
+
+    let mut stdout = std::io::stdout();
+    let mut response = None;
+    let mut events = chat_manager.next_message_events(&question, backend);
+    while let Some(event) = events.next().await {
+        match event {
+            ChatEvent::ContentDelta(delta_content) => {
+                stdout.write_all(delta_content.as_bytes())?;
+                stdout.flush()?;
+            }
+            ChatEvent::ToolCall { tool } => stderr.write_line(&format!("Called tool {tool}"))?,
+            ChatEvent::Usage {
+                recorded,
+                estimated_tokens,
+                context_window,
+            } => match recorded {
+                Some(token_usage) => stderr.write_line(&format!(
+                    "Recorded usage {}/{context_window} tokens used",
+                    token_usage.total_tokens
+                ))?,
+                None => stderr.write_line(&format!(
+                    "Estimated usage {estimated_tokens}/{context_window} tokens used"
+                ))?,
+            },
+            ChatEvent::Finished { content } => response = Some(content),
+            ChatEvent::Error(error) => return Err(error),
+            ChatEvent::Role(_) | ChatEvent::Cancelled => (),
+        }
+    }
+    drop(events);
+    let response = response.context("Response stream ended without result")?;
+    if !response.ends_with('\n') {
+        stdout.write_all(b"\n")?;
+    }
+
+    if let Some(storage) = storage {
+        chat_manager.wait_for_title().await;
+        storage.save(chat_manager)?;
+        if let Some(path) = chat_manager.file_path() {
+            stderr.write_line(&format!("Saved to {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Question from arguments followed by anything piped to stdin
+fn read_question(arguments: &[String]) -> anyhow::Result<String> {
+    let mut parts = vec![];
+    if !arguments.is_empty() {
+        parts.push(arguments.join(" "));
+    }
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        let mut input = String::new();
+        stdin.lock().read_to_string(&mut input)?;
+        if !input.trim().is_empty() {
+            parts.push(input.trim_end().to_owned());
+        }
+    }
+    if parts.is_empty() {
+        anyhow::bail!("Nothing to ask, pass question as argument or pipe it to stdin");
+    }
+    Ok(parts.join("\n\n"))
+}
+
+/// Search conversations in the SQLite database and let user pick one
+#[cfg(feature = "sqlite")]
+fn search_conversations(
+    query: &str,
+    term: &Term,
+    term_theme: &ColorfulTheme,
+) -> anyhow::Result<Option<chat_manager::ChatHistory>> {
+    const SEARCH_RESULT_LIMIT: usize = 50;
+    let store = ConversationStore::open_default()?;
+    let results = store.search(query, SEARCH_RESULT_LIMIT)?;
+    if results.is_empty() {
+        term.write_line("No conversations found")?;
+        return Ok(None);
+    }
+    let items: Vec<_> = results
+        .iter()
+        .map(|result| {
+            format!(
+                "{} | {} | {}",
+                result
+                    .start
+                    .map(|start| start.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default(),
+                result
+                    .title
+                    .as_deref()
+                    .unwrap_or("untitled")
+                    .replace('_', " "),
+                result.snippet.replace('\n', " ")
+            )
+        })
+        .collect();
+    let Some(selection) = FuzzySelect::with_theme(term_theme)
+        .with_prompt("Select conversation")
+        .items(&items)
+        .default(0)
+        .interact_on_opt(term)?
+    else {
+        return Ok(None);
+    };
+    let conversation = store.load(results[selection].conversation_id)?;
+    conversation.print_history(term)?;
+    Ok(Some(conversation))
+}
+
+/// Import all saved YAML conversations into the SQLite database
+#[cfg(feature = "sqlite")]
+fn import_yaml_conversations(term: &Term) -> anyhow::Result<()> {
+    let mut store = ConversationStore::open_default()?;
+    let files = chat_manager::ChatHistory::get_all_saved_conversations()?;
+    let mut failed = 0;
+    for path in &files {
+        if let Err(error) = store.import_file(path) {
+            failed += 1;
+            term.write_line(&format!("Failed to import {}: {error:#}", path.display()))?;
         }
     }
+    term.write_line(&format!(
+        "Imported {} of {} conversations",
+        files.len() - failed,
+        files.len()
+    ))?;
+    Ok(())
+}
+
+fn export_conversation(
+    file: &Path,
+    format: ExportFormat,
+    output: Option<&Path>,
+) -> anyhow::Result<()> {
+    let chat_history = chat_manager::ChatHistory::load_from_file(file)?;
+    let exported = export::export(&chat_history, format)?;
+    match output {
+        Some(output) => std::fs::write(output, exported)
+            .with_context(|| format!("Failed to write {}", output.display()))?,
+        None => print!("{exported}"),
+    }
+    Ok(())
+}
+
+/// Print usage ledger totals per day and model
+fn print_usage(since: &str, term: &Term) -> anyhow::Result<()> {
+    let since = chrono::Local::now() - usage_ledger::parse_duration(since)?;
+    let entries = UsageLedger::open_default()?.entries_since(since)?;
+    if entries.is_empty() {
+        term.write_line("No usage recorded")?;
+        return Ok(());
+    }
+    term.write_line(&format!(
+        "{:<10}  {:<20}  {:>8}  {:>10}  {:>10}  {:>9}  {:>9}",
+        "day", "model", "requests", "prompt", "completion", "audio min", "cost $"
+    ))?;
+    let mut total_cost = 0.0;
+    for ((day, model), totals) in usage_ledger::totals_per_day_and_model(&entries) {
+        total_cost += totals.cost;
+        term.write_line(&format!(
+            "{:<10}  {:<20}  {:>8}  {:>10}  {:>10}  {:>9.1}  {:>9.4}",
+            day.to_string(),
+            model,
+            totals.requests,
+            totals.prompt_tokens,
+            totals.completion_tokens,
+            totals.audio_seconds / 60.0,
+            totals.cost
+        ))?;
+    }
+    term.write_line(&format!(
+        "{INCREASING_TREND_EMOJI} Total {} requests, ${total_cost:.4}",
+        entries.len()
+    ))?;
+    Ok(())
+}
+
+fn prune_conversations(dry_run: bool, term: &Term) -> anyhow::Result<()> {
+    let config = AppConfig::load_user_config()?;
+    let mut storage = ConversationStorage::open(config.storage)?;
+    let report = storage.prune(&config.retention, None, dry_run)?;
+    for file in &report.unreadable {
+        term.write_line(&format!(
+            "Skipped unreadable {}: {:#}",
+            file.path.display(),
+            file.error
+        ))?;
+    }
+    if report.pruned.is_empty() && report.failed.is_empty() {
+        term.write_line("Nothing to prune")?;
+    } else {
+        let action = if dry_run { "Would remove" } else { "Removed" };
+        for conversation in &report.pruned {
+            term.write_line(&format!(
+                "{action} {} ({})",
+                conversation.summary.location, conversation.reason
+            ))?;
+        }
+        let freed: u64 = report
+            .pruned
+            .iter()
+            .map(|conversation| conversation.summary.size)
+            .sum();
+        term.write_line(&format!(
+            "{action} {} conversations, {:.1} MB",
+            report.pruned.len(),
+            freed as f64 / (1024.0 * 1024.0)
+        ))?;
+    }
+    if !report.unreadable.is_empty() {
+        term.write_line(&format!(
+            "{} unreadable files were left in place",
+            report.unreadable.len()
+        ))?;
+    }
+    for failed in &report.failed {
+        term.write_line(&format!(
+            "Failed to remove {}: {:#}",
+            failed.conversation.summary.location, failed.error
+        ))?;
+    }
+    Ok(())
+}
+
+fn import_chatgpt_conversations(file: &Path, term: &Term) -> anyhow::Result<()> {
+    let config = AppConfig::load_user_config()?;
+    let mut storage = ConversationStorage::open(config.storage)?;
+    let report = chatgpt_import::import_chatgpt_export(file, &mut storage)?;
+    for skipped in &report.skipped_messages {
+        term.write_line(&format!(
+            "Skipped message {} in conversation {} ({}): {}",
+            skipped.message_id,
+            skipped.conversation_index,
+            skipped.title.as_deref().unwrap_or("untitled"),
+            skipped.reason
+        ))?;
+    }
+    for skipped in &report.skipped {
+        term.write_line(&format!(
+            "Skipped conversation {} ({}): {}",
+            skipped.index,
+            skipped.title.as_deref().unwrap_or("untitled"),
+            skipped.reason
+        ))?;
+    }
+    for location in &report.overwritten {
+        term.write_line(&format!("Overwrote earlier import {location}"))?;
+    }
+    term.write_line(&format!(
+        "Imported {} new conversations, overwrote {} earlier imports, skipped {} conversations and {} messages",
+        report.imported.len(),
+        report.overwritten.len(),
+        report.skipped.len(),
+        report.skipped_messages.len()
+    ))?;
+    Ok(())
+}
+
+/// Upgrade all saved conversations and report files that failed
+fn migrate_saved_conversations(term: &Term) -> anyhow::Result<()> {
+    let files = chat_manager::ChatHistory::get_all_saved_conversations()?;
+    let mut migrated = 0;
+    let mut failed = 0;
+    for path in &files {
+        match chat_manager::ChatHistory::migrate_file(path) {
+            Ok(Some(version)) => {
+                migrated += 1;
+                term.write_line(&format!(
+                    "Migrated {} from version {version}",
+                    path.display()
+                ))?;
+            }
+            Ok(None) => (),
+            Err(error) => {
+                failed += 1;
+                term.write_line(&format!("Failed to migrate {}: {error:#}", path.display()))?;
+            }
+        }
+    }
+    term.write_line(&format!(
+        "Checked {} files, migrated {migrated}, failed {failed}",
+        files.len()
+    ))?;
+    if failed > 0 {
+        anyhow::bail!("{failed} conversation files could not be migrated");
+    }
+    Ok(())
+}
+
+/// Everything slash commands need besides the conversation
+struct CommandContext<'a> {
+    backend: &'a dyn ChatBackend,
+    model_registry: &'a ModelRegistry,
+    persona_registry: &'a PersonaRegistry,
+    term: &'a Term,
+    term_theme: &'a ColorfulTheme,
+    no_save: bool,
+}
+
+/// Run slash command typed in chat
+///
+/// Errors are shown to the user and don't end the chat
+async fn run_command(
+    command: SlashCommand,
+    chat_manager: &mut chat_manager::ChatHistory,
+    storage: &mut ConversationStorage,
+    context: &CommandContext<'_>,
+) -> anyhow::Result<()> {
+    let term = context.term;
+    let mut changed = false;
+    match command {
+        SlashCommand::Model(Some(model_name)) => {
+            chat_manager.set_model(context.model_registry.get(&model_name)?.clone());
+            term.write_line(&format!("Switched to {}", chat_manager.model().name))?;
+            changed = true;
+        }
+        SlashCommand::Model(None) => {
+            pick_model(
+                chat_manager,
+                context.model_registry,
+                term,
+                context.term_theme,
+            )?;
+            changed = true;
+        }
+        SlashCommand::System(Some(system_message)) => {
+            chat_manager.set_system_message(&system_message)?;
+            term.write_line("System prompt replaced")?;
+            changed = true;
+        }
+        SlashCommand::System(None) => match chat_manager
+            .message_at(0)
+            .filter(|message| message.role == Role::System)
+        {
+            Some(message) => term.write_line(&message.content)?,
+            None => term.write_line("Conversation has no system prompt")?,
+        },
+        SlashCommand::Persona(Some(persona_name)) => {
+            let persona = context.persona_registry.get(&persona_name)?;
+            switch_persona(chat_manager, persona, context.model_registry)?;
+            term.write_line(&format!(
+                "Switched to {} using {}",
+                persona.name,
+                chat_manager.model().name
+            ))?;
+            changed = true;
+        }
+        SlashCommand::Persona(None) => {
+            pick_persona(
+                chat_manager,
+                context.persona_registry,
+                context.model_registry,
+                term,
+                context.term_theme,
+            )?;
+            changed = true;
+        }
+        SlashCommand::Title(title) => {
+            match title {
+                Some(title) => chat_manager.set_title(&title),
+                None => chat_manager.populate_title(context.backend).await?,
+            }
+            term.write_line(&format!(
+                "Title: {}",
+                chat_manager.conversation_title().unwrap_or_default()
+            ))?;
+            changed = true;
+        }
+        SlashCommand::Save(name) => {
+            // explicit save also works with --no-save
+            chat_manager.set_title(&name);
+            storage.save(chat_manager)?;
+            match chat_manager.file_path() {
+                Some(path) => term.write_line(&format!("Saved to {}", path.display()))?,
+                None => term.write_line(&format!("Saved as {name}"))?,
+            }
+        }
+        SlashCommand::Clear => {
+            // title of the previous conversation might still be on its way
+            if !context.no_save && chat_manager.wait_for_title().await.is_some() {
+                storage.save(chat_manager)?;
+            }
+            chat_manager.start_new_conversation();
+            term.write_line("Started new conversation")?;
+        }
+        SlashCommand::Undo => {
+            match chat_manager.undo_last_question() {
+                Some(question) => {
+                    term.write_line(&format!("Removed \"{}\"", preview(&question.content)))?
+                }
+                None => term.write_line("Nothing to undo")?,
+            }
+            changed = true;
+        }
+        SlashCommand::Tokens => {
+            term.write_line(&format!(
+                "{INCREASING_TREND_EMOJI} Estimated usage {}/{} tokens",
+                chat_manager.count_tokens()?,
+                chat_manager.model().context_window
+            ))?;
+            if let Some(token_usage) = chat_manager.token_usage() {
+                term.write_line(&format!(
+                    "{INCREASING_TREND_EMOJI} Last request {} prompt + {} completion tokens",
+                    token_usage.prompt_tokens, token_usage.completion_tokens
+                ))?;
+            }
+        }
+        SlashCommand::Export { format, output } => {
+            let exported = export::export(chat_manager, format)?;
+            match output {
+                Some(output) => {
+                    std::fs::write(&output, exported)
+                        .with_context(|| format!("Failed to write {}", output.display()))?;
+                    term.write_line(&format!("Exported to {}", output.display()))?;
+                }
+                None => term.write_line(&exported)?,
+            }
+        }
+        SlashCommand::Help => term.write_line(&slash_commands::help())?,
+        SlashCommand::Menu => anyhow::bail!("Action menu can only be opened from the chat prompt"),
+    }
+    if changed && !context.no_save {
+        storage.save(chat_manager)?;
+    }
+    Ok(())
+}
+
+fn pick_model(
+    chat_manager: &mut chat_manager::ChatHistory,
+    model_registry: &ModelRegistry,
+    term: &Term,
+    term_theme: &ColorfulTheme,
+) -> anyhow::Result<()> {
+    let model_names = model_registry.model_names();
+    let current = model_names
+        .iter()
+        .position(|name| *name == chat_manager.model().name)
+        .unwrap_or_default();
+    let selection = FuzzySelect::with_theme(term_theme)
+        .with_prompt("Select model")
+        .items(&model_names)
+        .default(current)
+        .interact_on_opt(term)?;
+    if let Some(index) = selection {
+        chat_manager.set_model(model_registry.models()[index].clone());
+        term.write_line(&format!("Switched to {}", chat_manager.model().name))?;
+    }
+    Ok(())
+}
+
+fn pick_persona(
+    chat_manager: &mut chat_manager::ChatHistory,
+    persona_registry: &PersonaRegistry,
+    model_registry: &ModelRegistry,
+    term: &Term,
+    term_theme: &ColorfulTheme,
+) -> anyhow::Result<()> {
+    let persona_names = persona_registry.persona_names();
+    let selection = FuzzySelect::with_theme(term_theme)
+        .with_prompt("Select persona")
+        .items(&persona_names)
+        .default(0)
+        .interact_on_opt(term)?;
+    if let Some(persona) = selection.and_then(|index| persona_registry.personas().get(index)) {
+        switch_persona(chat_manager, persona, model_registry)?;
+        term.write_line(&format!(
+            "Switched to {} using {}",
+            persona.name,
+            chat_manager.model().name
+        ))?;
+    }
+    Ok(())
+}
+
+/// Model picked on the command line, then the one preferred by persona, then the default one
+fn choose_model<'a>(
+    model_registry: &'a ModelRegistry,
+    model_name: Option<&str>,
+    persona: &Persona,
+) -> anyhow::Result<&'a ModelInfo> {
+    match model_name.or(persona.model.as_deref()) {
+        Some(model_name) => model_registry.get(model_name),
+        None => model_registry.default_model(),
+    }
+}
+
+/// Continue conversation with system prompt, model and parameters of `persona`
+fn switch_persona(
+    chat_manager: &mut chat_manager::ChatHistory,
+    persona: &Persona,
+    model_registry: &ModelRegistry,
+) -> anyhow::Result<()> {
+    if let Some(model_name) = &persona.model {
+        chat_manager.set_model(model_registry.get(model_name)?.clone());
+    }
+    let mut sampling = chat_manager.sampling_parameters().clone();
+    sampling.merge(&persona.sampling);
+    chat_manager.set_sampling_parameters(sampling)?;
+    let system_message = persona.system_message(chat_manager.model())?;
+    chat_manager.set_system_message(&system_message)
+}
+
+/// Pick saved conversation to continue
+///
+/// Conversations can also be renamed, duplicated or deleted here.
+/// Returns None when nothing was picked
+fn pick_conversation(
+    storage: &mut ConversationStorage,
+    term: &Term,
+    term_theme: &ColorfulTheme,
+) -> anyhow::Result<Option<chat_manager::ChatHistory>> {
+    loop {
+        let summaries = storage.list()?;
+        if summaries.is_empty() {
+            term.write_line("No saved conversations")?;
+            return Ok(None);
+        }
+        let items: Vec<_> = summaries
+            .iter()
+            .map(|summary| {
+                format!(
+                    "{}{} | {} | {} messages | {} | {}",
+                    if summary.starred { "* " } else { "" },
+                    summary.modified.format("%Y-%m-%d %H:%M"),
+                    summary.title.as_deref().unwrap_or("untitled"),
+                    summary.message_count,
+                    summary.model,
+                    summary
+                        .first_question
+                        .as_deref()
+                        .map(preview)
+                        .unwrap_or_default()
+                )
+            })
+            .collect();
+        let Some(summary) = FuzzySelect::with_theme(term_theme)
+            .with_prompt("Select conversation")
+            .items(&items)
+            .default(0)
+            .interact_on_opt(term)?
+            .and_then(|index| summaries.get(index))
+        else {
+            return Ok(None);
+        };
+
+        let selection = FuzzySelect::with_theme(term_theme)
+            .with_prompt("Select action")
+            .items(&ConversationActions::all_str())
+            .default(0)
+            .interact_on_opt(term)?;
+        match selection.and_then(|index| ConversationActions::all().get(index)) {
+            Some(ConversationActions::Continue) => {
+                return Ok(Some(storage.load(&summary.location)?))
+            }
+            Some(ConversationActions::Rename) => {
+                let title: String = Input::with_theme(term_theme)
+                    .with_prompt("Title:")
+                    .with_initial_text(summary.title.as_deref().unwrap_or_default())
+                    .interact_text_on(term)?;
+                let title = title.trim();
+                if title.is_empty() {
+                    continue;
+                }
+                let mut chat_history = storage.load(&summary.location)?;
+                chat_history.set_title(title);
+                storage.save(&mut chat_history)?;
+            }
+            Some(ConversationActions::ToggleStar) => {
+                let mut chat_history = storage.load(&summary.location)?;
+                chat_history.set_starred(!summary.starred);
+                storage.save(&mut chat_history)?;
+            }
+            Some(ConversationActions::Duplicate) => {
+                let mut copy = storage.load(&summary.location)?.into_copy();
+                let location = storage.save(&mut copy)?;
+                term.write_line(&format!("Saved copy to {location}"))?;
+            }
+            Some(ConversationActions::Delete) => {
+                let confirmed = Confirm::with_theme(term_theme)
+                    .with_prompt(format!(
+                        "Delete {}?",
+                        summary.title.as_deref().unwrap_or("untitled conversation")
+                    ))
+                    .default(false)
+                    .interact_on(term)?;
+                if confirmed {
+                    storage.delete(&summary.location)?;
+                }
+            }
+            Some(ConversationActions::Back) | None => (),
+        }
+    }
+}
+
+/// Let user pick alternative version of a message on the active branch
+fn switch_branch(
+    chat_manager: &mut chat_manager::ChatHistory,
+    term: &Term,
+    term_theme: &ColorfulTheme,
+) -> anyhow::Result<()> {
+    let tree = chat_manager.message_tree();
+    let branch_points: Vec<_> = tree
+        .active_path()
+        .into_iter()
+        .filter(|id| tree.siblings(*id).len() > 1)
+        .collect();
+    if branch_points.is_empty() {
+        term.write_line("Conversation has no other branches")?;
+        return Ok(());
+    }
+
+    let branch_point_names: Vec<_> = branch_points
+        .iter()
+        .filter_map(|id| tree.get(*id))
+        .map(|node| format!("{}: {}", node.message.role, preview(&node.message.content)))
+        .collect();
+    let Some(branch_point) = FuzzySelect::with_theme(term_theme)
+        .with_prompt("Select message")
+        .items(&branch_point_names)
+        .default(branch_points.len() - 1)
+        .interact_on_opt(term)?
+    else {
+        return Ok(());
+    };
+    let branch_point = branch_points[branch_point];
+
+    let alternatives = tree.siblings(branch_point);
+    let alternative_names: Vec<_> = alternatives
+        .iter()
+        .filter_map(|id| tree.get(*id))
+        .enumerate()
+        .map(|(index, node)| format!("{}. {}", index + 1, preview(&node.message.content)))
+        .collect();
+    let current = alternatives
+        .iter()
+        .position(|id| *id == branch_point)
+        .unwrap_or_default();
+    let Some(alternative) = FuzzySelect::with_theme(term_theme)
+        .with_prompt("Select alternative")
+        .items(&alternative_names)
+        .default(current)
+        .interact_on_opt(term)?
+    else {
+        return Ok(());
+    };
+
+    chat_manager.switch_branch(alternatives[alternative])?;
+    chat_manager.print_history(term)?;
+    Ok(())
+}
+
+/// Let user change one sampling parameter of the conversation
+fn set_sampling_parameter(
+    chat_manager: &mut chat_manager::ChatHistory,
+    term: &Term,
+    term_theme: &ColorfulTheme,
+) -> anyhow::Result<()> {
+    let mut sampling = chat_manager.sampling_parameters().clone();
+    let parameter_names: Vec<_> = SamplingParameters::NAMES
+        .iter()
+        .map(|name| {
+            format!(
+                "{name}: {}",
+                sampling
+                    .get(name)
+                    .unwrap_or_else(|| String::from("default"))
+            )
+        })
+        .collect();
+    let Some(selection) = FuzzySelect::with_theme(term_theme)
+        .with_prompt("Select parameter")
+        .items(&parameter_names)
+        .default(0)
+        .interact_on_opt(term)?
+    else {
+        return Ok(());
+    };
+    let name = SamplingParameters::NAMES[selection];
+    let value: String = Input::with_theme(term_theme)
+        .with_prompt(format!("{name} (empty for default)"))
+        .with_initial_text(sampling.get(name).unwrap_or_default())
+        .allow_empty(true)
+        .interact_text_on(term)?;
+    match sampling.set(name, &value) {
+        Ok(()) => {
+            chat_manager.set_sampling_parameters(sampling)?;
+            term.write_line(&format!(
+                "Set {name} to {}",
+                chat_manager
+                    .sampling_parameters()
+                    .get(name)
+                    .unwrap_or_else(|| String::from("default"))
+            ))?;
+        }
+        Err(error) => term.write_line(&format!("{error:#}"))?,
+    }
+    Ok(())
+}
+
+/// Let user pick any message of active branch and change it in `$EDITOR`
+///
+/// Edit goes to a new branch, returns true when it ends with a question that should be answered
+fn edit_message_in_editor(
+    chat_manager: &mut chat_manager::ChatHistory,
+    term: &Term,
+    term_theme: &ColorfulTheme,
+) -> anyhow::Result<bool> {
+    let message_names: Vec<_> = chat_manager
+        .message_tree()
+        .messages()
+        .iter()
+        .enumerate()
+        .map(|(index, message)| {
+            format!(
+                "{}. {}: {}",
+                index + 1,
+                message.role,
+                preview(&message.content)
+            )
+        })
+        .collect();
+    if message_names.is_empty() {
+        term.write_line("No messages to edit")?;
+        return Ok(false);
+    }
+    let Some(index) = FuzzySelect::with_theme(term_theme)
+        .with_prompt("Select message")
+        .items(&message_names)
+        .default(message_names.len() - 1)
+        .interact_on_opt(term)?
+    else {
+        return Ok(false);
+    };
+    let content = chat_manager
+        .message_at(index)
+        .context("Selected message does not exist")?
+        .content
+        .clone();
+    let Some(edited) = Editor::new().extension(".md").edit(&content)? else {
+        term.write_line("Message was not saved, nothing changed")?;
+        return Ok(false);
+    };
+    let edited = edited.trim_end();
+    if edited == content.trim_end() {
+        term.write_line("Message did not change")?;
+        return Ok(false);
+    }
+    // original message and everything after it stay available as a branch
+    chat_manager.edit_message_at(index, edited)
+}
+
+/// First line of message shortened for selection lists
+fn preview(content: &str) -> String {
+    const PREVIEW_LENGTH: usize = 60;
+    let line = content.lines().next().unwrap_or_default();
+    if line.chars().count() > PREVIEW_LENGTH {
+        format!(
+            "{}...",
+            line.chars().take(PREVIEW_LENGTH).collect::<String>()
+        )
+    } else {
+        line.to_owned()
+    }
+}
+
+/// First Ctrl-C stops the response that is being generated
+///
+/// Any other Ctrl-C asks main loop to exit once conversation is saved
+fn spawn_ctrl_c_handler(cancellation: StreamCancellation, exit_requested: Arc<AtomicBool>) {
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if !cancellation.cancel() {
+                exit_requested.store(true, Ordering::SeqCst);
+            }
+        }
+    });
 }
 
 #[derive(Debug, Clone, Copy)]
+enum ConversationActions {
+    Continue,
+    Rename,
+    ToggleStar,
+    Duplicate,
+    Delete,
+    Back,
+}
+
+impl ConversationActions {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ConversationActions::Continue => "Continue",
+            ConversationActions::Rename => "Rename",
+            ConversationActions::ToggleStar => "Star / unstar",
+            ConversationActions::Duplicate => "Duplicate",
+            ConversationActions::Delete => "Delete",
+            ConversationActions::Back => "Back",
+        }
+    }
+
+    fn all() -> &'static [ConversationActions] {
+        &[
+            ConversationActions::Continue,
+            ConversationActions::Rename,
+            ConversationActions::ToggleStar,
+            ConversationActions::Duplicate,
+            ConversationActions::Delete,
+            ConversationActions::Back,
+        ]
+    }
+
+    fn all_str() -> Vec<&'static str> {
+        Self::all().iter().map(|opt| opt.as_str()).collect()
+    }
+}
+
 enum UserActions {
     ReturnToChat,
     RecreateTitle,
     RegenerateResponse,
+    EditQuestion,
+    EditMessageInEditor,
+    SwitchBranch,
     PrintChatHistory,
+    SwitchModel,
+    SwitchPersona,
+    SetSamplingParameter,
 }
 
 impl UserActions {
@@ -182,7 +1428,13 @@ impl UserActions {
             UserActions::ReturnToChat => "Return to chat",
             UserActions::RecreateTitle => "Recreate title",
             UserActions::RegenerateResponse => "Regenerate response",
+            UserActions::EditQuestion => "Edit question",
+            UserActions::EditMessageInEditor => "Edit message in $EDITOR",
+            UserActions::SwitchBranch => "Switch branch",
             UserActions::PrintChatHistory => "Print chat history",
+            UserActions::SwitchModel => "Switch model",
+            UserActions::SwitchPersona => "Switch persona",
+            UserActions::SetSamplingParameter => "Set sampling parameter",
         }
     }
 
@@ -191,7 +1443,13 @@ impl UserActions {
             UserActions::ReturnToChat,
             UserActions::RecreateTitle,
             UserActions::RegenerateResponse,
+            UserActions::EditQuestion,
+            UserActions::EditMessageInEditor,
+            UserActions::SwitchBranch,
             UserActions::PrintChatHistory,
+            UserActions::SwitchModel,
+            UserActions::SwitchPersona,
+            UserActions::SetSamplingParameter,
         ]
     }
 
@@ -51,7 +51,7 @@ async fn main() -> anyhow::Result<()> {
         // this is a meh way to do this
         let config_new = AppConfig {
             open_ai_api_key: String::from("EMPTY_TOKEN"),
-            mqtt: None,
+            ..Default::default()
         };
         config_new.save_user_config()?;
         return Ok(());
@@ -0,0 +1,70 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::Notify;
+
+/// Shared handle used to stop a response early
+///
+/// [crate::chat_manager::ChatHistory] marks the handle active while it is generating a response,
+/// so that a signal handler can tell whether there is anything to cancel
+#[derive(Debug, Clone, Default)]
+pub struct StreamCancellation {
+    inner: Arc<CancellationState>,
+}
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    active: AtomicBool,
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl StreamCancellation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel response that is currently generated
+    ///
+    /// Returns false if there was no active response to cancel
+    pub fn cancel(&self) -> bool {
+        if !self.inner.active.load(Ordering::SeqCst) || self.is_cancelled() {
+            return false;
+        }
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.inner.active.load(Ordering::SeqCst)
+    }
+
+    /// mark start of a new response
+    pub(crate) fn start(&self) {
+        self.inner.cancelled.store(false, Ordering::SeqCst);
+        self.inner.active.store(true, Ordering::SeqCst);
+    }
+
+    /// mark end of response
+    pub(crate) fn finish(&self) {
+        self.inner.active.store(false, Ordering::SeqCst);
+    }
+
+    /// wait until cancelled
+    pub async fn cancelled(&self) {
+        loop {
+            // register before checking flag so that we can't miss notification
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
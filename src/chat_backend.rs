@@ -0,0 +1,211 @@
+use anyhow::{Context, Result};
+use async_openai::{
+    error::OpenAIError,
+    types::{
+        ChatCompletionResponseStream, CreateChatCompletionRequest, CreateChatCompletionResponse,
+        CreateChatCompletionStreamResponse,
+    },
+    Client,
+};
+use async_trait::async_trait;
+use serde_json::json;
+use std::{collections::VecDeque, sync::Mutex};
+
+/// Something that can answer chat completion requests
+///
+/// [crate::chat_manager::ChatHistory] only talks to the model through this trait
+/// so that conversations can be driven without network access
+#[async_trait]
+pub trait ChatBackend: Send + Sync {
+    /// request the whole response at once
+    async fn complete(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse>;
+
+    /// request response as a stream of deltas
+    async fn complete_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<ChatCompletionResponseStream>;
+}
+
+#[async_trait]
+impl ChatBackend for Client {
+    async fn complete(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse> {
+        Ok(self.chat().create(request).await?)
+    }
+
+    async fn complete_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<ChatCompletionResponseStream> {
+        Ok(self.chat().create_stream(request).await?)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ScriptedResponse {
+    /// content of every choice
+    Message(Vec<String>),
+    Error(String),
+}
+
+/// In-process backend that replays prepared responses in order
+///
+/// Every request is recorded so that callers can inspect what would have been sent to the API
+#[derive(Debug, Default)]
+pub struct ScriptedChatBackend {
+    responses: Mutex<VecDeque<ScriptedResponse>>,
+    requests: Mutex<Vec<CreateChatCompletionRequest>>,
+}
+
+impl ScriptedChatBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// queue response and return self
+    pub fn with_response(self, content: &str) -> Self {
+        self.push_response(content);
+        self
+    }
+
+    /// queue assistant response
+    pub fn push_response(&self, content: &str) {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back(ScriptedResponse::Message(vec![content.to_owned()]));
+    }
+
+    /// queue response with one choice per content, like a request with `n` set
+    pub fn push_choices(&self, contents: &[&str]) {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back(ScriptedResponse::Message(
+                contents
+                    .iter()
+                    .map(|content| (*content).to_owned())
+                    .collect(),
+            ));
+    }
+
+    /// queue failed request
+    pub fn push_error(&self, message: &str) {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back(ScriptedResponse::Error(message.to_owned()));
+    }
+
+    /// requests received so far
+    pub fn requests(&self) -> Vec<CreateChatCompletionRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// number of queued responses that were not consumed yet
+    pub fn remaining_responses(&self) -> usize {
+        self.responses.lock().unwrap().len()
+    }
+
+    fn next_response(&self, request: CreateChatCompletionRequest) -> Result<Vec<String>> {
+        self.requests.lock().unwrap().push(request);
+        match self
+            .responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .context("Scripted backend ran out of responses")?
+        {
+            ScriptedResponse::Message(contents) => Ok(contents),
+            ScriptedResponse::Error(message) => Err(anyhow::anyhow!(message)),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatBackend for ScriptedChatBackend {
+    async fn complete(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse> {
+        let model = request.model.clone();
+        let choices: Vec<_> = self
+            .next_response(request)?
+            .into_iter()
+            .enumerate()
+            .map(|(index, content)| {
+                json!({
+                    "index": index,
+                    "message": {"role": "assistant", "content": content},
+                    "finish_reason": "stop"
+                })
+            })
+            .collect();
+        let response = json!({
+            "id": "scripted",
+            "object": "chat.completion",
+            "created": 0,
+            "model": model,
+            "choices": choices
+        });
+        Ok(serde_json::from_value(response)?)
+    }
+
+    async fn complete_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<ChatCompletionResponseStream> {
+        let model = request.model.clone();
+        let contents = self.next_response(request)?;
+
+        // first delta only carries role, same as the real API
+        let mut per_choice: Vec<VecDeque<_>> = contents
+            .iter()
+            .map(|content| {
+                std::iter::once(json!({"role": "assistant"}))
+                    .chain(
+                        content
+                            .split_inclusive(' ')
+                            .map(|chunk| json!({ "content": chunk })),
+                    )
+                    .collect()
+            })
+            .collect();
+        // choices take turns like they do with n > 1
+        let mut interleaved = vec![];
+        while per_choice.iter().any(|deltas| !deltas.is_empty()) {
+            for (index, deltas) in per_choice.iter_mut().enumerate() {
+                if let Some(delta) = deltas.pop_front() {
+                    let finish_reason = deltas.is_empty().then_some("stop");
+                    interleaved.push((index, delta, finish_reason));
+                }
+            }
+        }
+
+        let chunks: Vec<Result<CreateChatCompletionStreamResponse, OpenAIError>> = interleaved
+            .into_iter()
+            .map(|(index, delta, finish_reason)| {
+                let chunk = json!({
+                    "id": "scripted",
+                    "object": "chat.completion.chunk",
+                    "created": 0,
+                    "model": model,
+                    "choices": [{
+                        "index": index,
+                        "delta": delta,
+                        "finish_reason": finish_reason
+                    }]
+                });
+                serde_json::from_value(chunk).map_err(OpenAIError::JSONDeserialize)
+            })
+            .collect();
+
+        Ok(Box::pin(futures::stream::iter(chunks)))
+    }
+}
@@ -1,53 +1,636 @@
 use crate::{
-    configuration::get_project_dirs,
+    cancellation::StreamCancellation,
+    chat_backend::ChatBackend,
+    configuration::{ContextWindowConfig, OverflowStrategy, SamplingParameters},
+    conversation_files::{self, write_atomically, ConversationMetadata},
+    message_tree::{MessageId, MessageNode, MessageTree},
+    migrations,
+    models::ModelInfo,
+    stream_display::ChatStreamSinks,
+    token_counter::{MessageTokenCache, TokenCounter},
+    tools::{
+        is_tool_result, run_tool_call, tool_instructions, tool_result_message, Tool, ToolCall,
+        ToolCallFilter,
+    },
+    usage_ledger::{UsageEntry, UsageLedger},
     utils::{
-        CHAT_GPT_MODEL_NAME, CHAT_GPT_MODEL_TOKEN_LIMIT, INCREASING_TREND_EMOJI,
-        QUESTION_MARK_EMOJI, ROBOT_EMOJI, SYSTEM_EMOJI,
+        INCREASING_TREND_EMOJI, QUESTION_MARK_EMOJI, ROBOT_EMOJI, STOP_EMOJI, SYSTEM_EMOJI,
+        TOOL_EMOJI,
     },
 };
 use anyhow::{Context, Result};
-use async_openai::{
-    types::{
-        ChatCompletionRequestMessage, ChatCompletionRequestMessageArgs,
-        CreateChatCompletionRequestArgs, Role, Usage,
-    },
-    Client,
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestMessageArgs, CreateChatCompletionRequest,
+    CreateChatCompletionRequestArgs, Role, Usage,
 };
-use async_trait::async_trait;
 use chrono::prelude::{DateTime, Local};
 use dialoguer::console::Term;
-use futures::StreamExt;
-use rumqttc::AsyncClient;
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    stream::BoxStream,
+    FutureExt, Stream, StreamExt,
+};
 use serde::{Deserialize, Serialize};
 use std::{
-    borrow::BorrowMut,
+    collections::BTreeMap,
     path::{Path, PathBuf},
+    sync::Arc,
 };
-use tiktoken_rs::cl100k_base;
+use tokio::task::{JoinError, JoinHandle};
+
+/// What to do with partially streamed response after cancellation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CancelledResponse {
+    /// keep partial response in history
+    #[default]
+    Keep,
+    /// drop both partial response and the question that triggered it
+    Discard,
+}
+
+/// Marks stream as finished on every exit path
+struct CancellationGuard {
+    cancellation: Option<StreamCancellation>,
+}
+
+impl Drop for CancellationGuard {
+    fn drop(&mut self) {
+        if let Some(cancellation) = &self.cancellation {
+            cancellation.finish();
+        }
+    }
+}
+
+/// Restores cursor on every exit path
+struct CursorGuard<'a> {
+    term: &'a Term,
+}
+
+impl Drop for CursorGuard<'_> {
+    fn drop(&mut self) {
+        _ = self.term.show_cursor();
+    }
+}
+
+/// Event produced while a response is streamed
+///
+/// See [ChatHistory::next_message_events]
+#[derive(Debug)]
+pub enum ChatEvent {
+    /// role of the response, comes before its content
+    Role(Role),
+    /// next part of the response
+    ContentDelta(String),
+    /// response was a tool call, the model is asked again with its result
+    ToolCall { tool: String },
+    /// response was cancelled with [StreamCancellation]
+    Cancelled,
+    /// token usage after the response
+    Usage {
+        /// as reported by the API
+        recorded: Option<Usage>,
+        /// estimated by local tokenizer for the whole history
+        estimated_tokens: i64,
+        context_window: u32,
+    },
+    /// response is complete and stored in history, this is the last event
+    Finished { content: String },
+    /// turn failed with the original error, this is the last event
+    Error(anyhow::Error),
+}
+
+/// What a streamed turn responds to
+enum Turn {
+    NewMessage(ChatCompletionRequestMessage),
+    Regenerate,
+}
+
+/// Backend and model used to generate conversation titles in the background
+#[derive(Clone)]
+pub struct TitleGenerator {
+    backend: Arc<dyn ChatBackend>,
+    /// conversation model is used when None
+    model: Option<ModelInfo>,
+}
+
+impl TitleGenerator {
+    pub fn new(backend: Arc<dyn ChatBackend>, model: Option<ModelInfo>) -> Self {
+        Self { backend, model }
+    }
+}
+
+/// Result of background title request
+struct GeneratedTitle {
+    title: String,
+    model: ModelInfo,
+    prompt: Vec<ChatCompletionRequestMessage>,
+    usage: Option<Usage>,
+}
+
+const TITLE_INSTRUCTION: &str = "How would you title this conversation up until before this message? Answer in all lowercase with underscores 
+\"_\" between words so that it can be used as a file name. Be concise.";
+
+const SUMMARY_INSTRUCTION: &str =
+    "Summarize the conversation so far. Keep all facts, names and decisions \
+that might be needed to continue it. Be concise.";
+
+/// Message of a request with its token count
+struct RequestMessage {
+    /// None for messages that are not stored in history
+    id: Option<MessageId>,
+    message: ChatCompletionRequestMessage,
+    tokens: i64,
+}
+
+/// Summary of the oldest messages on active branch reused by following requests
+///
+/// Only lives in memory, saved conversation always keeps the original messages
+struct ContextSummary {
+    summarized: Vec<MessageId>,
+    message: ChatCompletionRequestMessage,
+}
+
+/// Index of first message after the leading system prompt
+fn first_droppable_message(messages: &[RequestMessage]) -> usize {
+    messages
+        .iter()
+        .position(|message| message.message.role != Role::System)
+        .unwrap_or(messages.len())
+}
+
+async fn wait_for_cancellation(cancellation: Option<&StreamCancellation>) {
+    match cancellation {
+        Some(cancellation) => cancellation.cancelled().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Upper limit of tool calls the model can make while answering one message
+const MAX_TOOL_ROUNDS: usize = 8;
 
 /// Manager for conversations
 pub struct ChatHistory {
-    history: Vec<ChatCompletionRequestMessage>,
+    history: MessageTree,
     token_usage: Option<Usage>,
     conversation_start: Option<DateTime<Local>>,
     conversation_title: Option<String>,
+    model: ModelInfo,
+    context_window_config: ContextWindowConfig,
+    sampling: SamplingParameters,
+    tools: Vec<Tool>,
+    cancellation: Option<StreamCancellation>,
+    cancelled_response: CancelledResponse,
+    /// file the conversation is saved to once it has been saved or loaded
+    file_path: Option<PathBuf>,
+    usage_ledger: Option<UsageLedger>,
+    token_cache: MessageTokenCache,
+    title_generator: Option<TitleGenerator>,
+    title_task: Option<JoinHandle<Result<GeneratedTitle>>>,
+    /// file name doesn't match the current title, it's renamed on next save
+    file_name_outdated: bool,
+    /// kept by retention policy
+    starred: bool,
+    /// request titles after responses
+    generate_titles: bool,
+    context_summary: Option<ContextSummary>,
 }
 
 impl ChatHistory {
     pub fn new(prompt: &str) -> anyhow::Result<Self> {
-        let history = vec![ChatCompletionRequestMessageArgs::default()
-            .content(prompt)
-            .role(Role::System)
-            .build()?];
+        let mut history = MessageTree::new();
+        history.push(
+            ChatCompletionRequestMessageArgs::default()
+                .content(prompt)
+                .role(Role::System)
+                .build()?,
+        );
         let dt: DateTime<Local> = Local::now();
         Ok(Self {
             history,
             token_usage: None,
             conversation_start: Some(dt),
             conversation_title: None,
+            model: ModelInfo::default(),
+            context_window_config: ContextWindowConfig::default(),
+            sampling: SamplingParameters::default(),
+            tools: vec![],
+            cancellation: None,
+            cancelled_response: CancelledResponse::default(),
+            file_path: None,
+            usage_ledger: None,
+            token_cache: MessageTokenCache::default(),
+            title_generator: None,
+            title_task: None,
+            file_name_outdated: false,
+            starred: false,
+            generate_titles: true,
+            context_summary: None,
         })
     }
 
+    /// Model used for requests
+    pub fn model(&self) -> &ModelInfo {
+        &self.model
+    }
+
+    pub fn conversation_title(&self) -> Option<&str> {
+        self.conversation_title.as_deref()
+    }
+
+    pub fn conversation_start(&self) -> Option<DateTime<Local>> {
+        self.conversation_start
+    }
+
+    /// Starred conversations are never removed by retention policy
+    pub fn is_starred(&self) -> bool {
+        self.starred
+    }
+
+    pub fn set_starred(&mut self, starred: bool) {
+        self.starred = starred;
+    }
+
+    /// Identifier of the conversation used in usage records
+    pub fn conversation_id(&self) -> Option<String> {
+        self.conversation_start.map(|start| start.to_rfc3339())
+    }
+
+    /// Summary shown in conversation lists
+    pub fn metadata(&self) -> ConversationMetadata {
+        let messages: Vec<_> = self
+            .history
+            .active_path()
+            .into_iter()
+            .filter_map(|id| self.history.get(id))
+            .map(|node| &node.message)
+            .filter(|message| message.role == Role::User || message.role == Role::Assistant)
+            .collect();
+        ConversationMetadata {
+            title: self.conversation_title.clone(),
+            start: self.conversation_start,
+            model: self.model.name.clone(),
+            starred: self.starred,
+            message_count: messages.len(),
+            first_question: messages
+                .iter()
+                .find(|message| message.role == Role::User)
+                .map(|message| {
+                    message
+                        .content
+                        .chars()
+                        .take(conversation_files::FIRST_QUESTION_PREVIEW_LENGTH)
+                        .collect()
+                }),
+        }
+    }
+
+    /// Record usage of every request made for this conversation
+    pub fn set_usage_ledger(&mut self, usage_ledger: UsageLedger) {
+        self.usage_ledger = Some(usage_ledger);
+    }
+
+    /// Turn off title requests, for example when the conversation isn't saved
+    pub fn set_generate_titles(&mut self, generate_titles: bool) {
+        self.generate_titles = generate_titles;
+    }
+
+    /// Generate titles in the background instead of waiting for them after the first response
+    pub fn set_title_generator(&mut self, title_generator: TitleGenerator) {
+        self.title_generator = Some(title_generator);
+    }
+
+    /// Add request to usage ledger
+    ///
+    /// Tokens are counted locally from `prompt` if the API didn't report usage, which is always the case for streaming.
+    /// Failing to write the ledger doesn't fail the request, failing to load the tokenizer does
+    fn record_usage(
+        &self,
+        usage: Option<&Usage>,
+        prompt: &[ChatCompletionRequestMessage],
+        response: &str,
+    ) -> Result<()> {
+        self.record_model_usage(&self.model, usage, prompt, response)
+    }
+
+    /// [ChatHistory::record_usage] for request made with a different model
+    fn record_model_usage(
+        &self,
+        model: &ModelInfo,
+        usage: Option<&Usage>,
+        prompt: &[ChatCompletionRequestMessage],
+        response: &str,
+    ) -> Result<()> {
+        let Some(usage_ledger) = &self.usage_ledger else {
+            return Ok(());
+        };
+        let entry = match usage {
+            Some(usage) => UsageEntry::chat(
+                model,
+                self.conversation_id(),
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                false,
+            ),
+            None => {
+                let token_counter = TokenCounter::for_model(model)?;
+                let prompt_tokens = token_counter.count_prompt(prompt.iter());
+                UsageEntry::chat(
+                    model,
+                    self.conversation_id(),
+                    prompt_tokens.max(0) as u32,
+                    token_counter.count_text(response) as u32,
+                    true,
+                )
+            }
+        };
+        if let Err(error) = usage_ledger.record(&entry) {
+            tracing::warn!("Failed to record usage {:?}", error);
+        }
+        Ok(())
+    }
+
+    /// File the conversation is written to by [ChatHistory::save_to_file]
+    ///
+    /// None until conversation is saved for the first time
+    pub fn file_path(&self) -> Option<&Path> {
+        self.file_path.as_deref()
+    }
+
+    /// Switch model used for following requests
+    pub fn set_model(&mut self, model: ModelInfo) {
+        self.model = model;
+        // tokenizer and counting rules depend on the model
+        self.token_cache.clear();
+    }
+
+    /// Configure how history is trimmed when it gets close to the model limit
+    pub fn set_context_window_config(&mut self, config: ContextWindowConfig) {
+        self.context_window_config = config;
+    }
+
+    /// Sampling parameters used for requests
+    pub fn sampling_parameters(&self) -> &SamplingParameters {
+        &self.sampling
+    }
+
+    /// Set sampling parameters used for following requests
+    ///
+    /// Parameters are saved with the conversation
+    pub fn set_sampling_parameters(&mut self, sampling: SamplingParameters) -> Result<()> {
+        sampling.validate()?;
+        self.sampling = sampling;
+        Ok(())
+    }
+
+    /// Allow responses to be stopped early using `cancellation`
+    pub fn set_cancellation(&mut self, cancellation: StreamCancellation) {
+        self.cancellation = Some(cancellation);
+    }
+
+    /// Choose what happens with partial response when it is cancelled
+    pub fn set_cancelled_response(&mut self, cancelled_response: CancelledResponse) {
+        self.cancelled_response = cancelled_response;
+    }
+
+    /// Make tool available to the model
+    pub fn register_tool(&mut self, tool: Tool) {
+        self.tools.retain(|existing| existing.name() != tool.name());
+        self.tools.push(tool);
+    }
+
+    pub fn tools(&self) -> &[Tool] {
+        &self.tools
+    }
+
+    /// Number of tokens the history can use while leaving room for the response
+    fn prompt_token_budget(&self) -> Result<i64> {
+        Ok(self.model.context_window as i64
+            - self.context_window_config.reserved_response_tokens as i64
+            - self.tool_instruction_tokens()?)
+    }
+
+    /// Tokens of the tool instructions message added by [ChatHistory::build_request]
+    fn tool_instruction_tokens(&self) -> Result<i64> {
+        if self.tools.is_empty() {
+            return Ok(0);
+        }
+        let token_counter = self.token_counter()?;
+        Ok(tool_instructions(&self.tools)
+            .map(|instructions| {
+                token_counter.count_message(&ChatCompletionRequestMessage {
+                    role: Role::System,
+                    content: instructions,
+                    name: None,
+                })
+            })
+            .unwrap_or_default())
+    }
+
+    /// Active branch as it's sent to the API before fitting it into context window
+    fn request_messages(&self) -> Result<Vec<RequestMessage>> {
+        let token_counter = self.token_counter()?;
+        Ok(self
+            .history
+            .active_path()
+            .into_iter()
+            .filter_map(|id| self.history.get(id))
+            .map(|node| RequestMessage {
+                id: Some(node.id),
+                tokens: self
+                    .token_cache
+                    .get_or_count(node.id, &node.message, &token_counter),
+                message: node.message.clone(),
+            })
+            .collect())
+    }
+
+    /// Make sure request `messages` fit into context window of the model
+    ///
+    /// Applies configured [OverflowStrategy] to the request only, history is never changed.
+    /// Summary is kept and reused by following requests. The latest message is never removed
+    async fn fit_context_window<B: ChatBackend + ?Sized>(
+        &mut self,
+        messages: &mut Vec<RequestMessage>,
+        backend: &B,
+    ) -> Result<()> {
+        let budget = self.prompt_token_budget()? - self.token_counter()?.rules().reply_priming;
+        let fits = |messages: &[RequestMessage]| {
+            messages.iter().map(|message| message.tokens).sum::<i64>() <= budget
+        };
+        if fits(messages) {
+            return Ok(());
+        }
+        match self.context_window_config.overflow_strategy {
+            OverflowStrategy::Disabled => return Ok(()),
+            OverflowStrategy::DropOldest => (),
+            OverflowStrategy::Summarize => {
+                self.apply_context_summary(messages)?;
+                if !fits(messages) {
+                    if let Err(error) = self.summarize_older_messages(messages, backend).await {
+                        tracing::warn!("Failed to summarize conversation {:?}", error);
+                    }
+                }
+            }
+        }
+        while !fits(messages) {
+            let index = first_droppable_message(messages);
+            if index + 1 >= messages.len() {
+                anyhow::bail!(
+                    "Message does not fit into context window of {}",
+                    self.model.name
+                );
+            }
+            messages.remove(index);
+        }
+        Ok(())
+    }
+
+    /// Replace messages covered by previous summary with it
+    fn apply_context_summary(&self, messages: &mut Vec<RequestMessage>) -> Result<()> {
+        let Some(context_summary) = &self.context_summary else {
+            return Ok(());
+        };
+        let start = first_droppable_message(messages);
+        let end = start + context_summary.summarized.len();
+        let is_summarized = messages.len() > end
+            && messages[start..end]
+                .iter()
+                .map(|message| message.id)
+                .eq(context_summary.summarized.iter().map(|id| Some(*id)));
+        if is_summarized {
+            messages.splice(
+                start..end,
+                std::iter::once(RequestMessage {
+                    id: None,
+                    tokens: self
+                        .token_counter()?
+                        .count_message(&context_summary.message),
+                    message: context_summary.message.clone(),
+                }),
+            );
+        }
+        Ok(())
+    }
+
+    /// replace older messages of request with a single system message summarizing them
+    async fn summarize_older_messages<B: ChatBackend + ?Sized>(
+        &mut self,
+        messages: &mut Vec<RequestMessage>,
+        backend: &B,
+    ) -> Result<()> {
+        let start = first_droppable_message(messages);
+        let end = messages
+            .len()
+            .saturating_sub(self.context_window_config.summarize_keep_recent_messages);
+        if end <= start {
+            return Ok(());
+        }
+
+        let summarized_messages: Vec<_> = messages[start..end]
+            .iter()
+            .map(|message| message.message.clone())
+            .collect();
+        let summary = self.summarize(&summarized_messages, backend).await?;
+        let summary_message = ChatCompletionRequestMessageArgs::default()
+            .content(format!("Summary of earlier conversation:\n{summary}"))
+            .role(Role::System)
+            .build()?;
+
+        // earlier summary is part of the new one
+        let mut summarized = vec![];
+        for message in &messages[start..end] {
+            match message.id {
+                Some(id) => summarized.push(id),
+                None => summarized.extend(
+                    self.context_summary
+                        .iter()
+                        .flat_map(|context_summary| context_summary.summarized.iter().copied()),
+                ),
+            }
+        }
+        messages.splice(
+            start..end,
+            std::iter::once(RequestMessage {
+                id: None,
+                tokens: self.token_counter()?.count_message(&summary_message),
+                message: summary_message.clone(),
+            }),
+        );
+        self.context_summary = Some(ContextSummary {
+            summarized,
+            message: summary_message,
+        });
+        Ok(())
+    }
+
+    /// Summary of `messages`
+    ///
+    /// Messages are summarized in chunks that fit into context window,
+    /// each chunk starts with the summary of the previous ones
+    async fn summarize<B: ChatBackend + ?Sized>(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        backend: &B,
+    ) -> Result<String> {
+        let token_counter = self.token_counter()?;
+        let instruction = ChatCompletionRequestMessageArgs::default()
+            .content(SUMMARY_INSTRUCTION)
+            .role(Role::User)
+            .build()?;
+        let budget = self.model.context_window as i64
+            - self.context_window_config.reserved_response_tokens as i64
+            - token_counter.count_prompt(std::iter::once(&instruction));
+
+        let mut chunk = vec![];
+        let mut chunk_tokens = 0;
+        for message in messages {
+            let tokens = token_counter.count_message(message);
+            if !chunk.is_empty() && chunk_tokens + tokens > budget {
+                let summary = self.request_summary(chunk, &instruction, backend).await?;
+                let summary_message = ChatCompletionRequestMessageArgs::default()
+                    .content(format!("Summary of earlier conversation:\n{summary}"))
+                    .role(Role::System)
+                    .build()?;
+                chunk_tokens = token_counter.count_message(&summary_message);
+                chunk = vec![summary_message];
+            }
+            if chunk_tokens + tokens > budget {
+                anyhow::bail!(
+                    "Message is too long to be summarized by {}",
+                    self.model.name
+                );
+            }
+            chunk.push(message.clone());
+            chunk_tokens += tokens;
+        }
+        self.request_summary(chunk, &instruction, backend).await
+    }
+
+    async fn request_summary<B: ChatBackend + ?Sized>(
+        &self,
+        mut messages: Vec<ChatCompletionRequestMessage>,
+        instruction: &ChatCompletionRequestMessage,
+        backend: &B,
+    ) -> Result<String> {
+        messages.push(instruction.clone());
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(&self.model.name)
+            .messages(messages.clone())
+            .build()?;
+
+        let response = backend.complete(request).await?;
+        let summary = response
+            .choices
+            .first()
+            .context("No first choice on response")?
+            .message
+            .content
+            .trim()
+            .to_owned();
+        self.record_usage(response.usage.as_ref(), &messages, &summary)?;
+        Ok(summary)
+    }
+
     /// Get Usage as reported by the API
     ///
     /// Usage is not reported in streaming mode for some reason
@@ -57,89 +640,193 @@ impl ChatHistory {
 
     /// Use local tokenizer library to estimate token usage
     ///
-    /// This can be imprecise if we have different tokenization rules than the model
-    pub fn count_tokens(&self) -> i64 {
-        // based on this https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb
-        // but there some weird hacks because the counts weren't lining up
-
-        // used by gpt-3.5-turbo-0301
-        let bpe = cl100k_base().expect("Failed to load cl100k_base");
-        // Start with -1 because somehow we always had 1 extra token
-        let mut token_count = -1_i64;
-        for message in &self.history {
-            // each message adds 4 tokens
-            // because every message follows <im_start>{role/name}\n{content}<im_end>\n
-            match message.role {
-                Role::User => {
-                    token_count += 4;
-                    if let Some(name) = &message.name {
-                        // example says "if there's a name, the role is omitted"
-                        // but it says "role is always required and always 1 token"
-                        // so I don't know
-                        token_count -= 1;
-                        // add name to count
-                        token_count += bpe.encode_with_special_tokens(name).len() as i64;
-                    }
-                }
-                Role::System => {
-                    token_count += 4;
-                }
-                Role::Assistant => {
-                    // Assistant messages should be primed with <im_start>assistant
-                    // so that'd be 2. But from my testing it looks like there are still 4
-                    token_count += 4;
-                }
-            }
+    /// Token counts of messages are cached so only messages added since the last call are encoded
+    pub fn count_tokens(&self) -> Result<i64> {
+        let token_counter = self.token_counter()?;
+        Ok(token_counter.rules().reply_priming
+            + self
+                .history
+                .active_path()
+                .into_iter()
+                .filter_map(|id| self.history.get(id))
+                .map(|node| {
+                    self.token_cache
+                        .get_or_count(node.id, &node.message, &token_counter)
+                })
+                .sum::<i64>())
+    }
 
-            // add role to count
-            token_count += bpe
-                .encode_with_special_tokens(&message.role.to_string())
-                .len() as i64;
+    fn token_counter(&self) -> Result<TokenCounter> {
+        TokenCounter::for_model(&self.model)
+    }
 
-            // add message to count
-            token_count += bpe.encode_with_special_tokens(&message.content).len() as i64;
+    /// Start generating title if the conversation doesn't have one yet
+    ///
+    /// Runs in the background when [TitleGenerator] is set, otherwise waits for the title.
+    /// Failing to generate title doesn't fail the turn, it's tried again after the next response.
+    /// `term` gets the title as soon as it's ready
+    async fn generate_title_if_empty<B: ChatBackend + ?Sized>(
+        &mut self,
+        backend: &B,
+        term: Option<&Term>,
+    ) {
+        self.poll_title();
+        if !self.generate_titles || self.conversation_title.is_some() || self.title_task.is_some() {
+            return;
         }
-        token_count
+        let Some(title_generator) = self.title_generator.clone() else {
+            if let Err(error) = self.populate_title(backend).await {
+                tracing::warn!("Failed to generate title {:?}", error);
+            }
+            return;
+        };
+        let model = title_generator.model.unwrap_or_else(|| self.model.clone());
+        let prompt = match self.title_prompt() {
+            Ok(prompt) => prompt,
+            Err(error) => {
+                tracing::warn!("Failed to generate title {:?}", error);
+                return;
+            }
+        };
+        let term = term.cloned();
+        self.title_task = Some(tokio::spawn(async move {
+            let request = CreateChatCompletionRequestArgs::default()
+                .model(&model.name)
+                .messages(prompt.clone())
+                .build()?;
+            let response = title_generator.backend.complete(request).await?;
+            let title = response
+                .choices
+                .first()
+                .context("No first choice on response")?
+                .message
+                .content
+                .trim()
+                .to_owned();
+            if let Some(term) = term {
+                term.set_title(title.replace('_', " "));
+            }
+            Ok(GeneratedTitle {
+                title,
+                model,
+                prompt,
+                usage: response.usage,
+            })
+        }));
     }
 
-    /// fun attempt at generating titles for chats
-    /// would be great if this could be async
-    async fn populate_title_if_empty(&mut self, client: &Client) -> Result<()> {
-        if self.conversation_title.is_none() {
-            self.populate_title(client).await?;
+    /// Apply title generated in the background if it's ready
+    ///
+    /// Returns the new title
+    pub fn poll_title(&mut self) -> Option<String> {
+        if !self.title_task.as_ref()?.is_finished() {
+            return None;
         }
-        Ok(())
+        let result = self.title_task.take()?.now_or_never()?;
+        self.apply_generated_title(result)
     }
 
-    /// create a new title for the chat using special ChatGPT query
-    pub async fn populate_title(&mut self, client: &Client) -> Result<()> {
-        let mut history_copy = self.history.clone();
-        let message =
-                "How would you title this conversation up until before this message? Answer in all lowercase with underscores 
-\"_\" between words so that it can be used as a file name. Be concise.";
+    /// Wait for title that is being generated in the background
+    ///
+    /// Returns the new title
+    pub async fn wait_for_title(&mut self) -> Option<String> {
+        let result = self.title_task.take()?.await;
+        self.apply_generated_title(result)
+    }
 
-        let user_message = ChatCompletionRequestMessageArgs::default()
-            .content(message)
-            .role(Role::User)
-            .build()?;
+    fn apply_generated_title(
+        &mut self,
+        result: std::result::Result<Result<GeneratedTitle>, JoinError>,
+    ) -> Option<String> {
+        let generated = match result {
+            Ok(Ok(generated)) => generated,
+            Ok(Err(error)) => {
+                tracing::warn!("Failed to generate title {:?}", error);
+                return None;
+            }
+            Err(error) => {
+                tracing::warn!("Title task failed {:?}", error);
+                return None;
+            }
+        };
+        if let Err(error) = self.record_model_usage(
+            &generated.model,
+            generated.usage.as_ref(),
+            &generated.prompt,
+            &generated.title,
+        ) {
+            tracing::warn!("Failed to record title usage {:?}", error);
+        }
+        self.set_title(&generated.title);
+        Some(generated.title)
+    }
 
-        history_copy.push(user_message);
+    /// Replace title, saved file is renamed to match it on next save
+    ///
+    /// Title that is being generated in the background is discarded
+    pub fn set_title(&mut self, title: &str) {
+        if let Some(title_task) = self.title_task.take() {
+            title_task.abort();
+        }
+        self.conversation_title = Some(title.to_owned());
+        self.file_name_outdated = self.file_path.is_some();
+    }
+
+    /// History followed by request for title
+    fn title_prompt(&self) -> Result<Vec<ChatCompletionRequestMessage>> {
+        let mut prompt = self.history.to_messages();
+        prompt.push(
+            ChatCompletionRequestMessageArgs::default()
+                .content(TITLE_INSTRUCTION)
+                .role(Role::User)
+                .build()?,
+        );
+        Ok(prompt)
+    }
+
+    /// create a new title for the chat using special ChatGPT query
+    ///
+    /// Replaces title that is being generated in the background
+    pub async fn populate_title<B: ChatBackend + ?Sized>(&mut self, backend: &B) -> Result<()> {
+        if let Some(title_task) = self.title_task.take() {
+            title_task.abort();
+        }
+        let model = self
+            .title_generator
+            .as_ref()
+            .and_then(|title_generator| title_generator.model.clone())
+            .unwrap_or_else(|| self.model.clone());
+        let prompt = self.title_prompt()?;
 
         let request = CreateChatCompletionRequestArgs::default()
-            .model(CHAT_GPT_MODEL_NAME)
-            .messages(history_copy)
+            .model(&model.name)
+            .messages(prompt.clone())
             .build()?;
 
-        let response = client.chat().create(request).await?;
-
-        let title = response.choices[0].message.content.trim().to_owned();
-        self.conversation_title = Some(title);
+        let response = backend.complete(request).await?;
+
+        let title = response
+            .choices
+            .first()
+            .context("No first choice on response")?
+            .message
+            .content
+            .trim()
+            .to_owned();
+        self.record_model_usage(&model, response.usage.as_ref(), &prompt, &title)?;
+        self.set_title(&title);
         Ok(())
     }
 
     /// pop and return the last message in history
     pub fn pop_last_message(&mut self) -> Option<ChatCompletionRequestMessage> {
-        self.history.pop()
+        let head = self.history.head()?;
+        let message = self.history.pop();
+        // message stays in the tree if other branches continue from it
+        if self.history.get(head).is_none() {
+            self.token_cache.invalidate(head);
+        }
+        message
     }
 
     /// Get clone of last message in history
@@ -148,6 +835,237 @@ impl ChatHistory {
         self.history.last().cloned()
     }
 
+    /// All messages of the conversation including alternative branches
+    pub fn message_tree(&self) -> &MessageTree {
+        &self.history
+    }
+
+    /// Make branch going through message `id` the active one
+    ///
+    /// Latest alternative is followed after the message
+    pub fn switch_branch(&mut self, id: MessageId) -> Result<()> {
+        if !self.history.switch_to(id) {
+            anyhow::bail!("Message {id} does not exist");
+        }
+        self.token_usage = None;
+        Ok(())
+    }
+
+    /// Create alternative version of message `id` with new content
+    ///
+    /// Original message and everything after it stays available as a separate branch.
+    /// The new message becomes the end of active branch,
+    /// use [ChatHistory::regenerate_response] or [ChatHistory::regenerate_response_stream_stdout] to answer it
+    pub fn edit_message(&mut self, id: MessageId, content: &str) -> Result<MessageId> {
+        let original = self
+            .history
+            .get(id)
+            .context("Edited message does not exist")?;
+        let message = ChatCompletionRequestMessage {
+            content: content.to_owned(),
+            ..original.message.clone()
+        };
+        let new_id = self
+            .history
+            .add_sibling(id, message)
+            .context("Edited message does not exist")?;
+        self.token_usage = None;
+        Ok(new_id)
+    }
+
+    /// Move head of active branch back to the last user message
+    ///
+    /// Responses after it stay in the tree so new response becomes their sibling
+    fn rewind_to_last_user_message(&mut self) -> Result<MessageId> {
+        let user_message = self
+            .history
+            .active_path()
+            .into_iter()
+            .rev()
+            .find(|id| {
+                self.history
+                    .get(*id)
+                    .map(|node| node.message.role == Role::User)
+                    .unwrap_or(false)
+            })
+            .context("There is no user message to respond to")?;
+        self.history.set_head(user_message);
+        self.token_usage = None;
+        Ok(user_message)
+    }
+
+    /// Pop messages added after message `id`
+    ///
+    /// Does nothing if the message is no longer on active branch
+    fn rewind_to(&mut self, id: MessageId) {
+        if !self.history.active_path().contains(&id) {
+            return;
+        }
+        while self.history.head() != Some(id) {
+            self.pop_last_message();
+        }
+    }
+
+    /// Drop responses to the question at `user_message_id` and the question itself if it was new
+    fn discard_cancelled_turn(&mut self, user_message_id: MessageId, discard_question: bool) {
+        self.rewind_to(user_message_id);
+        if discard_question && self.history.head() == Some(user_message_id) {
+            self.pop_last_message();
+        }
+    }
+
+    /// Remove last question and responses to it from the active branch
+    ///
+    /// Returns the removed question
+    pub fn undo_last_question(&mut self) -> Option<ChatCompletionRequestMessage> {
+        let path = self.history.active_path();
+        let question_index = path.iter().rposition(|id| {
+            self.history
+                .get(*id)
+                .map(|node| node.message.role == Role::User)
+                .unwrap_or(false)
+        })?;
+        let mut removed = None;
+        for _ in question_index..path.len() {
+            removed = self.pop_last_message();
+        }
+        self.token_usage = None;
+        removed
+    }
+
+    /// Continue as a new conversation with the same system message and settings
+    ///
+    /// Conversation is saved to a new file, title that is being generated is discarded
+    pub fn start_new_conversation(&mut self) {
+        let system_message = self
+            .message_at(0)
+            .filter(|message| message.role == Role::System)
+            .cloned();
+        if let Some(title_task) = self.title_task.take() {
+            title_task.abort();
+        }
+        self.history = MessageTree::new();
+        if let Some(system_message) = system_message {
+            self.history.push(system_message);
+        }
+        self.token_cache.clear();
+        self.context_summary = None;
+        self.token_usage = None;
+        self.conversation_start = Some(Local::now());
+        self.conversation_title = None;
+        self.file_path = None;
+        self.file_name_outdated = false;
+        self.starred = false;
+    }
+
+    /// Id of message at `index` of active branch
+    pub fn message_id_at(&self, index: usize) -> Option<MessageId> {
+        self.history.active_path().get(index).copied()
+    }
+
+    /// Message at `index` of active branch, see [MessageTree::messages] for the whole list
+    pub fn message_at(&self, index: usize) -> Option<&ChatCompletionRequestMessage> {
+        self.history
+            .get(self.message_id_at(index)?)
+            .map(|node| &node.message)
+    }
+
+    /// Change content of message at `index` in place
+    ///
+    /// Messages after it are kept as they are, use [ChatHistory::edit_message_at] to answer again
+    pub fn replace_message_at(&mut self, index: usize, content: &str) -> Result<()> {
+        let id = self
+            .message_id_at(index)
+            .with_context(|| format!("There is no message {index}"))?;
+        let message = ChatCompletionRequestMessage {
+            content: content.to_owned(),
+            ..self
+                .history
+                .get(id)
+                .context("Message does not exist")?
+                .message
+                .clone()
+        };
+        self.history.replace(id, message);
+        self.token_cache.invalidate(id);
+        // summary might include the old content
+        self.context_summary = None;
+        self.token_usage = None;
+        Ok(())
+    }
+
+    /// Remove message at `index`, messages after it move up
+    pub fn delete_message_at(&mut self, index: usize) -> Result<ChatCompletionRequestMessage> {
+        let id = self
+            .message_id_at(index)
+            .with_context(|| format!("There is no message {index}"))?;
+        let message = self.history.remove(id).context("Message does not exist")?;
+        self.token_cache.invalidate(id);
+        self.token_usage = None;
+        Ok(message)
+    }
+
+    /// Insert message so that it ends up at `index`
+    ///
+    /// Index equal to the number of messages appends it
+    pub fn insert_message_at(&mut self, index: usize, content: &str, role: Role) -> Result<()> {
+        let message = ChatCompletionRequestMessage {
+            role,
+            content: content.to_owned(),
+            name: None,
+        };
+        if index == self.history.len() {
+            self.history.push(message);
+        } else {
+            let id = self
+                .message_id_at(index)
+                .with_context(|| format!("Can't insert message at {index}"))?;
+            self.history.insert_before(id, message);
+        }
+        self.token_usage = None;
+        Ok(())
+    }
+
+    /// Replace system message at the start of the conversation or add one
+    ///
+    /// Rest of the conversation is kept, for example when switching persona
+    pub fn set_system_message(&mut self, content: &str) -> Result<()> {
+        match self.message_at(0) {
+            Some(message) if message.role == Role::System => self.replace_message_at(0, content),
+            _ => self.insert_message_at(0, content, Role::System),
+        }
+    }
+
+    /// Edit message at `index` on a new branch that continues up to the next question
+    ///
+    /// Edited question ends the branch. After an edited system prompt or response
+    /// messages are copied until the next question so that it can be answered again.
+    /// Returns true when the branch ends with a question that needs a response
+    pub fn edit_message_at(&mut self, index: usize, content: &str) -> Result<bool> {
+        let path = self.history.active_path();
+        let id = *path
+            .get(index)
+            .with_context(|| format!("There is no message {index}"))?;
+        let new_id = self.edit_message(id, content)?;
+        if self.history.get(new_id).map(|node| &node.message.role) == Some(&Role::User) {
+            return Ok(true);
+        }
+        for id in &path[index + 1..] {
+            let message = self
+                .history
+                .get(*id)
+                .context("Message does not exist")?
+                .message
+                .clone();
+            let is_question = message.role == Role::User;
+            self.history.push(message);
+            if is_question {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     /// Insert message to history
     ///
     /// does *NOT* talk to the api
@@ -163,146 +1081,444 @@ impl ChatHistory {
         Ok(())
     }
 
+    /// Request that will be sent to the API for current history
+    ///
+    /// Messages are fitted into context window and tool instructions are added after the system prompt,
+    /// neither is stored in history
+    async fn build_request<B: ChatBackend + ?Sized>(
+        &mut self,
+        backend: &B,
+    ) -> Result<CreateChatCompletionRequest> {
+        let mut request_messages = self.request_messages()?;
+        self.fit_context_window(&mut request_messages, backend)
+            .await?;
+        let tool_instructions_index = first_droppable_message(&request_messages);
+        let mut messages: Vec<_> = request_messages
+            .into_iter()
+            .map(|message| message.message)
+            .collect();
+        if !self.tools.is_empty() {
+            let tool_instructions = ChatCompletionRequestMessageArgs::default()
+                .content(tool_instructions(&self.tools)?)
+                .role(Role::System)
+                .build()?;
+            messages.insert(tool_instructions_index, tool_instructions);
+        }
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request.model(&self.model.name).messages(messages);
+        self.sampling.apply(&mut request);
+        Ok(request.build()?)
+    }
+
+    /// Store other choices of a response with n > 1 as its siblings
+    ///
+    /// Response `id` stays on the active branch
+    fn add_alternative_responses(
+        &mut self,
+        id: MessageId,
+        alternatives: impl Iterator<Item = ChatCompletionRequestMessage>,
+    ) {
+        for alternative in alternatives {
+            self.history.add_sibling(id, alternative);
+        }
+        self.history.set_head(id);
+    }
+
+    /// Run tool call if the response contains one
+    ///
+    /// Returns the executed call if its result was added to history and the model should be asked again
+    async fn handle_tool_call(&mut self, response: &str) -> Result<Option<ToolCall>> {
+        if self.tools.is_empty() {
+            return Ok(None);
+        }
+        let Some(call) = ToolCall::parse(response) else {
+            return Ok(None);
+        };
+        let result = run_tool_call(&self.tools, &call).await;
+        self.history.push(tool_result_message(&call, &result));
+        self.token_usage = None;
+        Ok(Some(call))
+    }
+
     /// generate next message
-    pub async fn next_message(
+    ///
+    /// Tool calls requested by the model are executed until it returns a final answer
+    pub async fn next_message<B: ChatBackend + ?Sized>(
         &mut self,
         user_message: &str,
-        client: &Client,
+        backend: &B,
     ) -> anyhow::Result<String> {
         let user_message = ChatCompletionRequestMessageArgs::default()
             .content(user_message)
             .role(Role::User)
             .build()?;
 
-        self.history.push(user_message);
+        let user_message_id = self.history.push(user_message);
 
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(CHAT_GPT_MODEL_NAME)
-            .messages(self.history.clone())
-            .build()?;
+        self.complete_response(backend, user_message_id, true).await
+    }
+
+    /// generate new response to the last user message
+    ///
+    /// Previous response is kept as an alternative branch
+    pub async fn regenerate_response<B: ChatBackend + ?Sized>(
+        &mut self,
+        backend: &B,
+    ) -> anyhow::Result<String> {
+        let user_message_id = self.rewind_to_last_user_message()?;
+        self.complete_response(backend, user_message_id, false)
+            .await
+    }
 
-        let response = client.chat().create(request).await?;
+    /// Request responses until the model stops calling tools
+    ///
+    /// Returns empty response when cancelled with [StreamCancellation]
+    async fn complete_response<B: ChatBackend + ?Sized>(
+        &mut self,
+        backend: &B,
+        user_message_id: MessageId,
+        discard_question: bool,
+    ) -> anyhow::Result<String> {
+        let cancellation = self.cancellation.clone();
+        if let Some(cancellation) = &cancellation {
+            cancellation.start();
+        }
+        let _cancellation_guard = CancellationGuard {
+            cancellation: cancellation.clone(),
+        };
 
-        let added_response = ChatCompletionRequestMessageArgs::default()
-            .content(response.choices[0].message.content.clone())
-            .role(response.choices[0].message.role.clone())
-            .build()?;
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let completed = tokio::select! {
+                completed = async {
+                    let request = self.build_request(backend).await?;
+                    let prompt = request.messages.clone();
+                    let response = backend.complete(request).await?;
+                    anyhow::Ok((prompt, response))
+                } => Some(completed?),
+                _ = wait_for_cancellation(cancellation.as_ref()) => None,
+            };
+            let Some((prompt, response)) = completed else {
+                if self.cancelled_response == CancelledResponse::Discard {
+                    self.discard_cancelled_turn(user_message_id, discard_question);
+                }
+                return Ok(String::new());
+            };
+            let choice = response
+                .choices
+                .first()
+                .context("No first choice on response")?;
+            self.record_usage(response.usage.as_ref(), &prompt, &choice.message.content)?;
 
-        self.history.push(added_response);
-        self.token_usage = response.usage;
+            let added_response = ChatCompletionRequestMessageArgs::default()
+                .content(choice.message.content.clone())
+                .role(choice.message.role.clone())
+                .build()?;
 
-        self.populate_title_if_empty(client).await?;
+            let response_id = self.history.push(added_response);
+            if let Some(node) = self.history.get_mut(response_id) {
+                node.usage = response.usage.clone();
+            }
+            self.add_alternative_responses(
+                response_id,
+                response
+                    .choices
+                    .iter()
+                    .skip(1)
+                    .map(|choice| ChatCompletionRequestMessage {
+                        role: choice.message.role.clone(),
+                        content: choice.message.content.clone(),
+                        name: None,
+                    }),
+            );
+            self.token_usage = response.usage.clone();
+
+            let content = choice.message.content.clone();
+            if self.handle_tool_call(&content).await?.is_none() {
+                self.generate_title_if_empty(backend, None).await;
+                return Ok(content);
+            }
+        }
+        anyhow::bail!("Model kept calling tools after {MAX_TOOL_ROUNDS} rounds")
+    }
+
+    /// Stream response to `user_message` as [ChatEvent]s
+    ///
+    /// The turn only makes progress while the stream is polled.
+    /// History is updated before [ChatEvent::Finished] is sent, dropping the stream early abandons the turn
+    pub fn next_message_events<'a, B: ChatBackend + ?Sized>(
+        &'a mut self,
+        user_message: &str,
+        backend: &'a B,
+    ) -> BoxStream<'a, ChatEvent> {
+        let user_message = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: user_message.to_owned(),
+            name: None,
+        };
+        self.response_events(backend, Turn::NewMessage(user_message), None)
+    }
 
-        Ok(response.choices[0].message.content.clone())
+    /// Stream new response to the last user message as [ChatEvent]s
+    ///
+    /// Previous response is kept as an alternative branch
+    pub fn regenerate_response_events<'a, B: ChatBackend + ?Sized>(
+        &'a mut self,
+        backend: &'a B,
+    ) -> BoxStream<'a, ChatEvent> {
+        self.response_events(backend, Turn::Regenerate, None)
     }
 
     /// stream next message to terminal
-    pub async fn next_message_stream_stdout(
+    pub async fn next_message_stream_stdout<B: ChatBackend + ?Sized>(
         &mut self,
         user_message: &str,
-        client: &Client,
+        backend: &B,
         term: &Term,
-        mut chat_streamer: Option<&mut dyn ChatStreamDisplay>,
+        sinks: Option<&ChatStreamSinks>,
     ) -> anyhow::Result<String> {
-        // this probably shouldn't leak abstraction to terminal
-        // but until I have a use case where the abstriction helps this is okay....ish
-        let user_message = ChatCompletionRequestMessageArgs::default()
-            .content(user_message)
-            .role(Role::User)
-            .build()?;
+        let user_message = ChatCompletionRequestMessage {
+            role: Role::User,
+            content: user_message.to_owned(),
+            name: None,
+        };
+        let events =
+            self.response_events(backend, Turn::NewMessage(user_message), Some(term.clone()));
+        let response = print_events(events, term, sinks).await;
+        self.set_term_title(term);
+        response
+    }
 
-        self.history.push(user_message);
+    /// stream new response to the last user message to terminal
+    ///
+    /// Previous response is kept as an alternative branch
+    pub async fn regenerate_response_stream_stdout<B: ChatBackend + ?Sized>(
+        &mut self,
+        backend: &B,
+        term: &Term,
+        sinks: Option<&ChatStreamSinks>,
+    ) -> anyhow::Result<String> {
+        let events = self.response_events(backend, Turn::Regenerate, Some(term.clone()));
+        let response = print_events(events, term, sinks).await;
+        self.set_term_title(term);
+        response
+    }
 
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(CHAT_GPT_MODEL_NAME)
-            .messages(self.history.clone())
-            .build()?;
+    fn set_term_title(&self, term: &Term) {
+        if let Some(title) = &self.conversation_title {
+            term.set_title(title.replace('_', " "));
+        }
+    }
 
-        let mut stream = client.chat().create_stream(request).await?;
+    /// Run turn while the returned stream is polled
+    ///
+    /// Every event goes through one channel so they arrive in order,
+    /// the driving future only adds the final event once the turn is done
+    fn response_events<'a, B: ChatBackend + ?Sized>(
+        &'a mut self,
+        backend: &'a B,
+        turn: Turn,
+        title_term: Option<Term>,
+    ) -> BoxStream<'a, ChatEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        let driver = async move {
+            let last_event = match self
+                .stream_response(backend, &sender, turn, title_term)
+                .await
+            {
+                Ok(content) => ChatEvent::Finished { content },
+                Err(error) => ChatEvent::Error(error),
+            };
+            _ = sender.unbounded_send(last_event);
+        };
+        futures::stream::select(
+            receiver,
+            driver
+                .into_stream()
+                .filter_map(|()| futures::future::ready(None)),
+        )
+        .boxed()
+    }
 
-        let mut response_role = None;
-        let mut response_content_buffer = String::new();
+    /// stream response for `turn` and report progress to `events`
+    async fn stream_response<B: ChatBackend + ?Sized>(
+        &mut self,
+        backend: &B,
+        events: &UnboundedSender<ChatEvent>,
+        turn: Turn,
+        title_term: Option<Term>,
+    ) -> anyhow::Result<String> {
+        // receiver is only gone when nobody polls the turn anymore
+        let emit = |event| {
+            _ = events.unbounded_send(event);
+        };
+        // user message is also dropped when cancelled new message is discarded
+        let (user_message_id, discard_question) = match turn {
+            Turn::NewMessage(user_message) => (self.history.push(user_message), true),
+            Turn::Regenerate => (self.rewind_to_last_user_message()?, false),
+        };
 
-        term.hide_cursor()?;
+        let cancellation = self.cancellation.clone();
+        if let Some(cancellation) = &cancellation {
+            cancellation.start();
+        }
+        let cancellation_guard = CancellationGuard {
+            cancellation: cancellation.clone(),
+        };
 
-        // For reasons not documented in OpenAI docs / OpenAPI spec, the response of streaming call is different and doesn't include all the same fields.
-        while let Some(result) = stream.next().await {
-            let response = result?;
-            if let Some(new_usage) = response.usage {
-                self.token_usage = Some(new_usage);
-            }
+        let mut tool_rounds = 0;
+        let mut cancelled = false;
+        let response_content_buffer = loop {
+            // summarizing and retries can take a while so they are cancelled too
+            let connected = tokio::select! {
+                connected = async {
+                    let request = self.build_request(backend).await?;
+                    let prompt = request.messages.clone();
+                    let stream = backend.complete_stream(request).await?;
+                    anyhow::Ok((prompt, stream))
+                } => Some(connected?),
+                _ = wait_for_cancellation(cancellation.as_ref()) => None,
+            };
+            let Some((prompt, mut stream)) = connected else {
+                cancelled = true;
+                emit(ChatEvent::Cancelled);
+                if self.cancelled_response == CancelledResponse::Discard {
+                    self.discard_cancelled_turn(user_message_id, discard_question);
+                }
+                break String::new();
+            };
+
+            let mut response_role = None;
+            let mut response_usage = None;
+            let mut response_content_buffer = String::new();
+            let mut alternatives: BTreeMap<u32, (Option<Role>, String)> = BTreeMap::new();
+            // tool calls are not shown, only the fact that a tool was called
+            let mut tool_call_filter = (!self.tools.is_empty()).then(ToolCallFilter::default);
+
+            // For reasons not documented in OpenAI docs / OpenAPI spec, the response of streaming call is different and doesn't include all the same fields.
+            loop {
+                let next = tokio::select! {
+                    next = stream.next() => next,
+                    _ = wait_for_cancellation(cancellation.as_ref()) => {
+                        cancelled = true;
+                        None
+                    }
+                };
+                let Some(result) = next else {
+                    break;
+                };
+                let response = result?;
+                if let Some(new_usage) = response.usage {
+                    response_usage = Some(new_usage.clone());
+                    self.token_usage = Some(new_usage);
+                }
 
-            // this ignores if there are multiple choices on the answer
-            let delta = &response
-                .choices
-                .first()
-                .context("No first choice on response")?
-                .delta;
-            // role and content are not guaranteed to be set on all deltas
+                // role and content are not guaranteed to be set on all deltas
+                for choice in &response.choices {
+                    let delta = &choice.delta;
+                    // with n > 1 only the first choice is shown, others become alternatives
+                    if choice.index != 0 {
+                        let (role, content) = alternatives.entry(choice.index).or_default();
+                        if let Some(delta_role) = &delta.role {
+                            *role = Some(delta_role.clone());
+                        }
+                        if let Some(delta_content) = &delta.content {
+                            content.push_str(delta_content);
+                        }
+                        continue;
+                    }
 
-            if let Some(role) = &delta.role {
-                response_role = Some(role.clone());
-            }
+                    if let Some(role) = &delta.role {
+                        response_role = Some(role.clone());
+                        emit(ChatEvent::Role(role.clone()));
+                    }
 
-            if let Some(delta_content) = &delta.content {
-                response_content_buffer.push_str(delta_content);
-                term.write_str(delta_content)?;
-                if let Some(chat_streamer) = chat_streamer.borrow_mut() {
-                    chat_streamer.push_message(delta_content).await?;
+                    if let Some(delta_content) = &delta.content {
+                        response_content_buffer.push_str(delta_content);
+                        let shown = match &mut tool_call_filter {
+                            Some(tool_call_filter) => tool_call_filter.push(delta_content),
+                            None => delta_content.clone(),
+                        };
+                        if !shown.is_empty() {
+                            emit(ChatEvent::ContentDelta(shown));
+                        }
+                    }
                 }
             }
-        }
 
-        // this markdown thing doesn't work very well right now
-        // re-render as markdown
-        // consider adding slowdown for effect?
-        // let lines = response_content_buffer.lines().count();
-        // term.clear_last_lines(lines)?;
-        // // this markdown is weird. Doesn't render correctly I think
-        // let markdown = termimad::inline(&response_content_buffer);
-        // term.write_line(&format!("{}", markdown))?;
-
-        // empty new line after stream is done
-
-        term.write_line("\n")?;
-
-        if let Some(chat_streamer) = chat_streamer.borrow_mut() {
-            chat_streamer.push_message("\n\n").await?;
-        }
-
-        // print usage recorded
-        if let Some(token_usage) = self.token_usage.as_ref() {
-            let msg = format!("{INCREASING_TREND_EMOJI} Recorded usage {}/{CHAT_GPT_MODEL_TOKEN_LIMIT} tokens used",token_usage.total_tokens);
-            term.write_line(&msg)?;
+            if let Some(tool_call_filter) = &mut tool_call_filter {
+                let is_tool_call =
+                    !cancelled && ToolCall::parse(&response_content_buffer).is_some();
+                let rest = tool_call_filter.finish(is_tool_call);
+                if !rest.is_empty() {
+                    emit(ChatEvent::ContentDelta(rest));
+                }
+            }
 
-            if let Some(chat_streamer) = chat_streamer.borrow_mut() {
-                chat_streamer.push_message(&msg).await?;
+            // cancelled responses are billed too
+            self.record_usage(response_usage.as_ref(), &prompt, &response_content_buffer)?;
+
+            if cancelled {
+                emit(ChatEvent::Cancelled);
+                match self.cancelled_response {
+                    CancelledResponse::Keep => {
+                        if !response_content_buffer.is_empty() {
+                            let added_response = ChatCompletionRequestMessageArgs::default()
+                                .content(&response_content_buffer)
+                                .role(response_role.unwrap_or(Role::Assistant))
+                                .build()?;
+                            self.history.push(added_response);
+                        }
+                        break response_content_buffer;
+                    }
+                    CancelledResponse::Discard => {
+                        self.discard_cancelled_turn(user_message_id, discard_question);
+                        break String::new();
+                    }
+                }
             }
-        }
 
-        // print usage calculated
-        let usage_msg = format!(
-            "{INCREASING_TREND_EMOJI} Estimated usage {}/{CHAT_GPT_MODEL_TOKEN_LIMIT} tokens used",
-            self.count_tokens()
-        );
-        term.write_line(&usage_msg)?;
-        if let Some(chat_streamer) = chat_streamer.borrow_mut() {
-            chat_streamer.push_message(&usage_msg).await?;
-        }
+            let added_response = ChatCompletionRequestMessageArgs::default()
+                .content(&response_content_buffer)
+                .role(response_role.unwrap_or(Role::Assistant))
+                .build()?;
 
-        term.show_cursor()?;
+            let response_id = self.history.push(added_response);
+            if let Some(node) = self.history.get_mut(response_id) {
+                node.usage = response_usage;
+            }
+            self.add_alternative_responses(
+                response_id,
+                alternatives
+                    .into_values()
+                    .map(|(role, content)| ChatCompletionRequestMessage {
+                        role: role.unwrap_or(Role::Assistant),
+                        content,
+                        name: None,
+                    }),
+            );
+
+            match self.handle_tool_call(&response_content_buffer).await? {
+                Some(call) => emit(ChatEvent::ToolCall { tool: call.tool }),
+                None => break response_content_buffer,
+            }
 
-        let added_response = ChatCompletionRequestMessageArgs::default()
-            .content(&response_content_buffer)
-            .role(response_role.unwrap_or(Role::Assistant))
-            .build()?;
+            tool_rounds += 1;
+            if tool_rounds >= MAX_TOOL_ROUNDS {
+                anyhow::bail!("Model kept calling tools after {MAX_TOOL_ROUNDS} rounds");
+            }
+        };
 
-        self.history.push(added_response);
+        emit(ChatEvent::Usage {
+            recorded: self.token_usage.clone(),
+            estimated_tokens: self.count_tokens()?,
+            context_window: self.model.context_window,
+        });
 
-        self.populate_title_if_empty(client).await?;
+        drop(cancellation_guard);
 
-        if let Some(title) = &self.conversation_title {
-            term.set_title(title.replace('_', " "));
+        if !cancelled {
+            self.generate_title_if_empty(backend, title_term.as_ref())
+                .await;
         }
 
         Ok(response_content_buffer)
@@ -313,11 +1529,30 @@ impl ChatHistory {
         // this should probably not live here
         term.write_line("---------------------------------")?;
         term.write_line("Conversation so far:")?;
-        for message in &self.history {
+        for id in self.history.active_path() {
+            let Some(node) = self.history.get(id) else {
+                continue;
+            };
+            let message = &node.message;
+            // show which alternative is active if message was regenerated or edited
+            let siblings = self.history.siblings(id);
+            let branch = if siblings.len() > 1 {
+                let position = siblings.iter().position(|sibling| *sibling == id);
+                format!(
+                    " (branch {}/{})",
+                    position.unwrap_or_default() + 1,
+                    siblings.len()
+                )
+            } else {
+                String::new()
+            };
             match message.role {
-                Role::System => term.write_line(&format!("{SYSTEM_EMOJI} System:\n"))?,
-                Role::Assistant => term.write_line(&format!("{ROBOT_EMOJI} ChatGPT:\n"))?,
-                Role::User => term.write_line(&format!("{QUESTION_MARK_EMOJI} User:\n"))?,
+                _ if is_tool_result(message) => {
+                    term.write_line(&format!("{TOOL_EMOJI} Tool result{branch}:\n"))?
+                }
+                Role::System => term.write_line(&format!("{SYSTEM_EMOJI} System{branch}:\n"))?,
+                Role::Assistant => term.write_line(&format!("{ROBOT_EMOJI} ChatGPT{branch}:\n"))?,
+                Role::User => term.write_line(&format!("{QUESTION_MARK_EMOJI} User{branch}:\n"))?,
             }
             term.write_line(&message.content)?;
         }
@@ -326,58 +1561,140 @@ impl ChatHistory {
         // print usage recorded
         if let Some(token_usage) = self.token_usage.as_ref() {
             term.write_line(&format!(
-                "{INCREASING_TREND_EMOJI} Recorded usage {}/{CHAT_GPT_MODEL_TOKEN_LIMIT} tokens",
-                token_usage.total_tokens
+                "{INCREASING_TREND_EMOJI} Recorded usage {}/{} tokens",
+                token_usage.total_tokens, self.model.context_window
             ))?;
         }
 
         // print usage calculated
         term.write_line(&format!(
-            "{INCREASING_TREND_EMOJI} Estimated usage {}/{CHAT_GPT_MODEL_TOKEN_LIMIT} tokens",
-            self.count_tokens()
+            "{INCREASING_TREND_EMOJI} Estimated usage {}/{} tokens",
+            self.count_tokens()?,
+            self.model.context_window
         ))?;
         term.write_line("---------------------------------")?;
         Ok(())
     }
 
     /// save chat history file
-    pub fn save_to_file(&self) -> Result<()> {
-        // TODO(David): Extract this outside
-        let project_dirs = get_project_dirs()?;
-        let cache_dir = project_dirs.cache_dir();
+    ///
+    /// Conversation keeps writing to the same file once it was saved or loaded
+    pub fn save_to_file(&mut self) -> Result<()> {
+        let file_path = match &self.file_path {
+            // title arrived after the first save or was changed
+            Some(file_path) if self.file_name_outdated && self.conversation_title.is_some() => {
+                let titled_file_path = self.default_file_path()?;
+                if file_path.exists() {
+                    std::fs::rename(file_path, &titled_file_path)?;
+                }
+                self.file_name_outdated = false;
+                titled_file_path
+            }
+            Some(file_path) => file_path.clone(),
+            None => {
+                self.file_name_outdated = self.conversation_title.is_none();
+                self.default_file_path()?
+            }
+        };
 
-        std::fs::create_dir_all(cache_dir).context("failed to crate user cache directory")?;
+        write_atomically(&file_path, self.to_yaml()?.as_bytes())?;
+        self.file_path = Some(file_path);
+        Ok(())
+    }
 
-        let time = self
-            .conversation_start
-            .unwrap_or_else(Local::now)
-            .to_rfc3339();
+    /// Path for new file named after start time and title
+    fn default_file_path(&self) -> Result<PathBuf> {
+        let stem = conversation_files::file_stem(
+            self.conversation_start.unwrap_or_else(Local::now),
+            self.conversation_title.as_deref(),
+        );
+        Ok(conversation_files::available_path(
+            &conversation_files::conversations_dir()?,
+            &stem,
+            |path| self.is_saved_in(path),
+        ))
+    }
 
-        let title = self.conversation_title.as_deref().unwrap_or_default();
-        let file_path = cache_dir.join(format!("{time}_{title}.yaml"));
+    /// File was saved from this conversation, for example by an earlier import
+    fn is_saved_in(&self, file_path: &Path) -> bool {
+        self.conversation_start.is_some()
+            && read_storage(file_path)
+                .map(|(stored, _)| stored.start == self.conversation_start)
+                .unwrap_or(false)
+    }
 
-        let history_for_storage: Vec<ChatHistoryElement> =
-            self.history.iter().map(|element| element.into()).collect();
+    /// Serialize conversation in the same format as [ChatHistory::save_to_file]
+    pub fn to_yaml(&self) -> Result<String> {
+        Ok(serde_yaml::to_string(&self.to_storage())?)
+    }
 
-        let history_storage = ChatHistoryStorage {
-            messages: history_for_storage,
-        };
+    /// Deserialize conversation written by [ChatHistory::to_yaml]
+    ///
+    /// Older formats are migrated
+    pub fn from_yaml(yaml: &str) -> Result<ChatHistory> {
+        let document: serde_yaml::Value = serde_yaml::from_str(yaml)?;
+        let (chat_history, _) = migrate_storage(document)?;
+        Self::from_storage(chat_history, None)
+    }
 
-        let file = std::fs::File::create(file_path)?;
-        serde_yaml::to_writer(file, &history_storage)?;
-        Ok(())
+    fn to_storage(&self) -> ChatHistoryStorage {
+        let metadata = self.metadata();
+        ChatHistoryStorage {
+            version: migrations::CURRENT_VERSION,
+            title: self.conversation_title.clone(),
+            start: self.conversation_start,
+            model: Some(self.model.name.clone()),
+            token_usage: self.token_usage.clone(),
+            sampling: self.sampling.clone(),
+            nodes: self.history.nodes().map(|node| node.into()).collect(),
+            head: self.history.head(),
+            starred: self.starred,
+            message_count: Some(metadata.message_count),
+            first_question: metadata.first_question,
+        }
     }
 
-    pub fn get_all_saved_conversations() -> Result<Vec<PathBuf>> {
-        let project_dirs = get_project_dirs()?;
-        let cache_dir = project_dirs.cache_dir();
+    /// Read only what conversation lists show from a saved file
+    ///
+    /// Files written before the metadata was stored, or in an older format, are loaded fully
+    pub fn read_metadata(file_path: &Path) -> Result<ConversationMetadata> {
+        let file = std::fs::File::open(file_path)?;
+        let stored: StoredMetadata = serde_yaml::from_reader(file)
+            .with_context(|| format!("Invalid conversation {}", file_path.display()))?;
+        match stored.message_count {
+            Some(message_count) if stored.version == migrations::CURRENT_VERSION => {
+                Ok(ConversationMetadata {
+                    title: stored.title,
+                    start: stored.start,
+                    model: stored.model.unwrap_or_else(|| ModelInfo::default().name),
+                    starred: stored.starred,
+                    message_count,
+                    first_question: stored.first_question,
+                })
+            }
+            _ => Ok(Self::load_from_file(file_path)?.metadata()),
+        }
+    }
+
+    /// Upgrade saved conversation file to current format in place
+    ///
+    /// Returns the previous version if the file was rewritten
+    pub fn migrate_file(file_path: &Path) -> Result<Option<u64>> {
+        let (chat_history, original_version) = read_storage(file_path)?;
+        if original_version == migrations::CURRENT_VERSION {
+            return Ok(None);
+        }
+        write_atomically(file_path, serde_yaml::to_string(&chat_history)?.as_bytes())?;
+        Ok(Some(original_version))
+    }
 
+    pub fn get_all_saved_conversations() -> Result<Vec<PathBuf>> {
         let mut files = vec![];
 
-        for entry in std::fs::read_dir(cache_dir)? {
+        for entry in std::fs::read_dir(conversation_files::conversations_dir()?)? {
             let entry = entry?;
             let path = entry.path();
-            if path.is_file() {
+            if path.is_file() && !conversation_files::is_temporary_file(&path) {
                 files.push(path);
             }
         }
@@ -385,21 +1702,122 @@ impl ChatHistory {
     }
 
     /// load from chat history file
+    ///
+    /// Model is restored by name only, use [ChatHistory::set_model] to provide full model info
     pub fn load_from_file(file_path: &Path) -> anyhow::Result<ChatHistory> {
-        let file = std::fs::File::open(file_path)?;
-        let chat_history: ChatHistoryStorage = serde_yaml::from_reader(file)?;
-        let converted_history_list: Vec<ChatCompletionRequestMessage> = chat_history
-            .messages
-            .into_iter()
-            .map(|message| message.into())
-            .collect();
+        let (chat_history, _) = read_storage(file_path)?;
+        Self::from_storage(chat_history, Some(file_path.to_owned()))
+            .with_context(|| format!("Invalid conversation {}", file_path.display()))
+    }
+
+    fn from_storage(
+        chat_history: ChatHistoryStorage,
+        file_path: Option<PathBuf>,
+    ) -> Result<ChatHistory> {
+        let history = MessageTree::from_nodes(
+            chat_history
+                .nodes
+                .into_iter()
+                .map(|node| node.into())
+                .collect(),
+            chat_history.head,
+        )?;
+        let model = match chat_history.model {
+            Some(name) => ModelInfo {
+                name,
+                ..Default::default()
+            },
+            None => ModelInfo::default(),
+        };
         Ok(ChatHistory {
-            history: converted_history_list,
-            token_usage: None,
-            conversation_start: None,
-            conversation_title: None,
+            history,
+            token_usage: chat_history.token_usage,
+            conversation_start: chat_history.start,
+            conversation_title: chat_history.title,
+            model,
+            context_window_config: ContextWindowConfig::default(),
+            sampling: chat_history.sampling,
+            tools: vec![],
+            cancellation: None,
+            cancelled_response: CancelledResponse::default(),
+            file_path,
+            usage_ledger: None,
+            token_cache: MessageTokenCache::default(),
+            title_generator: None,
+            title_task: None,
+            file_name_outdated: false,
+            starred: chat_history.starred,
+            generate_titles: true,
+            context_summary: None,
         })
     }
+
+    /// Load saved conversation as a new conversation
+    ///
+    /// Copy starts now and is saved to its own file
+    pub fn load_copy_of_file(file_path: &Path) -> Result<ChatHistory> {
+        let (chat_history, _) = read_storage(file_path)?;
+        Ok(Self::from_storage(chat_history, None)?.into_copy())
+    }
+
+    /// Turn loaded conversation into a new conversation
+    ///
+    /// Copy starts now and is saved separately from the original
+    pub fn into_copy(mut self) -> ChatHistory {
+        self.conversation_start = Some(Local::now());
+        self.conversation_title = self.conversation_title.map(|title| format!("{title} copy"));
+        self.file_path = None;
+        self
+    }
+
+    /// Set file used by [ChatHistory::save_to_file]
+    pub fn set_file_path(&mut self, file_path: PathBuf) {
+        self.file_path = Some(file_path);
+    }
+
+    /// Create conversation from existing messages, used for imports
+    pub fn from_message_tree(
+        history: MessageTree,
+        title: Option<String>,
+        start: Option<DateTime<Local>>,
+    ) -> ChatHistory {
+        ChatHistory {
+            history,
+            token_usage: None,
+            conversation_start: start,
+            conversation_title: title,
+            model: ModelInfo::default(),
+            context_window_config: ContextWindowConfig::default(),
+            sampling: SamplingParameters::default(),
+            tools: vec![],
+            cancellation: None,
+            cancelled_response: CancelledResponse::default(),
+            file_path: None,
+            usage_ledger: None,
+            token_cache: MessageTokenCache::default(),
+            title_generator: None,
+            title_task: None,
+            file_name_outdated: false,
+            starred: false,
+            generate_titles: true,
+            context_summary: None,
+        }
+    }
+}
+
+/// Read conversation file and upgrade it to the current format
+///
+/// Also returns the version the file was written with
+fn read_storage(file_path: &Path) -> Result<(ChatHistoryStorage, u64)> {
+    let file = std::fs::File::open(file_path)?;
+    let document: serde_yaml::Value = serde_yaml::from_reader(file)?;
+    migrate_storage(document)
+}
+
+fn migrate_storage(mut document: serde_yaml::Value) -> Result<(ChatHistoryStorage, u64)> {
+    let original_version = migrations::migrate(&mut document)?;
+    let chat_history = serde_yaml::from_value(document)?;
+    Ok((chat_history, original_version))
 }
 
 impl From<&ChatCompletionRequestMessage> for ChatHistoryElement {
@@ -422,11 +1840,94 @@ impl From<ChatHistoryElement> for ChatCompletionRequestMessage {
     }
 }
 
+impl From<&MessageNode> for StoredMessageNode {
+    fn from(source: &MessageNode) -> Self {
+        Self {
+            id: source.id,
+            parent: source.parent,
+            timestamp: source.timestamp,
+            usage: source.usage.clone(),
+            message: (&source.message).into(),
+        }
+    }
+}
+
+impl From<StoredMessageNode> for MessageNode {
+    fn from(source: StoredMessageNode) -> Self {
+        Self {
+            id: source.id,
+            parent: source.parent,
+            message: source.message.into(),
+            timestamp: source.timestamp,
+            usage: source.usage,
+        }
+    }
+}
+
 /// used for storage
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct ChatHistoryStorage {
-    /// message
-    pub messages: Vec<ChatHistoryElement>,
+    /// format version, see [migrations]
+    pub version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// when the conversation was started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<DateTime<Local>>,
+    /// name of the model used last
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// usage reported for the latest request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_usage: Option<Usage>,
+    #[serde(default, skip_serializing_if = "SamplingParameters::is_empty")]
+    pub sampling: SamplingParameters,
+    /// messages from all branches
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<StoredMessageNode>,
+    /// last message of the active branch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head: Option<MessageId>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub starred: bool,
+    /// copy of [ConversationMetadata::message_count] so that lists don't need to read messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_count: Option<usize>,
+    /// copy of [ConversationMetadata::first_question]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_question: Option<String>,
+}
+
+/// Fields of [ChatHistoryStorage] read by [ChatHistory::read_metadata], messages are skipped
+#[derive(Debug, Deserialize)]
+struct StoredMetadata {
+    pub version: u64,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub start: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub starred: bool,
+    #[serde(default)]
+    pub message_count: Option<usize>,
+    #[serde(default)]
+    pub first_question: Option<String>,
+}
+
+/// Message with its position in conversation tree
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct StoredMessageNode {
+    pub id: MessageId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<MessageId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<DateTime<Local>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    #[serde(flatten)]
+    pub message: ChatHistoryElement,
 }
 
 /// Used for storage because [ChatCompletionRequestMessage] is not fully serde'd
@@ -441,39 +1942,789 @@ struct ChatHistoryElement {
     pub name: Option<String>,
 }
 
-#[async_trait]
-pub trait ChatStreamDisplay {
-    async fn push_message(&mut self, text: &str) -> anyhow::Result<()>;
-}
+/// Print streamed response to terminal and forward it to `sinks`
+///
+/// Returns the final response or the error that ended the turn
+pub async fn print_events(
+    mut events: impl Stream<Item = ChatEvent> + Unpin,
+    term: &Term,
+    sinks: Option<&ChatStreamSinks>,
+) -> anyhow::Result<String> {
+    term.hide_cursor()?;
+    let _cursor_guard = CursorGuard { term };
+    // sinks only get the response text, notices are for the terminal
+    let _response_guard = sinks.map(|sinks| sinks.start_response());
+
+    while let Some(event) = events.next().await {
+        match event {
+            ChatEvent::Role(_) => (),
+            ChatEvent::ContentDelta(delta_content) => {
+                term.write_str(&delta_content)?;
+                if let Some(sinks) = sinks {
+                    sinks.push(&delta_content);
+                }
+            }
+            ChatEvent::ToolCall { tool } => {
+                let msg = format!("\n{TOOL_EMOJI} Called tool {tool}\n");
+                term.write_line(&msg)?;
+            }
+            ChatEvent::Cancelled => {
+                term.write_line(&format!("\n\n{STOP_EMOJI} Response cancelled"))?;
+            }
+            ChatEvent::Usage {
+                recorded,
+                estimated_tokens,
+                context_window,
+            } => {
+                // empty new line after stream is done
+                term.write_line("\n")?;
+
+                // print usage recorded
+                if let Some(token_usage) = recorded {
+                    let msg = format!(
+                        "{INCREASING_TREND_EMOJI} Recorded usage {}/{context_window} tokens used",
+                        token_usage.total_tokens
+                    );
+                    term.write_line(&msg)?;
+                }
 
-pub struct MqttChatStreamDisplay {
-    buffer: String,
-    topic: String,
-    client: AsyncClient,
+                // print usage calculated
+                let usage_msg = format!(
+                    "{INCREASING_TREND_EMOJI} Estimated usage {estimated_tokens}/{context_window} tokens used"
+                );
+                term.write_line(&usage_msg)?;
+            }
+            ChatEvent::Finished { content } => return Ok(content),
+            ChatEvent::Error(error) => return Err(error),
+        }
+    }
+    anyhow::bail!("Response stream ended without result")
 }
 
-impl MqttChatStreamDisplay {
-    pub fn new(topic: &str, client: AsyncClient) -> Self {
-        Self {
-            buffer: String::new(),
-            topic: topic.to_owned(),
-            client,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_backend::ScriptedChatBackend;
+
+    fn small_model() -> ModelInfo {
+        ModelInfo {
+            context_window: 200,
+            ..Default::default()
         }
     }
-}
 
-#[async_trait]
-impl ChatStreamDisplay for MqttChatStreamDisplay {
-    async fn push_message(&mut self, text: &str) -> anyhow::Result<()> {
-        self.buffer.push_str(text);
-        self.client
-            .publish(
-                &self.topic,
-                rumqttc::QoS::AtMostOnce,
-                false,
-                self.buffer.as_bytes(),
+    /// conversation that doesn't fit into [small_model] with every message
+    fn long_conversation(overflow_strategy: OverflowStrategy) -> ChatHistory {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_model(small_model());
+        chat_history.set_generate_titles(false);
+        chat_history.set_context_window_config(ContextWindowConfig {
+            overflow_strategy,
+            reserved_response_tokens: 100,
+            summarize_keep_recent_messages: 1,
+        });
+        for index in 0..8 {
+            let role = if index % 2 == 0 {
+                Role::User
+            } else {
+                Role::Assistant
+            };
+            chat_history
+                .manually_insert_message(
+                    &format!("message {index} one two three four five six seven"),
+                    role,
+                )
+                .unwrap();
+        }
+        chat_history
+    }
+
+    fn contents(messages: &[ChatCompletionRequestMessage]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_only_trims_request() {
+        let mut chat_history = long_conversation(OverflowStrategy::DropOldest);
+        let backend = ScriptedChatBackend::new().with_response("answer");
+
+        chat_history
+            .next_message("question", &backend)
+            .await
+            .unwrap();
+
+        let requests = backend.requests();
+        let sent = contents(&requests[0].messages);
+        assert_eq!(sent.first(), Some(&"You are a test"));
+        assert_eq!(sent.last(), Some(&"question"));
+        assert!(!sent.contains(&"message 0 one two three four five six seven"));
+        let counter = TokenCounter::for_model(&small_model()).unwrap();
+        assert!(counter.count_prompt(requests[0].messages.iter()) <= 100);
+
+        // system prompt, 8 messages, question and answer
+        assert_eq!(chat_history.message_tree().to_messages().len(), 11);
+        assert_eq!(
+            chat_history.message_at(1).unwrap().content,
+            "message 0 one two three four five six seven"
+        );
+    }
+
+    #[tokio::test]
+    async fn summary_fits_context_and_is_reused() {
+        let mut chat_history = long_conversation(OverflowStrategy::Summarize);
+        let backend = ScriptedChatBackend::new()
+            .with_response("first part")
+            .with_response("both parts")
+            .with_response("answer")
+            .with_response("second answer");
+
+        chat_history
+            .next_message("question", &backend)
+            .await
+            .unwrap();
+        chat_history
+            .next_message("second question", &backend)
+            .await
+            .unwrap();
+
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 4);
+        let counter = TokenCounter::for_model(&small_model()).unwrap();
+        for request in &requests {
+            assert!(counter.count_prompt(request.messages.iter()) <= 100);
+        }
+
+        // messages are summarized in two chunks, second one continues the first summary
+        assert_eq!(
+            requests[0].messages.last().unwrap().content,
+            SUMMARY_INSTRUCTION
+        );
+        assert_eq!(
+            requests[1].messages[0].content,
+            "Summary of earlier conversation:\nfirst part"
+        );
+        assert_eq!(
+            requests[1].messages.last().unwrap().content,
+            SUMMARY_INSTRUCTION
+        );
+
+        let summary = "Summary of earlier conversation:\nboth parts";
+        assert_eq!(
+            contents(&requests[2].messages),
+            vec!["You are a test", summary, "question"]
+        );
+        assert_eq!(
+            contents(&requests[3].messages),
+            vec![
+                "You are a test",
+                summary,
+                "question",
+                "answer",
+                "second question"
+            ]
+        );
+
+        // saved conversation keeps every message
+        let saved = ChatHistory::from_yaml(&chat_history.to_yaml().unwrap()).unwrap();
+        assert_eq!(saved.message_tree().to_messages().len(), 13);
+    }
+
+    #[tokio::test]
+    async fn streamed_choices_become_alternatives() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_generate_titles(false);
+        let backend = ScriptedChatBackend::new();
+        backend.push_choices(&["first answer", "second answer", "third answer"]);
+
+        let events: Vec<_> = chat_history
+            .next_message_events("question", &backend)
+            .collect()
+            .await;
+
+        let streamed: String = events
+            .iter()
+            .filter_map(|event| match event {
+                ChatEvent::ContentDelta(content) => Some(content.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(streamed, "first answer");
+        assert!(matches!(
+            events.last(),
+            Some(ChatEvent::Finished { content }) if content == "first answer"
+        ));
+
+        let head = chat_history.message_tree().head().unwrap();
+        assert_eq!(
+            chat_history.peek_last_message().unwrap().content,
+            "first answer"
+        );
+        let alternatives: Vec<_> = chat_history
+            .message_tree()
+            .siblings(head)
+            .into_iter()
+            .map(|id| {
+                chat_history
+                    .message_tree()
+                    .get(id)
+                    .unwrap()
+                    .message
+                    .content
+                    .clone()
+            })
+            .collect();
+        assert_eq!(
+            alternatives,
+            vec!["first answer", "second answer", "third answer"]
+        );
+    }
+
+    #[derive(serde::Deserialize, schemars::JsonSchema)]
+    struct TimeArguments {
+        zone: String,
+    }
+
+    fn time_tool() -> Tool {
+        Tool::new(
+            "get_time",
+            "current time",
+            |arguments: TimeArguments| async move {
+                Ok(serde_json::json!({ "time": format!("noon {}", arguments.zone) }))
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn streamed_tool_call_is_run_and_hidden() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_generate_titles(false);
+        chat_history.register_tool(time_tool());
+        let backend = ScriptedChatBackend::new()
+            .with_response(
+                "Checking. ```tool_call {\"tool\": \"get_time\", \"arguments\": {\"zone\": \"UTC\"}} ```",
             )
-            .await?;
-        Ok(())
+            .with_response("It is noon.");
+
+        let events: Vec<_> = chat_history
+            .next_message_events("What time is it?", &backend)
+            .collect()
+            .await;
+
+        let streamed: String = events
+            .iter()
+            .filter_map(|event| match event {
+                ChatEvent::ContentDelta(content) => Some(content.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(streamed, "Checking. It is noon.");
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, ChatEvent::ToolCall { tool } if tool == "get_time")));
+
+        // tool result is sent with the second request
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 2);
+        let result = requests[1].messages.last().unwrap();
+        assert!(is_tool_result(result));
+        assert!(result.content.contains("noon UTC"));
+
+        let messages = chat_history.message_tree().to_messages();
+        let roles: Vec<_> = messages
+            .iter()
+            .map(|message| (message.role.clone(), is_tool_result(message)))
+            .collect();
+        assert_eq!(
+            roles,
+            vec![
+                (Role::System, false),
+                (Role::User, false),
+                (Role::Assistant, false),
+                (Role::System, true),
+                (Role::Assistant, false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn tool_loop_stops_after_max_rounds() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_generate_titles(false);
+        chat_history.register_tool(time_tool());
+        let backend = ScriptedChatBackend::new();
+        for _ in 0..MAX_TOOL_ROUNDS {
+            backend.push_response(
+                "```tool_call\n{\"tool\": \"get_time\", \"arguments\": {\"zone\": \"UTC\"}}\n```",
+            );
+        }
+
+        let error = chat_history
+            .next_message("What time is it?", &backend)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("kept calling tools"));
+        assert_eq!(backend.remaining_responses(), 0);
+    }
+
+    #[tokio::test]
+    async fn unknown_tool_error_goes_back_to_model() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_generate_titles(false);
+        chat_history.register_tool(time_tool());
+        let backend = ScriptedChatBackend::new()
+            .with_response("```tool_call\n{\"tool\": \"get_weather\"}\n```")
+            .with_response("I can't check the weather.");
+
+        let response = chat_history
+            .next_message("Weather?", &backend)
+            .await
+            .unwrap();
+
+        assert_eq!(response, "I can't check the weather.");
+        let result = backend.requests()[1].messages.last().unwrap().clone();
+        assert!(is_tool_result(&result));
+        assert!(result.content.contains("Unknown tool get_weather"));
+    }
+
+    #[tokio::test]
+    async fn json_answer_is_not_a_tool_call() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_generate_titles(false);
+        chat_history.register_tool(time_tool());
+        let backend = ScriptedChatBackend::new().with_response("{\"tool\": \"get_time\"}");
+
+        let response = chat_history
+            .next_message("Show me a tool call", &backend)
+            .await
+            .unwrap();
+
+        assert_eq!(response, "{\"tool\": \"get_time\"}");
+        assert_eq!(backend.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn next_message_sends_whole_conversation() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_generate_titles(false);
+        let backend = ScriptedChatBackend::new()
+            .with_response("first answer")
+            .with_response("second answer");
+
+        let first = chat_history.next_message("first", &backend).await.unwrap();
+        let second = chat_history.next_message("second", &backend).await.unwrap();
+
+        assert_eq!(first, "first answer");
+        assert_eq!(second, "second answer");
+        let requests = backend.requests();
+        assert_eq!(
+            contents(&requests[1].messages),
+            vec!["You are a test", "first", "first answer", "second"]
+        );
+        assert_eq!(requests[1].model, chat_history.model().name);
+        assert_eq!(chat_history.message_tree().to_messages().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn streamed_events_arrive_in_order() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_generate_titles(false);
+        let backend = ScriptedChatBackend::new().with_response("streamed answer here");
+
+        let events: Vec<_> = chat_history
+            .next_message_events("question", &backend)
+            .collect()
+            .await;
+
+        assert!(matches!(events[0], ChatEvent::Role(Role::Assistant)));
+        let deltas: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                ChatEvent::ContentDelta(content) => Some(content.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deltas, vec!["streamed ", "answer ", "here"]);
+        assert!(matches!(events[events.len() - 2], ChatEvent::Usage { .. }));
+        assert!(matches!(
+            events.last(),
+            Some(ChatEvent::Finished { content }) if content == "streamed answer here"
+        ));
+        assert_eq!(
+            chat_history.peek_last_message().unwrap().content,
+            "streamed answer here"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_request_ends_turn_with_error() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_generate_titles(false);
+        let backend = ScriptedChatBackend::new();
+        backend.push_error("service unavailable");
+        backend.push_error("service unavailable");
+
+        let error = chat_history
+            .next_message("question", &backend)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "service unavailable");
+
+        let events: Vec<_> = chat_history
+            .next_message_events("question", &backend)
+            .collect()
+            .await;
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], ChatEvent::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn printed_events_keep_error_type() {
+        let events = futures::stream::iter(vec![
+            ChatEvent::ContentDelta("partial".to_owned()),
+            ChatEvent::Error(
+                async_openai::error::OpenAIError::StreamError("stream broke".to_owned()).into(),
+            ),
+        ]);
+        let error = print_events(events, &Term::stderr(), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<async_openai::error::OpenAIError>(),
+            Some(async_openai::error::OpenAIError::StreamError(message)) if message == "stream broke"
+        ));
+    }
+
+    struct RecordingDisplay {
+        messages: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::stream_display::ChatStreamDisplay for RecordingDisplay {
+        async fn push_message(&mut self, text: &str) -> Result<()> {
+            self.messages.lock().unwrap().push(text.to_owned());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn sinks_only_get_response_content() {
+        let messages = Arc::new(std::sync::Mutex::new(vec![]));
+        let sinks = ChatStreamSinks::new().with(RecordingDisplay {
+            messages: messages.clone(),
+        });
+        let events = futures::stream::iter(vec![
+            ChatEvent::Role(Role::Assistant),
+            ChatEvent::ToolCall {
+                tool: "lights".to_owned(),
+            },
+            ChatEvent::ContentDelta("Lights ".to_owned()),
+            ChatEvent::ContentDelta("are on.".to_owned()),
+            ChatEvent::Usage {
+                recorded: Some(Usage {
+                    prompt_tokens: 10,
+                    completion_tokens: 3,
+                    total_tokens: 13,
+                }),
+                estimated_tokens: 13,
+                context_window: 200,
+            },
+            ChatEvent::Finished {
+                content: "Lights are on.".to_owned(),
+            },
+        ]);
+        let response = print_events(events, &Term::stderr(), Some(&sinks))
+            .await
+            .unwrap();
+        sinks.flush().await;
+        assert_eq!(response, "Lights are on.");
+        assert_eq!(*messages.lock().unwrap(), vec!["Lights ", "are on."]);
+    }
+
+    /// Backend that never answers, like one waiting out retries
+    struct HangingBackend;
+
+    #[async_trait::async_trait]
+    impl ChatBackend for HangingBackend {
+        async fn complete(
+            &self,
+            _request: CreateChatCompletionRequest,
+        ) -> Result<async_openai::types::CreateChatCompletionResponse> {
+            futures::future::pending().await
+        }
+
+        async fn complete_stream(
+            &self,
+            _request: CreateChatCompletionRequest,
+        ) -> Result<async_openai::types::ChatCompletionResponseStream> {
+            futures::future::pending().await
+        }
+    }
+
+    async fn cancel_when_active(cancellation: &StreamCancellation) {
+        while !cancellation.cancel() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn waiting_for_stream_can_be_cancelled() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_generate_titles(false);
+        let cancellation = StreamCancellation::new();
+        chat_history.set_cancellation(cancellation.clone());
+        chat_history.set_cancelled_response(CancelledResponse::Discard);
+
+        let (events, ()) = tokio::join!(
+            chat_history
+                .next_message_events("question", &HangingBackend)
+                .collect::<Vec<_>>(),
+            cancel_when_active(&cancellation),
+        );
+
+        assert!(matches!(events[0], ChatEvent::Cancelled));
+        assert!(matches!(
+            events.last(),
+            Some(ChatEvent::Finished { content }) if content.is_empty()
+        ));
+        assert_eq!(chat_history.message_tree().to_messages().len(), 1);
+        assert!(!cancellation.is_active());
+    }
+
+    #[tokio::test]
+    async fn waiting_for_response_can_be_cancelled() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_generate_titles(false);
+        let cancellation = StreamCancellation::new();
+        chat_history.set_cancellation(cancellation.clone());
+
+        let (response, ()) = tokio::join!(
+            chat_history.next_message("question", &HangingBackend),
+            cancel_when_active(&cancellation),
+        );
+
+        assert_eq!(response.unwrap(), "");
+        assert_eq!(
+            contents(&chat_history.message_tree().to_messages()),
+            vec!["You are a test", "question"]
+        );
+        assert!(!cancellation.is_active());
+    }
+
+    #[tokio::test]
+    async fn title_is_generated_after_first_response() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        let backend = ScriptedChatBackend::new()
+            .with_response("answer")
+            .with_response(" testing_titles\n")
+            .with_response("second answer");
+
+        chat_history
+            .next_message("question", &backend)
+            .await
+            .unwrap();
+        chat_history.next_message("again", &backend).await.unwrap();
+
+        assert_eq!(chat_history.conversation_title(), Some("testing_titles"));
+        let requests = backend.requests();
+        // title is only requested once
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            contents(&requests[1].messages),
+            vec!["You are a test", "question", "answer", TITLE_INSTRUCTION]
+        );
+    }
+
+    #[tokio::test]
+    async fn title_is_generated_in_background() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        let title_backend = Arc::new(ScriptedChatBackend::new().with_response("background_title"));
+        let title_model = ModelInfo {
+            name: String::from("title-model"),
+            ..Default::default()
+        };
+        chat_history.set_title_generator(TitleGenerator::new(
+            title_backend.clone(),
+            Some(title_model),
+        ));
+        let backend = ScriptedChatBackend::new().with_response("answer");
+
+        chat_history
+            .next_message("question", &backend)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            chat_history.wait_for_title().await.as_deref(),
+            Some("background_title")
+        );
+        assert_eq!(chat_history.conversation_title(), Some("background_title"));
+        assert_eq!(backend.requests().len(), 1);
+        assert_eq!(title_backend.requests()[0].model, "title-model");
+    }
+
+    #[test]
+    fn conversation_with_parent_cycle_is_rejected() {
+        let yaml = format!(
+            "version: {}
+nodes:
+- id: 0
+  role: system
+  content: You are a test
+- id: 1
+  parent: 2
+  role: user
+  content: question
+- id: 2
+  parent: 1
+  role: assistant
+  content: answer
+head: 2
+",
+            migrations::CURRENT_VERSION
+        );
+        assert!(ChatHistory::from_yaml(&yaml).is_err());
+    }
+
+    #[test]
+    fn token_cache_follows_history_changes() {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        let counter = TokenCounter::for_model(chat_history.model()).unwrap();
+        let assert_fresh = |chat_history: &ChatHistory| {
+            assert_eq!(
+                chat_history.count_tokens().unwrap(),
+                counter.count_prompt(chat_history.message_tree().messages().into_iter())
+            );
+        };
+        assert_fresh(&chat_history);
+
+        // push
+        chat_history
+            .manually_insert_message("short question", Role::User)
+            .unwrap();
+        chat_history
+            .manually_insert_message("short answer", Role::Assistant)
+            .unwrap();
+        assert_fresh(&chat_history);
+
+        // edit in place
+        chat_history
+            .replace_message_at(1, "much longer question that has a lot more tokens in it")
+            .unwrap();
+        assert_fresh(&chat_history);
+
+        // edit on a new branch
+        chat_history.edit_message_at(1, "another question").unwrap();
+        assert_fresh(&chat_history);
+
+        // pop
+        chat_history.pop_last_message();
+        assert_fresh(&chat_history);
+
+        // remove
+        chat_history
+            .manually_insert_message("question to remove", Role::User)
+            .unwrap();
+        chat_history
+            .manually_insert_message("answer that stays", Role::Assistant)
+            .unwrap();
+        assert_fresh(&chat_history);
+        chat_history.delete_message_at(1).unwrap();
+        assert_fresh(&chat_history);
+
+        // system prompt replaced in place
+        chat_history
+            .set_system_message("You are a much more verbose test with a longer prompt")
+            .unwrap();
+        assert_fresh(&chat_history);
+    }
+
+    fn temp_conversation_file(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("chatty_test_{}_{name}.yaml", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn conversation_with_branch() -> ChatHistory {
+        let mut chat_history = ChatHistory::new("You are a test").unwrap();
+        chat_history.set_title("metadata test");
+        chat_history
+            .manually_insert_message(&"long question ".repeat(50), Role::User)
+            .unwrap();
+        chat_history
+            .manually_insert_message("answer", Role::Assistant)
+            .unwrap();
+        chat_history.edit_message_at(1, "edited question").unwrap();
+        chat_history
+            .manually_insert_message("edited answer", Role::Assistant)
+            .unwrap();
+        chat_history
+            .manually_insert_message("follow up", Role::User)
+            .unwrap();
+        chat_history
+    }
+
+    #[test]
+    fn metadata_describes_active_branch() {
+        let mut chat_history = conversation_with_branch();
+        chat_history.set_starred(true);
+        let metadata = chat_history.metadata();
+        assert_eq!(metadata.title.as_deref(), Some("metadata test"));
+        assert_eq!(metadata.message_count, 3);
+        assert_eq!(metadata.first_question.as_deref(), Some("edited question"));
+        assert!(metadata.starred);
+
+        let original_answer = chat_history
+            .message_tree()
+            .nodes()
+            .find(|node| node.message.content == "answer")
+            .unwrap()
+            .id;
+        chat_history.switch_branch(original_answer).unwrap();
+        let metadata = chat_history.metadata();
+        assert_eq!(metadata.message_count, 2);
+        assert_eq!(
+            metadata.first_question.unwrap().chars().count(),
+            conversation_files::FIRST_QUESTION_PREVIEW_LENGTH
+        );
+    }
+
+    #[test]
+    fn read_metadata_uses_stored_summary() {
+        let chat_history = conversation_with_branch();
+        let yaml = chat_history.to_yaml().unwrap();
+        assert!(yaml.contains("message_count: 3"));
+
+        // stored value is trusted, messages aren't counted again
+        let path = temp_conversation_file(
+            "stored_summary",
+            &yaml.replace("message_count: 3", "message_count: 42"),
+        );
+        let metadata = ChatHistory::read_metadata(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(metadata.message_count, 42);
+        assert_eq!(metadata.title.as_deref(), Some("metadata test"));
+        assert_eq!(metadata.first_question.as_deref(), Some("edited question"));
+    }
+
+    #[test]
+    fn read_metadata_loads_files_without_summary() {
+        let chat_history = conversation_with_branch();
+        let yaml: String = chat_history
+            .to_yaml()
+            .unwrap()
+            .lines()
+            .filter(|line| {
+                !line.starts_with("message_count:") && !line.starts_with("first_question:")
+            })
+            .map(|line| format!("{line}\n"))
+            .collect();
+        let path = temp_conversation_file("without_summary", &yaml);
+        let metadata = ChatHistory::read_metadata(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(metadata, chat_history.metadata());
     }
 }
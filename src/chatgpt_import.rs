@@ -0,0 +1,391 @@
+use crate::{
+    chat_manager::ChatHistory,
+    conversation_files::ConversationLocation,
+    conversation_storage::ConversationStorage,
+    message_tree::{MessageId, MessageNode, MessageTree},
+    models::ModelInfo,
+};
+use anyhow::{Context, Result};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use chrono::prelude::{DateTime, Local, TimeZone};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+};
+
+/// Conversation from `conversations.json` in ChatGPT data export
+#[derive(Debug, Deserialize)]
+struct ExportedConversation {
+    title: Option<String>,
+    create_time: Option<f64>,
+    mapping: HashMap<String, ExportedNode>,
+    current_node: Option<String>,
+    default_model_slug: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportedNode {
+    message: Option<ExportedMessage>,
+    parent: Option<String>,
+    #[serde(default)]
+    children: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportedMessage {
+    author: ExportedAuthor,
+    create_time: Option<f64>,
+    content: ExportedContent,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportedAuthor {
+    role: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportedContent {
+    content_type: String,
+    #[serde(default)]
+    parts: Vec<serde_json::Value>,
+}
+
+/// Conversation that was not imported
+#[derive(Debug, Clone)]
+pub struct SkippedConversation {
+    /// position in the export
+    pub index: usize,
+    pub title: Option<String>,
+    pub reason: String,
+}
+
+/// Message left out of an imported conversation
+#[derive(Debug, Clone)]
+pub struct SkippedMessage {
+    /// position of the conversation in the export
+    pub conversation_index: usize,
+    pub title: Option<String>,
+    /// message id in the export
+    pub message_id: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// where new conversations were saved
+    pub imported: Vec<ConversationLocation>,
+    /// conversations from an earlier import that were replaced
+    pub overwritten: Vec<ConversationLocation>,
+    pub skipped: Vec<SkippedConversation>,
+    /// unsupported messages of imported conversations, for example with images
+    pub skipped_messages: Vec<SkippedMessage>,
+}
+
+/// Import every conversation from ChatGPT `conversations.json` into `storage`
+///
+/// Broken conversations and unsupported messages are skipped and listed in the report.
+/// Importing the same export again overwrites previously imported conversations,
+/// they are reported in [ImportReport::overwritten]
+pub fn import_chatgpt_export(
+    path: &Path,
+    storage: &mut ConversationStorage,
+) -> Result<ImportReport> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let conversations: Vec<serde_json::Value> =
+        serde_json::from_reader(file).context("ChatGPT export is not a list of conversations")?;
+
+    let existing: Vec<_> = storage
+        .list()?
+        .into_iter()
+        .map(|summary| summary.location)
+        .collect();
+
+    let mut report = ImportReport::default();
+    for (index, conversation) in conversations.into_iter().enumerate() {
+        let title = conversation
+            .get("title")
+            .and_then(|title| title.as_str())
+            .map(|title| title.to_owned());
+        let imported = convert_conversation(conversation).and_then(|mut converted| {
+            let location = storage.save(&mut converted.chat_history)?;
+            Ok((location, converted.skipped_messages))
+        });
+        match imported {
+            Ok((location, skipped_messages)) => {
+                if existing.contains(&location) {
+                    report.overwritten.push(location);
+                } else {
+                    report.imported.push(location);
+                }
+                report
+                    .skipped_messages
+                    .extend(skipped_messages.into_iter().map(|(message_id, reason)| {
+                        SkippedMessage {
+                            conversation_index: index,
+                            title: title.clone(),
+                            message_id,
+                            reason,
+                        }
+                    }));
+            }
+            Err(error) => report.skipped.push(SkippedConversation {
+                index,
+                title,
+                reason: format!("{error:#}"),
+            }),
+        }
+    }
+    Ok(report)
+}
+
+struct ConvertedConversation {
+    chat_history: ChatHistory,
+    /// export ids of messages that were left out and why
+    skipped_messages: Vec<(String, String)>,
+}
+
+/// Message of the export after conversion
+enum ConvertedMessage {
+    Message(ChatCompletionRequestMessage),
+    /// not part of the visible conversation such as tool calls
+    Hidden,
+    /// visible message that can't be represented, with reason
+    Unsupported(String),
+}
+
+fn convert_conversation(conversation: serde_json::Value) -> Result<ConvertedConversation> {
+    let conversation: ExportedConversation =
+        serde_json::from_value(conversation).context("Malformed conversation")?;
+
+    // walk from roots so that parents get their ids before children
+    let mut queue: VecDeque<(&String, Option<MessageId>)> = conversation
+        .mapping
+        .iter()
+        .filter(|(_, node)| {
+            node.parent
+                .as_ref()
+                .map(|parent| !conversation.mapping.contains_key(parent))
+                .unwrap_or(true)
+        })
+        .map(|(id, _)| (id, None))
+        .collect();
+    if queue.is_empty() {
+        anyhow::bail!("Conversation has no root message");
+    }
+
+    let mut nodes = vec![];
+    let mut skipped_messages = vec![];
+    // nodes that are not imported map to their closest imported ancestor
+    let mut imported_ids: HashMap<&String, Option<MessageId>> = HashMap::new();
+    while let Some((exported_id, parent)) = queue.pop_front() {
+        if imported_ids.contains_key(exported_id) {
+            anyhow::bail!("Message {exported_id} appears more than once");
+        }
+        let node = &conversation.mapping[exported_id];
+        let id = match node.message.as_ref().map(convert_message) {
+            Some(ConvertedMessage::Message(message)) => {
+                let id = nodes.len() as MessageId;
+                nodes.push(MessageNode {
+                    id,
+                    parent,
+                    message,
+                    timestamp: message_time(node),
+                    usage: None,
+                });
+                Some(id)
+            }
+            Some(ConvertedMessage::Unsupported(reason)) => {
+                skipped_messages.push((exported_id.clone(), reason));
+                parent
+            }
+            Some(ConvertedMessage::Hidden) | None => parent,
+        };
+        imported_ids.insert(exported_id, id);
+        for child in &node.children {
+            if conversation.mapping.contains_key(child) {
+                queue.push_back((child, id));
+            }
+        }
+    }
+    if !nodes
+        .iter()
+        .any(|node| node.message.role == Role::Assistant || node.message.role == Role::User)
+    {
+        anyhow::bail!("Conversation has no text messages");
+    }
+
+    let head = conversation
+        .current_node
+        .as_ref()
+        .and_then(|current_node| imported_ids.get(current_node).copied().flatten());
+    let start = conversation.create_time.and_then(unix_time);
+    let mut chat_history = ChatHistory::from_message_tree(
+        MessageTree::from_nodes(nodes, head)?,
+        conversation.title,
+        start,
+    );
+    if let Some(model) = conversation
+        .default_model_slug
+        .as_deref()
+        .and_then(model_name)
+    {
+        chat_history.set_model(ModelInfo {
+            name: model.to_owned(),
+            ..Default::default()
+        });
+    }
+    Ok(ConvertedConversation {
+        chat_history,
+        skipped_messages,
+    })
+}
+
+/// Convert message to chat message
+fn convert_message(message: &ExportedMessage) -> ConvertedMessage {
+    let role = match message.author.role.as_str() {
+        "system" => Role::System,
+        "user" => Role::User,
+        "assistant" => Role::Assistant,
+        _ => return ConvertedMessage::Hidden,
+    };
+    match message.content.content_type.as_str() {
+        "text" => (),
+        "multimodal_text" => {
+            return ConvertedMessage::Unsupported(
+                "Multimodal messages are not supported".to_owned(),
+            )
+        }
+        // code interpreter, browsing and other plugin output
+        _ => return ConvertedMessage::Hidden,
+    }
+    let mut parts = vec![];
+    for part in &message.content.parts {
+        match part.as_str() {
+            Some(text) => parts.push(text),
+            None => {
+                return ConvertedMessage::Unsupported(
+                    "Multimodal messages are not supported".to_owned(),
+                )
+            }
+        }
+    }
+    let content = parts.join("\n");
+    if content.trim().is_empty() {
+        return ConvertedMessage::Hidden;
+    }
+    ConvertedMessage::Message(ChatCompletionRequestMessage {
+        role,
+        content,
+        name: None,
+    })
+}
+
+fn message_time(node: &ExportedNode) -> Option<DateTime<Local>> {
+    node.message
+        .as_ref()
+        .and_then(|message| message.create_time)
+        .and_then(unix_time)
+}
+
+fn unix_time(timestamp: f64) -> Option<DateTime<Local>> {
+    let seconds = timestamp.trunc() as i64;
+    let nanoseconds = (timestamp.fract() * 1e9) as u32;
+    Local.timestamp_opt(seconds, nanoseconds).single()
+}
+
+/// API model closest to the web model
+fn model_name(slug: &str) -> Option<&'static str> {
+    if slug.starts_with("gpt-4") {
+        Some("gpt-4")
+    } else if slug.starts_with("text-davinci-002-render") || slug.starts_with("gpt-3.5") {
+        Some("gpt-3.5-turbo")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn node(
+        role: &str,
+        content_type: &str,
+        parts: serde_json::Value,
+        parent: Option<&str>,
+        children: &[&str],
+    ) -> serde_json::Value {
+        json!({
+            "message": {
+                "author": {"role": role},
+                "create_time": 1685620800.0,
+                "content": {"content_type": content_type, "parts": parts}
+            },
+            "parent": parent,
+            "children": children
+        })
+    }
+
+    fn contents(chat_history: &ChatHistory) -> Vec<String> {
+        chat_history
+            .message_tree()
+            .messages()
+            .into_iter()
+            .map(|message| message.content.clone())
+            .collect()
+    }
+
+    #[test]
+    fn multimodal_message_is_skipped_alone() {
+        let conversation = json!({
+            "title": "pictures",
+            "create_time": 1685620800.0,
+            "current_node": "d",
+            "default_model_slug": "gpt-4",
+            "mapping": {
+                "a": node("user", "text", json!(["describe this"]), None, &["b"]),
+                "b": node("user", "multimodal_text", json!([{"asset_pointer": "file"}]), Some("a"), &["c"]),
+                "c": node("assistant", "text", json!(["a cat"]), Some("b"), &["d"]),
+                "d": node("user", "text", json!(["thanks"]), Some("c"), &[]),
+            }
+        });
+        let converted = convert_conversation(conversation).unwrap();
+        assert_eq!(
+            contents(&converted.chat_history),
+            vec!["describe this", "a cat", "thanks"]
+        );
+        assert_eq!(converted.skipped_messages.len(), 1);
+        assert_eq!(converted.skipped_messages[0].0, "b");
+        assert_eq!(converted.chat_history.model().name, "gpt-4");
+    }
+
+    #[test]
+    fn image_part_in_text_message_is_skipped() {
+        let conversation = json!({
+            "title": null,
+            "create_time": 1685620800.0,
+            "current_node": "b",
+            "mapping": {
+                "a": node("user", "text", json!(["look", {"asset_pointer": "file"}]), None, &["b"]),
+                "b": node("assistant", "text", json!(["nice"]), Some("a"), &[]),
+            }
+        });
+        let converted = convert_conversation(conversation).unwrap();
+        assert_eq!(contents(&converted.chat_history), vec!["nice"]);
+        assert_eq!(converted.skipped_messages[0].0, "a");
+    }
+
+    #[test]
+    fn conversation_without_text_is_rejected() {
+        let conversation = json!({
+            "title": "only images",
+            "mapping": {
+                "a": node("user", "multimodal_text", json!([{"asset_pointer": "file"}]), None, &[]),
+            }
+        });
+        assert!(convert_conversation(conversation).is_err());
+    }
+}
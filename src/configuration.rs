@@ -1,6 +1,8 @@
 use std::path::{Path, PathBuf};
 
+use crate::models::ModelInfo;
 use anyhow::{Context, Result};
+use async_openai::types::{CreateChatCompletionRequestArgs, Stop};
 use config::Config;
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
@@ -32,6 +34,31 @@ pub struct AppConfig {
     pub open_ai_api_key: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mqtt: Option<MqttConfig>,
+    /// additional models or overrides for built in ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<ModelInfo>,
+    /// model used for new conversations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_model: Option<String>,
+    /// cheaper model used to generate conversation titles, conversation model is used when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title_model: Option<String>,
+    /// persona used by gpt-cli for new conversations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_persona: Option<String>,
+    #[serde(default)]
+    pub context_window: ContextWindowConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// sampling parameters for new conversations
+    #[serde(default, skip_serializing_if = "SamplingParameters::is_empty")]
+    pub sampling: SamplingParameters,
+    /// where gpt-cli saves conversations
+    #[serde(default)]
+    pub storage: StorageBackend,
+    /// cleanup of saved conversation files
+    #[serde(default)]
+    pub retention: RetentionConfig,
 }
 
 impl Default for AppConfig {
@@ -41,6 +68,15 @@ impl Default for AppConfig {
                 "Get token from https://platform.openai.com/account/api-keys",
             ),
             mqtt: None,
+            models: vec![],
+            default_model: None,
+            title_model: None,
+            default_persona: None,
+            context_window: ContextWindowConfig::default(),
+            retry: RetryConfig::default(),
+            sampling: SamplingParameters::default(),
+            storage: StorageBackend::default(),
+            retention: RetentionConfig::default(),
         }
     }
 }
@@ -103,3 +139,306 @@ pub struct MqttConfig {
     pub broker_port: u16,
     pub client_id: String,
 }
+
+/// Storage used for saved conversations
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// one YAML file per conversation in the cache directory
+    #[default]
+    Yaml,
+    /// SQLite database in the data directory with full-text search, requires `sqlite` feature
+    Sqlite,
+}
+
+/// What to do when conversation no longer fits into the model context window
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowStrategy {
+    /// send as is and let the API reject it
+    Disabled,
+    /// drop oldest messages but keep the system prompt
+    #[default]
+    DropOldest,
+    /// replace older messages with a summary generated by the model
+    Summarize,
+}
+
+const DEFAULT_RESERVED_RESPONSE_TOKENS: u32 = 512;
+
+const fn default_reserved_response_tokens() -> u32 {
+    DEFAULT_RESERVED_RESPONSE_TOKENS
+}
+
+const DEFAULT_SUMMARIZE_KEEP_RECENT_MESSAGES: usize = 4;
+
+const fn default_summarize_keep_recent_messages() -> usize {
+    DEFAULT_SUMMARIZE_KEEP_RECENT_MESSAGES
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextWindowConfig {
+    #[serde(default)]
+    pub overflow_strategy: OverflowStrategy,
+    /// tokens kept free for the response when checking the history size
+    #[serde(default = "default_reserved_response_tokens")]
+    pub reserved_response_tokens: u32,
+    /// number of most recent messages that are never summarized
+    #[serde(default = "default_summarize_keep_recent_messages")]
+    pub summarize_keep_recent_messages: usize,
+}
+
+impl Default for ContextWindowConfig {
+    fn default() -> Self {
+        Self {
+            overflow_strategy: OverflowStrategy::default(),
+            reserved_response_tokens: DEFAULT_RESERVED_RESPONSE_TOKENS,
+            summarize_keep_recent_messages: DEFAULT_SUMMARIZE_KEEP_RECENT_MESSAGES,
+        }
+    }
+}
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+
+const fn default_max_retries() -> u32 {
+    DEFAULT_MAX_RETRIES
+}
+
+const DEFAULT_INITIAL_BACKOFF_MS: u64 = 500;
+
+const fn default_initial_backoff_ms() -> u64 {
+    DEFAULT_INITIAL_BACKOFF_MS
+}
+
+const DEFAULT_MAX_BACKOFF_MS: u64 = 30_000;
+
+const fn default_max_backoff_ms() -> u64 {
+    DEFAULT_MAX_BACKOFF_MS
+}
+
+/// Retry policy for OpenAI API calls
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// retries after the first attempt, 0 disables retrying
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// delay before first retry, doubled for each following one
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    /// upper limit for delay between retries
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            initial_backoff_ms: DEFAULT_INITIAL_BACKOFF_MS,
+            max_backoff_ms: DEFAULT_MAX_BACKOFF_MS,
+        }
+    }
+}
+
+const fn default_keep_starred() -> bool {
+    true
+}
+
+/// Limits for saved conversation files
+///
+/// Applied by `gpt-cli prune` and automatically by the smart home.
+/// Most recently changed conversations are kept while they fit into the limits, nothing is removed when no limit is set
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RetentionConfig {
+    /// remove conversations not changed for this long, for example 90d or 12w
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<String>,
+    /// number of conversations to keep
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_count: Option<usize>,
+    /// combined size of conversation files in megabytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_total_size_mb: Option<u64>,
+    /// starred conversations are never removed and don't count towards the limits
+    #[serde(default = "default_keep_starred")]
+    pub keep_starred: bool,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            max_age: None,
+            max_count: None,
+            max_total_size_mb: None,
+            keep_starred: default_keep_starred(),
+        }
+    }
+}
+
+/// Sampling parameters sent with chat completion requests
+///
+/// Unset values are left to the API defaults
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct SamplingParameters {
+    /// between 0 and 2, higher values make output more random
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// nucleus sampling, between 0 and 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// upper limit of tokens generated for the response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u16>,
+    /// up to 4 sequences where the API stops generating
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    /// between -2 and 2, positive values encourage new topics
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    /// between -2 and 2, positive values discourage repetition
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    /// number of generated choices, only the first one is used in the conversation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<u8>,
+}
+
+impl SamplingParameters {
+    /// Names accepted by [SamplingParameters::set]
+    pub const NAMES: &'static [&'static str] = &[
+        "temperature",
+        "top_p",
+        "max_tokens",
+        "stop",
+        "presence_penalty",
+        "frequency_penalty",
+        "n",
+    ];
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Override parameters that are set in `other`
+    pub fn merge(&mut self, other: &SamplingParameters) {
+        self.temperature = other.temperature.or(self.temperature);
+        self.top_p = other.top_p.or(self.top_p);
+        self.max_tokens = other.max_tokens.or(self.max_tokens);
+        if !other.stop.is_empty() {
+            self.stop = other.stop.clone();
+        }
+        self.presence_penalty = other.presence_penalty.or(self.presence_penalty);
+        self.frequency_penalty = other.frequency_penalty.or(self.frequency_penalty);
+        self.n = other.n.or(self.n);
+    }
+
+    /// Set parameter by name from user input
+    ///
+    /// Empty value resets the parameter to API default.
+    /// Stop sequences are separated by commas
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+        fn parse<T: std::str::FromStr>(value: &str) -> Result<Option<T>>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            if value.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(
+                    value
+                        .parse()
+                        .with_context(|| format!("Failed to parse \"{value}\""))?,
+                ))
+            }
+        }
+        let mut updated = self.clone();
+        match name {
+            "temperature" => updated.temperature = parse(value)?,
+            "top_p" => updated.top_p = parse(value)?,
+            "max_tokens" => updated.max_tokens = parse(value)?,
+            "stop" => {
+                updated.stop = value
+                    .split(',')
+                    .filter(|stop| !stop.is_empty())
+                    .map(|stop| stop.to_owned())
+                    .collect()
+            }
+            "presence_penalty" => updated.presence_penalty = parse(value)?,
+            "frequency_penalty" => updated.frequency_penalty = parse(value)?,
+            "n" => updated.n = parse(value)?,
+            _ => anyhow::bail!(
+                "Unknown sampling parameter {name}. Expected one of {}",
+                Self::NAMES.join(", ")
+            ),
+        }
+        updated.validate()?;
+        *self = updated;
+        Ok(())
+    }
+
+    /// Current value of parameter by name formatted for display
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "temperature" => self.temperature.map(|value| value.to_string()),
+            "top_p" => self.top_p.map(|value| value.to_string()),
+            "max_tokens" => self.max_tokens.map(|value| value.to_string()),
+            "stop" => (!self.stop.is_empty()).then(|| self.stop.join(",")),
+            "presence_penalty" => self.presence_penalty.map(|value| value.to_string()),
+            "frequency_penalty" => self.frequency_penalty.map(|value| value.to_string()),
+            "n" => self.n.map(|value| value.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Check values are within ranges accepted by the API
+    pub fn validate(&self) -> Result<()> {
+        fn check_range(name: &str, value: Option<f32>, min: f32, max: f32) -> Result<()> {
+            match value {
+                Some(value) if !(min..=max).contains(&value) => {
+                    anyhow::bail!("{name} has to be between {min} and {max}, got {value}")
+                }
+                _ => Ok(()),
+            }
+        }
+        check_range("temperature", self.temperature, 0.0, 2.0)?;
+        check_range("top_p", self.top_p, 0.0, 1.0)?;
+        check_range("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+        check_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+        if self.stop.len() > 4 {
+            anyhow::bail!("At most 4 stop sequences are supported");
+        }
+        if self.n == Some(0) {
+            anyhow::bail!("n has to be at least 1");
+        }
+        if self.max_tokens == Some(0) {
+            anyhow::bail!("max_tokens has to be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Add parameters to chat completion request
+    pub fn apply(&self, request: &mut CreateChatCompletionRequestArgs) {
+        if let Some(temperature) = self.temperature {
+            request.temperature(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            request.top_p(top_p);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            request.max_tokens(max_tokens);
+        }
+        if !self.stop.is_empty() {
+            request.stop(Stop::StringArray(self.stop.clone()));
+        }
+        if let Some(presence_penalty) = self.presence_penalty {
+            request.presence_penalty(presence_penalty);
+        }
+        if let Some(frequency_penalty) = self.frequency_penalty {
+            request.frequency_penalty(frequency_penalty);
+        }
+        if let Some(n) = self.n {
+            request.n(n);
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use crate::{
+    chat_manager::ChatHistory,
+    configuration::{get_project_dirs, RetentionConfig},
+    usage_ledger::parse_duration,
+};
+use anyhow::{Context, Result};
+use chrono::prelude::{DateTime, Local};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+pub const CONVERSATION_FILE_EXTENSION: &str = "yaml";
+
+/// Longest title part of file name in characters
+pub const MAX_TITLE_LENGTH: usize = 64;
+
+/// Colons are not allowed in file names on some filesystems
+const TIME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+/// Directory with saved conversations
+pub fn conversations_dir() -> Result<PathBuf> {
+    let project_dirs = get_project_dirs()?;
+    let cache_dir = project_dirs.cache_dir();
+    std::fs::create_dir_all(cache_dir).context("failed to crate user cache directory")?;
+    Ok(cache_dir.to_owned())
+}
+
+/// Make title safe to use in a file name
+///
+/// Keeps letters, digits, `-` and `_`, everything else becomes a single `_`.
+/// Result is lowercase, never starts with a dot and is at most [MAX_TITLE_LENGTH] characters long.
+/// The title itself is stored inside the file so nothing is lost
+pub fn sanitize_title(title: &str) -> String {
+    let mut sanitized = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() || c == '-' {
+            sanitized.push(c);
+        } else if !sanitized.is_empty() && !sanitized.ends_with('_') {
+            sanitized.push('_');
+        }
+    }
+    let sanitized: String = sanitized.chars().take(MAX_TITLE_LENGTH).collect();
+    sanitized.trim_end_matches('_').to_owned()
+}
+
+/// File name without extension for conversation started at `start`
+pub fn file_stem(start: DateTime<Local>, title: Option<&str>) -> String {
+    let time = start.format(TIME_FORMAT).to_string();
+    match title.map(sanitize_title) {
+        Some(title) if !title.is_empty() => format!("{time}_{title}"),
+        _ => time,
+    }
+}
+
+/// Path in `dir` for `stem` that doesn't belong to another conversation
+///
+/// Existing file is reused when `is_same_conversation` returns true for it,
+/// otherwise a numeric suffix is added
+pub fn available_path(
+    dir: &Path,
+    stem: &str,
+    is_same_conversation: impl Fn(&Path) -> bool,
+) -> PathBuf {
+    let mut path = dir.join(format!("{stem}.{CONVERSATION_FILE_EXTENSION}"));
+    let mut suffix = 2;
+    while path.exists() && !is_same_conversation(&path) {
+        path = dir.join(format!("{stem}_{suffix}.{CONVERSATION_FILE_EXTENSION}"));
+        suffix += 1;
+    }
+    path
+}
+
+/// Temporary files left behind by interrupted [write_atomically] start with a dot
+pub fn is_temporary_file(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().starts_with('.'))
+        .unwrap_or(false)
+}
+
+/// Replace `path` with `contents` so that readers see either the old or the new file
+///
+/// Contents are written to a temporary file in the same directory which is then renamed over `path`
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = path
+        .parent()
+        .with_context(|| format!("{} has no parent directory", path.display()))?;
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{} has no file name", path.display()))?
+        .to_string_lossy();
+    let temp_path = dir.join(format!(".{file_name}.{}.tmp", std::process::id()));
+
+    let result = (|| {
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        _ = std::fs::remove_file(&temp_path);
+    }
+    result.with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Longest first question kept in [ConversationMetadata] in characters
+pub const FIRST_QUESTION_PREVIEW_LENGTH: usize = 200;
+
+/// What is shown about a conversation without loading all of its messages
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConversationMetadata {
+    pub title: Option<String>,
+    pub start: Option<DateTime<Local>>,
+    pub model: String,
+    pub starred: bool,
+    /// questions and responses on the active branch
+    pub message_count: usize,
+    /// beginning of the first question, at most [FIRST_QUESTION_PREVIEW_LENGTH] characters
+    pub first_question: Option<String>,
+}
+
+/// Where a saved conversation lives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversationLocation {
+    /// YAML file in the cache directory
+    File(PathBuf),
+    /// row id in the SQLite database
+    Database(i64),
+}
+
+impl std::fmt::Display for ConversationLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConversationLocation::File(path) => write!(f, "{}", path.display()),
+            ConversationLocation::Database(id) => write!(f, "database conversation {id}"),
+        }
+    }
+}
+
+/// Saved conversation as shown when picking one to continue
+#[derive(Debug, Clone)]
+pub struct ConversationSummary {
+    pub location: ConversationLocation,
+    pub title: Option<String>,
+    pub start: Option<DateTime<Local>>,
+    /// last time the conversation was written
+    pub modified: DateTime<Local>,
+    /// stored size in bytes
+    pub size: u64,
+    pub starred: bool,
+    /// questions and responses on the active branch
+    pub message_count: usize,
+    pub model: String,
+    pub first_question: Option<String>,
+}
+
+impl ConversationSummary {
+    pub fn new(
+        location: ConversationLocation,
+        metadata: ConversationMetadata,
+        modified: DateTime<Local>,
+        size: u64,
+    ) -> Self {
+        Self {
+            location,
+            title: metadata.title,
+            start: metadata.start,
+            modified,
+            size,
+            starred: metadata.starred,
+            message_count: metadata.message_count,
+            model: metadata.model,
+            first_question: metadata.first_question,
+        }
+    }
+
+    /// Summary of conversation file, only reads the metadata of the conversation
+    pub fn load(path: &Path) -> Result<Self> {
+        let conversation_metadata = ChatHistory::read_metadata(path)?;
+        let metadata = std::fs::metadata(path)?;
+        Ok(Self::new(
+            ConversationLocation::File(path.to_owned()),
+            conversation_metadata,
+            metadata.modified()?.into(),
+            metadata.len(),
+        ))
+    }
+}
+
+/// Saved file that could not be read
+#[derive(Debug)]
+pub struct UnreadableConversation {
+    pub path: PathBuf,
+    pub error: anyhow::Error,
+}
+
+/// Summaries of saved conversation files, most recently written first, and files that couldn't be read
+pub fn scan_conversations() -> Result<(Vec<ConversationSummary>, Vec<UnreadableConversation>)> {
+    let mut summaries = vec![];
+    let mut unreadable = vec![];
+    for path in ChatHistory::get_all_saved_conversations()? {
+        match ConversationSummary::load(&path) {
+            Ok(summary) => summaries.push(summary),
+            Err(error) => unreadable.push(UnreadableConversation { path, error }),
+        }
+    }
+    summaries.sort_by_key(|summary| std::cmp::Reverse(summary.modified));
+    Ok((summaries, unreadable))
+}
+
+/// Summaries of saved conversation files, most recently written first
+///
+/// Files that can't be loaded are skipped with a warning
+pub fn list_conversations() -> Result<Vec<ConversationSummary>> {
+    let (summaries, unreadable) = scan_conversations()?;
+    for file in unreadable {
+        warn!(
+            "Skipping conversation {} {:?}",
+            file.path.display(),
+            file.error
+        );
+    }
+    Ok(summaries)
+}
+
+/// Limit of [RetentionConfig] that a removed conversation didn't fit into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruneReason {
+    MaxAge,
+    MaxCount,
+    MaxTotalSize,
+}
+
+impl std::fmt::Display for PruneReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PruneReason::MaxAge => write!(f, "older than max_age"),
+            PruneReason::MaxCount => write!(f, "over max_count"),
+            PruneReason::MaxTotalSize => write!(f, "over max_total_size_mb"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PrunedConversation {
+    pub summary: ConversationSummary,
+    pub reason: PruneReason,
+}
+
+/// Conversations from `summaries` that don't fit into `retention`
+///
+/// `summaries` have to be ordered most recently written first like [list_conversations] returns them.
+/// Conversation at `keep`, usually the one in progress, is never pruned and is the first to count towards the limits
+pub fn plan_prune(
+    summaries: Vec<ConversationSummary>,
+    retention: &RetentionConfig,
+    now: DateTime<Local>,
+    keep: Option<&ConversationLocation>,
+) -> Result<Vec<PrunedConversation>> {
+    let max_age = retention
+        .max_age
+        .as_deref()
+        .map(parse_duration)
+        .transpose()
+        .context("Invalid retention max_age")?;
+    let max_total_size = retention.max_total_size_mb.map(|size| size * 1024 * 1024);
+
+    let mut kept_count = 0;
+    let mut kept_size = 0;
+    // kept conversation takes its place in the limits before the others
+    if let Some(kept) = summaries
+        .iter()
+        .find(|summary| Some(&summary.location) == keep)
+        .filter(|summary| !(retention.keep_starred && summary.starred))
+    {
+        kept_count += 1;
+        kept_size += kept.size;
+    }
+    let mut pruned = vec![];
+    for summary in summaries {
+        if (retention.keep_starred && summary.starred) || Some(&summary.location) == keep {
+            continue;
+        }
+        let reason = if max_age.is_some_and(|max_age| now - summary.modified > max_age) {
+            Some(PruneReason::MaxAge)
+        } else if retention
+            .max_count
+            .is_some_and(|max_count| kept_count >= max_count)
+        {
+            Some(PruneReason::MaxCount)
+        } else if max_total_size
+            .is_some_and(|max_total_size| kept_size + summary.size > max_total_size)
+        {
+            Some(PruneReason::MaxTotalSize)
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => pruned.push(PrunedConversation { summary, reason }),
+            None => {
+                kept_count += 1;
+                kept_size += summary.size;
+            }
+        }
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn now() -> DateTime<Local> {
+        DateTime::parse_from_rfc3339("2023-06-01T12:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    /// conversation last written `age_days` before [now]
+    fn summary(id: i64, age_days: i64, size: u64, starred: bool) -> ConversationSummary {
+        ConversationSummary::new(
+            ConversationLocation::Database(id),
+            ConversationMetadata {
+                starred,
+                ..Default::default()
+            },
+            now() - Duration::days(age_days),
+            size,
+        )
+    }
+
+    /// summaries of conversations 1..=count written one day apart, newest first
+    fn daily_summaries(count: i64) -> Vec<ConversationSummary> {
+        (1..=count).map(|id| summary(id, id, MB, false)).collect()
+    }
+
+    fn pruned(
+        summaries: Vec<ConversationSummary>,
+        retention: &RetentionConfig,
+        keep: Option<i64>,
+    ) -> Vec<(i64, PruneReason)> {
+        let keep = keep.map(ConversationLocation::Database);
+        plan_prune(summaries, retention, now(), keep.as_ref())
+            .unwrap()
+            .into_iter()
+            .map(|conversation| match conversation.summary.location {
+                ConversationLocation::Database(id) => (id, conversation.reason),
+                ConversationLocation::File(path) => panic!("unexpected file {path:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nothing_is_pruned_without_limits() {
+        assert!(pruned(daily_summaries(5), &RetentionConfig::default(), None).is_empty());
+    }
+
+    #[test]
+    fn age_limit() {
+        let retention = RetentionConfig {
+            max_age: Some("3d".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            pruned(daily_summaries(5), &retention, None),
+            vec![(4, PruneReason::MaxAge), (5, PruneReason::MaxAge)]
+        );
+    }
+
+    #[test]
+    fn count_limit_keeps_newest() {
+        let retention = RetentionConfig {
+            max_count: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            pruned(daily_summaries(4), &retention, None),
+            vec![(3, PruneReason::MaxCount), (4, PruneReason::MaxCount)]
+        );
+    }
+
+    #[test]
+    fn size_limit() {
+        let retention = RetentionConfig {
+            max_total_size_mb: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            pruned(daily_summaries(3), &retention, None),
+            vec![(3, PruneReason::MaxTotalSize)]
+        );
+    }
+
+    #[test]
+    fn current_conversation_is_kept_and_counted() {
+        let retention = RetentionConfig {
+            max_age: Some("2d".to_owned()),
+            max_count: Some(1),
+            ..Default::default()
+        };
+        // current conversation is older than max_age and would be over max_count
+        assert_eq!(
+            pruned(daily_summaries(3), &retention, Some(3)),
+            vec![(1, PruneReason::MaxCount), (2, PruneReason::MaxCount)]
+        );
+    }
+
+    #[test]
+    fn starred_conversations_are_kept() {
+        let retention = RetentionConfig {
+            max_count: Some(1),
+            ..Default::default()
+        };
+        let summaries = vec![
+            summary(1, 1, MB, true),
+            summary(2, 2, MB, false),
+            summary(3, 3, MB, false),
+        ];
+        assert_eq!(
+            pruned(summaries.clone(), &retention, None),
+            vec![(3, PruneReason::MaxCount)]
+        );
+
+        let retention = RetentionConfig {
+            keep_starred: false,
+            ..retention
+        };
+        assert_eq!(
+            pruned(summaries, &retention, None),
+            vec![(2, PruneReason::MaxCount), (3, PruneReason::MaxCount)]
+        );
+    }
+
+    #[test]
+    fn invalid_max_age_is_an_error() {
+        let retention = RetentionConfig {
+            max_age: Some("forever".to_owned()),
+            ..Default::default()
+        };
+        assert!(plan_prune(daily_summaries(1), &retention, now(), None).is_err());
+    }
+}
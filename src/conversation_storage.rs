@@ -0,0 +1,152 @@
+#[cfg(feature = "sqlite")]
+use crate::sqlite_store::ConversationStore;
+use crate::{
+    chat_manager::ChatHistory,
+    configuration::{RetentionConfig, StorageBackend},
+    conversation_files::{
+        self, ConversationLocation, ConversationSummary, PrunedConversation, UnreadableConversation,
+    },
+};
+use anyhow::{Context, Result};
+use chrono::Local;
+
+/// Outcome of [ConversationStorage::prune]
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    /// conversations that didn't fit into the retention policy
+    pub pruned: Vec<PrunedConversation>,
+    /// files that couldn't be read, they are left in place
+    pub unreadable: Vec<UnreadableConversation>,
+    /// conversations that should have been pruned but couldn't be removed
+    pub failed: Vec<FailedPrune>,
+}
+
+/// Conversation that couldn't be removed by [ConversationStorage::prune]
+#[derive(Debug)]
+pub struct FailedPrune {
+    pub conversation: PrunedConversation,
+    pub error: anyhow::Error,
+}
+
+/// Saved conversations in the backend selected by [StorageBackend]
+///
+/// Conversations are addressed by [ConversationLocation].
+/// YAML files can be loaded with either backend, saving always goes to the configured one
+pub enum ConversationStorage {
+    /// one YAML file per conversation
+    Files,
+    #[cfg(feature = "sqlite")]
+    Sqlite(ConversationStore),
+}
+
+impl ConversationStorage {
+    pub fn open(backend: StorageBackend) -> Result<Self> {
+        match backend {
+            StorageBackend::Yaml => Ok(Self::Files),
+            #[cfg(feature = "sqlite")]
+            StorageBackend::Sqlite => Ok(Self::Sqlite(ConversationStore::open_default()?)),
+            #[cfg(not(feature = "sqlite"))]
+            StorageBackend::Sqlite => anyhow::bail!("chatty was built without sqlite support"),
+        }
+    }
+
+    /// Summaries of saved conversations, most recently written first
+    pub fn list(&self) -> Result<Vec<ConversationSummary>> {
+        match self {
+            Self::Files => conversation_files::list_conversations(),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(store) => store.list(),
+        }
+    }
+
+    /// Most recently written conversation
+    pub fn latest(&self) -> Result<Option<ConversationSummary>> {
+        Ok(self.list()?.into_iter().next())
+    }
+
+    pub fn load(&self, location: &ConversationLocation) -> Result<ChatHistory> {
+        match (self, location) {
+            (_, ConversationLocation::File(path)) => ChatHistory::load_from_file(path),
+            #[cfg(feature = "sqlite")]
+            (Self::Sqlite(store), ConversationLocation::Database(conversation_id)) => {
+                store.load(*conversation_id)
+            }
+            (_, ConversationLocation::Database(_)) => {
+                anyhow::bail!("{location} can only be loaded with sqlite storage")
+            }
+        }
+    }
+
+    /// Save conversation and return where it was written
+    ///
+    /// Title generated in the background is picked up first if it is ready
+    pub fn save(&mut self, chat_history: &mut ChatHistory) -> Result<ConversationLocation> {
+        chat_history.poll_title();
+        match self {
+            Self::Files => {
+                chat_history.save_to_file()?;
+                let path = chat_history
+                    .file_path()
+                    .context("Saved conversation has no file")?;
+                Ok(ConversationLocation::File(path.to_owned()))
+            }
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(store) => Ok(ConversationLocation::Database(store.save(chat_history)?)),
+        }
+    }
+
+    pub fn delete(&mut self, location: &ConversationLocation) -> Result<()> {
+        match (self, location) {
+            (_, ConversationLocation::File(path)) => std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove {}", path.display())),
+            #[cfg(feature = "sqlite")]
+            (Self::Sqlite(store), ConversationLocation::Database(conversation_id)) => {
+                store.delete(*conversation_id)
+            }
+            (_, ConversationLocation::Database(_)) => {
+                anyhow::bail!("{location} can only be deleted with sqlite storage")
+            }
+        }
+    }
+
+    /// Remove conversations that don't fit into `retention`
+    ///
+    /// Conversation at `keep` is never removed. Nothing is removed on `dry_run`.
+    /// Conversations that fail to be removed don't stop the rest and are listed in [PruneReport::failed]
+    pub fn prune(
+        &mut self,
+        retention: &RetentionConfig,
+        keep: Option<&ConversationLocation>,
+        dry_run: bool,
+    ) -> Result<PruneReport> {
+        let (summaries, unreadable) = match self {
+            Self::Files => conversation_files::scan_conversations()?,
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(store) => (store.list()?, vec![]),
+        };
+        let planned = conversation_files::plan_prune(summaries, retention, Local::now(), keep)?;
+        if dry_run {
+            return Ok(PruneReport {
+                pruned: planned,
+                unreadable,
+                failed: vec![],
+            });
+        }
+        let mut pruned = vec![];
+        let mut failed = vec![];
+        for conversation in planned {
+            match self.delete(&conversation.summary.location) {
+                Ok(()) => pruned.push(conversation),
+                Err(error) => failed.push(FailedPrune {
+                    conversation,
+                    error,
+                }),
+            }
+        }
+        Ok(PruneReport {
+            pruned,
+            unreadable,
+            failed,
+        })
+    }
+}
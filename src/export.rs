@@ -0,0 +1,204 @@
+use crate::{chat_manager::ChatHistory, tools::is_tool_result};
+use anyhow::Result;
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use serde_json::json;
+
+/// Formats conversations can be exported to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    Markdown,
+    /// single self-contained page
+    Html,
+    /// OpenAI fine-tuning format
+    Jsonl,
+}
+
+/// Render active branch of conversation
+pub fn export(chat_history: &ChatHistory, format: ExportFormat) -> Result<String> {
+    match format {
+        ExportFormat::Markdown => Ok(to_markdown(chat_history)),
+        ExportFormat::Html => Ok(to_html(chat_history)),
+        ExportFormat::Jsonl => to_jsonl(chat_history),
+    }
+}
+
+fn title(chat_history: &ChatHistory) -> String {
+    chat_history
+        .conversation_title()
+        .map(|title| title.replace('_', " "))
+        .unwrap_or_else(|| String::from("Conversation"))
+}
+
+fn role_header(message: &ChatCompletionRequestMessage) -> &'static str {
+    if is_tool_result(message) {
+        return "Tool result";
+    }
+    match message.role {
+        Role::System => "System",
+        Role::User => "User",
+        Role::Assistant => "ChatGPT",
+    }
+}
+
+/// Close code fence left open by a cut off response so that it doesn't swallow the rest of the document
+fn close_code_fences(content: &str) -> String {
+    let fences = content
+        .lines()
+        .filter(|line| line.trim_start().starts_with("```"))
+        .count();
+    if fences % 2 == 1 {
+        format!("{}\n```", content.trim_end())
+    } else {
+        content.trim_end().to_owned()
+    }
+}
+
+pub fn to_markdown(chat_history: &ChatHistory) -> String {
+    let mut markdown = format!("# {}\n\n", title(chat_history));
+    if let Some(start) = chat_history.conversation_start() {
+        markdown.push_str(&format!("_{}_\n\n", start.format("%Y-%m-%d %H:%M")));
+    }
+    for message in chat_history.message_tree().messages() {
+        markdown.push_str(&format!("## {}\n\n", role_header(message)));
+        markdown.push_str(&close_code_fences(&message.content));
+        markdown.push_str("\n\n");
+    }
+    markdown
+}
+
+const HTML_STYLE: &str = "
+body { font-family: sans-serif; max-width: 50em; margin: 2em auto; padding: 0 1em; line-height: 1.5; color: #222; }
+.message { border-radius: 6px; padding: 0.5em 1em; margin: 1em 0; }
+.system { background: #f3f3f3; }
+.tool { background: #fdf6e3; }
+.user { background: #e8f0fe; }
+.assistant { background: #eef7ee; }
+.role { font-weight: bold; margin: 0.5em 0; }
+pre { background: #272822; color: #f8f8f2; padding: 0.75em; border-radius: 4px; overflow-x: auto; }
+code { font-family: monospace; }
+:not(pre) > code { background: rgba(0, 0, 0, 0.07); padding: 0 0.2em; border-radius: 3px; }
+";
+
+pub fn to_html(chat_history: &ChatHistory) -> String {
+    let title = escape_html(&title(chat_history));
+    let mut body = format!("<h1>{title}</h1>\n");
+    if let Some(start) = chat_history.conversation_start() {
+        body.push_str(&format!(
+            "<p><em>{}</em></p>\n",
+            start.format("%Y-%m-%d %H:%M")
+        ));
+    }
+    for message in chat_history.message_tree().messages() {
+        let class = match message.role {
+            _ if is_tool_result(message) => "tool",
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        };
+        body.push_str(&format!(
+            "<div class=\"message {class}\">\n<div class=\"role\">{}</div>\n{}</div>\n",
+            role_header(message),
+            markdown_to_html(&close_code_fences(&message.content))
+        ));
+    }
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>{HTML_STYLE}</style>\n</head>\n<body>\n{body}</body>\n</html>\n"
+    )
+}
+
+/// One line per conversation with `messages` list as expected by OpenAI fine-tuning
+pub fn to_jsonl(chat_history: &ChatHistory) -> Result<String> {
+    let messages: Vec<_> = chat_history
+        .message_tree()
+        .messages()
+        .into_iter()
+        .map(|message| {
+            let mut value = json!({
+                "role": message.role,
+                "content": message.content,
+            });
+            if let Some(name) = &message.name {
+                value["name"] = json!(name);
+            }
+            value
+        })
+        .collect();
+    let mut line = serde_json::to_string(&json!({ "messages": messages }))?;
+    line.push('\n');
+    Ok(line)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Minimal markdown rendering: fenced code blocks, paragraphs and inline code
+///
+/// Everything else is shown as plain text
+fn markdown_to_html(markdown: &str) -> String {
+    let mut html = String::new();
+    let mut paragraph: Vec<&str> = vec![];
+    let mut code_block: Option<(String, Vec<&str>)> = None;
+
+    fn flush_paragraph(html: &mut String, paragraph: &mut Vec<&str>) {
+        if !paragraph.is_empty() {
+            let lines: Vec<_> = paragraph.iter().map(|line| inline_code(line)).collect();
+            html.push_str(&format!("<p>{}</p>\n", lines.join("<br>\n")));
+            paragraph.clear();
+        }
+    }
+
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        if let Some(info) = trimmed.strip_prefix("```") {
+            match code_block.take() {
+                Some((language, code)) => {
+                    let class = if language.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" class=\"language-{}\"", escape_html(&language))
+                    };
+                    html.push_str(&format!(
+                        "<pre><code{class}>{}</code></pre>\n",
+                        escape_html(&code.join("\n"))
+                    ));
+                }
+                None => {
+                    flush_paragraph(&mut html, &mut paragraph);
+                    code_block = Some((info.trim().to_owned(), vec![]));
+                }
+            }
+        } else if let Some((_, code)) = &mut code_block {
+            code.push(line);
+        } else if trimmed.is_empty() {
+            flush_paragraph(&mut html, &mut paragraph);
+        } else {
+            paragraph.push(line);
+        }
+    }
+    flush_paragraph(&mut html, &mut paragraph);
+    html
+}
+
+/// Escape line and wrap text between backticks in code tags
+fn inline_code(line: &str) -> String {
+    let parts: Vec<_> = line.split('`').collect();
+    // unbalanced backtick is left as is
+    if parts.len() % 2 == 0 {
+        return escape_html(line);
+    }
+    parts
+        .iter()
+        .enumerate()
+        .map(|(index, part)| {
+            if index % 2 == 1 {
+                format!("<code>{}</code>", escape_html(part))
+            } else {
+                escape_html(part)
+            }
+        })
+        .collect()
+}
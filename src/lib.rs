@@ -5,7 +5,25 @@ pub mod audio;
 pub mod configuration;
 #[cfg(feature = "mqtt")]
 pub mod mqtt;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
 
+pub mod cancellation;
+pub mod chat_backend;
 pub mod chat_manager;
+pub mod chatgpt_import;
 pub mod cli_history;
+pub mod conversation_files;
+pub mod conversation_storage;
+pub mod export;
+pub mod message_tree;
+pub mod migrations;
+pub mod models;
+pub mod personas;
+pub mod retry;
+pub mod slash_commands;
+pub mod stream_display;
+pub mod token_counter;
+pub mod tools;
+pub mod usage_ledger;
 pub mod utils;
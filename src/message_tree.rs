@@ -0,0 +1,440 @@
+use anyhow::Result;
+use async_openai::types::{ChatCompletionRequestMessage, Usage};
+use chrono::prelude::{DateTime, Local};
+use std::collections::{BTreeMap, HashSet};
+
+pub type MessageId = u64;
+
+/// Single message in a conversation tree
+#[derive(Debug, Clone)]
+pub struct MessageNode {
+    pub id: MessageId,
+    pub parent: Option<MessageId>,
+    pub message: ChatCompletionRequestMessage,
+    /// when message was added, unknown for conversations saved before it was recorded
+    pub timestamp: Option<DateTime<Local>>,
+    /// usage reported by the API for the request that generated this message
+    pub usage: Option<Usage>,
+}
+
+/// Conversation stored as a tree of messages
+///
+/// Regenerating or editing a message adds a sibling instead of overwriting history.
+/// The active branch is the path from the root to `head`.
+#[derive(Debug, Clone, Default)]
+pub struct MessageTree {
+    nodes: BTreeMap<MessageId, MessageNode>,
+    head: Option<MessageId>,
+    next_id: MessageId,
+}
+
+impl MessageTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build tree from nodes
+    ///
+    /// Nodes with missing parents are attached to the root.
+    /// Parent links that form a cycle are an error since no branch could reach the root
+    pub fn from_nodes(nodes: Vec<MessageNode>, head: Option<MessageId>) -> Result<Self> {
+        let mut tree = Self::new();
+        for node in nodes {
+            tree.next_id = tree.next_id.max(node.id + 1);
+            tree.nodes.insert(node.id, node);
+        }
+        let ids: Vec<MessageId> = tree.nodes.keys().copied().collect();
+        for id in ids {
+            let parent = tree.nodes[&id].parent;
+            if parent
+                .map(|parent| !tree.nodes.contains_key(&parent))
+                .unwrap_or(false)
+            {
+                tree.nodes.get_mut(&id).unwrap().parent = None;
+            }
+        }
+        tree.check_for_cycles()?;
+        tree.head = match head {
+            Some(head) if tree.nodes.contains_key(&head) => Some(head),
+            _ => tree.nodes.keys().next_back().copied(),
+        };
+        Ok(tree)
+    }
+
+    /// Every message has to reach the root by following its parents
+    fn check_for_cycles(&self) -> Result<()> {
+        // messages already known to reach the root
+        let mut rooted = HashSet::new();
+        for id in self.nodes.keys() {
+            let mut visited = vec![];
+            let mut current = Some(*id);
+            while let Some(id) = current {
+                if rooted.contains(&id) {
+                    break;
+                }
+                if visited.contains(&id) {
+                    anyhow::bail!("Message {id} is its own ancestor");
+                }
+                visited.push(id);
+                current = self.nodes.get(&id).and_then(|node| node.parent);
+            }
+            rooted.extend(visited);
+        }
+        Ok(())
+    }
+
+    /// Build tree with a single branch
+    ///
+    /// Messages get no timestamps since we don't know when they were written
+    pub fn from_messages(messages: Vec<ChatCompletionRequestMessage>) -> Self {
+        let mut tree = Self::new();
+        for message in messages {
+            let id = tree.push(message);
+            if let Some(node) = tree.nodes.get_mut(&id) {
+                node.timestamp = None;
+            }
+        }
+        tree
+    }
+
+    /// Append message to active branch
+    pub fn push(&mut self, message: ChatCompletionRequestMessage) -> MessageId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.nodes.insert(
+            id,
+            MessageNode {
+                id,
+                parent: self.head,
+                message,
+                timestamp: Some(Local::now()),
+                usage: None,
+            },
+        );
+        self.head = Some(id);
+        id
+    }
+
+    /// Remove last message of active branch
+    ///
+    /// If other branches continue from it the message is kept and only the head moves back
+    pub fn pop(&mut self) -> Option<ChatCompletionRequestMessage> {
+        let head = self.head?;
+        let node = self.nodes.get(&head)?;
+        let message = node.message.clone();
+        self.head = node.parent;
+        if self.children(head).is_empty() {
+            self.nodes.remove(&head);
+        }
+        Some(message)
+    }
+
+    /// Pop messages until active branch has `len` messages
+    pub fn truncate(&mut self, len: usize) {
+        while self.len() > len {
+            self.pop();
+        }
+    }
+
+    pub fn head(&self) -> Option<MessageId> {
+        self.head
+    }
+
+    pub fn get(&self, id: MessageId) -> Option<&MessageNode> {
+        self.nodes.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: MessageId) -> Option<&mut MessageNode> {
+        self.nodes.get_mut(&id)
+    }
+
+    /// Ids of messages on active branch, root first
+    pub fn active_path(&self) -> Vec<MessageId> {
+        let mut path = vec![];
+        let mut current = self.head;
+        while let Some(id) = current {
+            path.push(id);
+            current = self.nodes.get(&id).and_then(|node| node.parent);
+        }
+        path.reverse();
+        path
+    }
+
+    /// Messages on active branch
+    pub fn messages(&self) -> Vec<&ChatCompletionRequestMessage> {
+        self.active_path()
+            .into_iter()
+            .filter_map(|id| self.nodes.get(&id).map(|node| &node.message))
+            .collect()
+    }
+
+    /// Clone of messages on active branch
+    pub fn to_messages(&self) -> Vec<ChatCompletionRequestMessage> {
+        self.messages().into_iter().cloned().collect()
+    }
+
+    /// Number of messages on active branch
+    pub fn len(&self) -> usize {
+        self.active_path().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    pub fn last(&self) -> Option<&ChatCompletionRequestMessage> {
+        self.head
+            .and_then(|id| self.nodes.get(&id))
+            .map(|node| &node.message)
+    }
+
+    /// All nodes in all branches ordered by creation
+    pub fn nodes(&self) -> impl Iterator<Item = &MessageNode> {
+        self.nodes.values()
+    }
+
+    pub fn children(&self, id: MessageId) -> Vec<MessageId> {
+        self.nodes
+            .values()
+            .filter(|node| node.parent == Some(id))
+            .map(|node| node.id)
+            .collect()
+    }
+
+    /// Alternatives to a message including the message itself
+    pub fn siblings(&self, id: MessageId) -> Vec<MessageId> {
+        let Some(parent) = self.nodes.get(&id).map(|node| node.parent) else {
+            return vec![];
+        };
+        self.nodes
+            .values()
+            .filter(|node| node.parent == parent)
+            .map(|node| node.id)
+            .collect()
+    }
+
+    /// Make message the head of active branch without changing anything else
+    pub fn set_head(&mut self, id: MessageId) -> bool {
+        if self.nodes.contains_key(&id) {
+            self.head = Some(id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Switch active branch to the one going through `id`
+    ///
+    /// Follows the newest child at every fork below `id`
+    pub fn switch_to(&mut self, id: MessageId) -> bool {
+        if !self.nodes.contains_key(&id) {
+            return false;
+        }
+        let mut current = id;
+        while let Some(newest_child) = self.children(current).last() {
+            current = *newest_child;
+        }
+        self.head = Some(current);
+        true
+    }
+
+    /// Add alternative to message `id` and make it the head of active branch
+    pub fn add_sibling(
+        &mut self,
+        id: MessageId,
+        message: ChatCompletionRequestMessage,
+    ) -> Option<MessageId> {
+        let parent = self.nodes.get(&id)?.parent;
+        self.head = parent;
+        Some(self.push(message))
+    }
+
+    /// Insert message between `id` and its parent
+    ///
+    /// Every branch going through `id` gets the new message
+    pub fn insert_before(
+        &mut self,
+        id: MessageId,
+        message: ChatCompletionRequestMessage,
+    ) -> Option<MessageId> {
+        let parent = self.nodes.get(&id)?.parent;
+        let new_id = self.next_id;
+        self.next_id += 1;
+        self.nodes.insert(
+            new_id,
+            MessageNode {
+                id: new_id,
+                parent,
+                message,
+                timestamp: Some(Local::now()),
+                usage: None,
+            },
+        );
+        if let Some(node) = self.nodes.get_mut(&id) {
+            node.parent = Some(new_id);
+        }
+        Some(new_id)
+    }
+
+    /// Replace content of message in place
+    pub fn replace(&mut self, id: MessageId, message: ChatCompletionRequestMessage) -> bool {
+        match self.nodes.get_mut(&id) {
+            Some(node) => {
+                node.message = message;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove single message and attach its children to its parent
+    pub fn remove(&mut self, id: MessageId) -> Option<ChatCompletionRequestMessage> {
+        let node = self.nodes.remove(&id)?;
+        for child in self.nodes.values_mut() {
+            if child.parent == Some(id) {
+                child.parent = node.parent;
+            }
+        }
+        if self.head == Some(id) {
+            self.head = node.parent;
+        }
+        Some(node.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::Role;
+
+    fn message(content: &str) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestMessage {
+            role: Role::User,
+            content: content.to_owned(),
+            name: None,
+        }
+    }
+
+    fn node(id: MessageId, parent: Option<MessageId>) -> MessageNode {
+        MessageNode {
+            id,
+            parent,
+            message: message(&id.to_string()),
+            timestamp: None,
+            usage: None,
+        }
+    }
+
+    fn contents(tree: &MessageTree) -> Vec<String> {
+        tree.messages()
+            .into_iter()
+            .map(|message| message.content.clone())
+            .collect()
+    }
+
+    #[test]
+    fn push_extends_active_branch() {
+        let mut tree = MessageTree::new();
+        let first = tree.push(message("first"));
+        let second = tree.push(message("second"));
+
+        assert_eq!(tree.active_path(), vec![first, second]);
+        assert_eq!(tree.get(second).unwrap().parent, Some(first));
+        assert_eq!(tree.head(), Some(second));
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn active_path_follows_head_branch() {
+        let mut tree = MessageTree::new();
+        let root = tree.push(message("root"));
+        let original = tree.push(message("original"));
+        let alternative = tree.add_sibling(original, message("alternative")).unwrap();
+
+        assert_eq!(tree.active_path(), vec![root, alternative]);
+        assert_eq!(tree.siblings(original), vec![original, alternative]);
+
+        assert!(tree.switch_to(original));
+        assert_eq!(contents(&tree), vec!["root", "original"]);
+    }
+
+    #[test]
+    fn remove_reparents_children() {
+        let mut tree = MessageTree::new();
+        let root = tree.push(message("root"));
+        let middle = tree.push(message("middle"));
+        let first_child = tree.push(message("first child"));
+        tree.set_head(middle);
+        let second_child = tree.push(message("second child"));
+
+        assert_eq!(tree.remove(middle).unwrap().content, "middle");
+
+        assert_eq!(tree.get(first_child).unwrap().parent, Some(root));
+        assert_eq!(tree.get(second_child).unwrap().parent, Some(root));
+        assert_eq!(tree.active_path(), vec![root, second_child]);
+    }
+
+    #[test]
+    fn remove_head_moves_head_to_parent() {
+        let mut tree = MessageTree::new();
+        let root = tree.push(message("root"));
+        let head = tree.push(message("head"));
+
+        tree.remove(head);
+
+        assert_eq!(tree.head(), Some(root));
+        assert!(tree.get(head).is_none());
+    }
+
+    #[test]
+    fn pop_keeps_message_other_branches_continue_from() {
+        let mut tree = MessageTree::new();
+        let root = tree.push(message("root"));
+        let shared = tree.push(message("shared"));
+        tree.push(message("branch"));
+        tree.set_head(shared);
+
+        tree.pop();
+
+        assert_eq!(tree.head(), Some(root));
+        assert!(tree.get(shared).is_some());
+    }
+
+    #[test]
+    fn from_nodes_attaches_orphans_to_root() {
+        let tree = MessageTree::from_nodes(vec![node(0, None), node(1, Some(7))], Some(1)).unwrap();
+
+        assert_eq!(tree.active_path(), vec![1]);
+        assert_eq!(tree.get(1).unwrap().parent, None);
+    }
+
+    #[test]
+    fn from_nodes_keeps_head_and_continues_ids() {
+        let mut tree = MessageTree::from_nodes(
+            vec![node(0, None), node(1, Some(0)), node(5, Some(0))],
+            Some(1),
+        )
+        .unwrap();
+
+        assert_eq!(tree.active_path(), vec![0, 1]);
+        assert_eq!(tree.push(message("new")), 6);
+    }
+
+    #[test]
+    fn from_nodes_rejects_self_parent() {
+        let error =
+            MessageTree::from_nodes(vec![node(0, None), node(1, Some(1))], Some(1)).unwrap_err();
+        assert!(error.to_string().contains("own ancestor"));
+    }
+
+    #[test]
+    fn from_nodes_rejects_cycle() {
+        let nodes = vec![
+            node(0, None),
+            node(1, Some(3)),
+            node(2, Some(1)),
+            node(3, Some(2)),
+        ];
+        assert!(MessageTree::from_nodes(nodes, Some(0)).is_err());
+    }
+}
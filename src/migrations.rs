@@ -0,0 +1,76 @@
+use anyhow::{Context, Result};
+use serde_yaml::{Mapping, Value};
+
+/// Version written to new conversation files
+pub const CURRENT_VERSION: u64 = 1;
+
+const VERSION_KEY: &str = "version";
+
+/// Version of raw conversation file
+///
+/// Files without version key are either flat message lists (version 0)
+/// or trees written before the version was added (version 1)
+pub fn file_version(document: &Value) -> Result<u64> {
+    let mapping = document
+        .as_mapping()
+        .context("Conversation file is not a mapping")?;
+    match mapping.get(VERSION_KEY) {
+        Some(version) => version
+            .as_u64()
+            .context("Conversation file version is not a number"),
+        None if mapping.contains_key("nodes") => Ok(1),
+        None => Ok(0),
+    }
+}
+
+/// Upgrade raw conversation file to [CURRENT_VERSION]
+///
+/// Returns the version the file had before migration
+pub fn migrate(document: &mut Value) -> Result<u64> {
+    let original_version = file_version(document)?;
+    if original_version > CURRENT_VERSION {
+        anyhow::bail!(
+            "Conversation file version {original_version} is newer than supported version {CURRENT_VERSION}"
+        );
+    }
+    let mapping = document
+        .as_mapping_mut()
+        .context("Conversation file is not a mapping")?;
+
+    let mut version = original_version;
+    while version < CURRENT_VERSION {
+        match version {
+            0 => migrate_v0_to_v1(mapping)?,
+            _ => unreachable!("missing migration from version {version}"),
+        }
+        version += 1;
+    }
+    mapping.insert(Value::from(VERSION_KEY), Value::from(CURRENT_VERSION));
+    Ok(original_version)
+}
+
+/// Flat list of messages becomes a tree with a single branch
+fn migrate_v0_to_v1(mapping: &mut Mapping) -> Result<()> {
+    let messages = match mapping.remove("messages") {
+        Some(Value::Sequence(messages)) => messages,
+        Some(Value::Null) | None => vec![],
+        Some(_) => anyhow::bail!("Conversation messages are not a list"),
+    };
+    let head = messages.len().checked_sub(1);
+    let mut nodes = Vec::with_capacity(messages.len());
+    for (id, message) in messages.into_iter().enumerate() {
+        let Value::Mapping(mut node) = message else {
+            anyhow::bail!("Message {id} is not a mapping");
+        };
+        node.insert(Value::from("id"), Value::from(id as u64));
+        if id > 0 {
+            node.insert(Value::from("parent"), Value::from(id as u64 - 1));
+        }
+        nodes.push(Value::Mapping(node));
+    }
+    mapping.insert(Value::from("nodes"), Value::Sequence(nodes));
+    if let Some(head) = head {
+        mapping.insert(Value::from("head"), Value::from(head as u64));
+    }
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use crate::configuration::AppConfig;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use tiktoken_rs::{cl100k_base, p50k_base, r50k_base, CoreBPE};
+
+pub const DEFAULT_MODEL_NAME: &str = "gpt-3.5-turbo";
+
+/// Byte pair encoding used by a model
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Tokenizer {
+    /// used by gpt-3.5-turbo and gpt-4
+    #[default]
+    Cl100kBase,
+    P50kBase,
+    R50kBase,
+}
+
+impl Tokenizer {
+    pub fn load(&self) -> Result<CoreBPE> {
+        let bpe = match self {
+            Tokenizer::Cl100kBase => cl100k_base(),
+            Tokenizer::P50kBase => p50k_base(),
+            Tokenizer::R50kBase => r50k_base(),
+        };
+        bpe.with_context(|| format!("Failed to load tokenizer {self:?}"))
+    }
+
+    /// Tokenizer loaded once and shared by the whole process
+    ///
+    /// Building the BPE is expensive so use this instead of [Tokenizer::load]
+    pub fn shared(&self) -> Result<&'static CoreBPE> {
+        static CL100K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+        static P50K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+        static R50K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+        let cell = match self {
+            Tokenizer::Cl100kBase => &CL100K_BASE,
+            Tokenizer::P50kBase => &P50K_BASE,
+            Tokenizer::R50kBase => &R50K_BASE,
+        };
+        if let Some(bpe) = cell.get() {
+            return Ok(bpe);
+        }
+        let bpe = self.load()?;
+        Ok(cell.get_or_init(|| bpe))
+    }
+}
+
+/// Description of a chat model
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    /// name as used by the API
+    pub name: String,
+    /// max number of tokens for prompt and completion combined
+    pub context_window: u32,
+    #[serde(default)]
+    pub tokenizer: Tokenizer,
+    /// USD per 1000 prompt tokens
+    #[serde(default)]
+    pub prompt_price_per_1k_tokens: f64,
+    /// USD per 1000 completion tokens
+    #[serde(default)]
+    pub completion_price_per_1k_tokens: f64,
+    #[serde(default = "default_knowledge_cutoff")]
+    pub knowledge_cutoff: String,
+}
+
+fn default_knowledge_cutoff() -> String {
+    String::from("September 2021")
+}
+
+impl ModelInfo {
+    fn new(
+        name: &str,
+        context_window: u32,
+        prompt_price_per_1k_tokens: f64,
+        completion_price_per_1k_tokens: f64,
+    ) -> Self {
+        Self {
+            name: name.to_owned(),
+            context_window,
+            tokenizer: Tokenizer::Cl100kBase,
+            prompt_price_per_1k_tokens,
+            completion_price_per_1k_tokens,
+            knowledge_cutoff: default_knowledge_cutoff(),
+        }
+    }
+
+    /// price in USD for given token counts
+    pub fn cost(&self, prompt_tokens: u32, completion_tokens: u32) -> f64 {
+        (prompt_tokens as f64 * self.prompt_price_per_1k_tokens
+            + completion_tokens as f64 * self.completion_price_per_1k_tokens)
+            / 1000.0
+    }
+}
+
+impl Default for ModelInfo {
+    fn default() -> Self {
+        Self::new(DEFAULT_MODEL_NAME, 4096, 0.002, 0.002)
+    }
+}
+
+/// Known models
+///
+/// Built in models can be overridden or extended by `models` in [AppConfig]
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    models: Vec<ModelInfo>,
+    default_model: String,
+    title_model: Option<String>,
+}
+
+impl ModelRegistry {
+    pub fn builtin() -> Self {
+        Self {
+            models: vec![
+                ModelInfo::default(),
+                ModelInfo::new("gpt-3.5-turbo-0301", 4096, 0.002, 0.002),
+                ModelInfo::new("gpt-4", 8192, 0.03, 0.06),
+                ModelInfo::new("gpt-4-0314", 8192, 0.03, 0.06),
+                ModelInfo::new("gpt-4-32k", 32768, 0.06, 0.12),
+                ModelInfo::new("gpt-4-32k-0314", 32768, 0.06, 0.12),
+            ],
+            default_model: String::from(DEFAULT_MODEL_NAME),
+            title_model: None,
+        }
+    }
+
+    pub fn from_config(config: &AppConfig) -> Self {
+        let mut registry = Self::builtin();
+        for model in &config.models {
+            registry.insert(model.clone());
+        }
+        if let Some(default_model) = &config.default_model {
+            registry.default_model = default_model.clone();
+        }
+        registry.title_model = config.title_model.clone();
+        registry
+    }
+
+    /// add model or replace model with the same name
+    pub fn insert(&mut self, model: ModelInfo) {
+        if let Some(existing) = self.models.iter_mut().find(|m| m.name == model.name) {
+            *existing = model;
+        } else {
+            self.models.push(model);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<&ModelInfo> {
+        self.models
+            .iter()
+            .find(|model| model.name == name)
+            .with_context(|| format!("Unknown model {name}"))
+    }
+
+    pub fn default_model(&self) -> Result<&ModelInfo> {
+        self.get(&self.default_model)
+    }
+
+    /// Model used for titles if it's different from the conversation model
+    pub fn title_model(&self) -> Result<Option<&ModelInfo>> {
+        self.title_model
+            .as_deref()
+            .map(|name| self.get(name))
+            .transpose()
+    }
+
+    pub fn models(&self) -> &[ModelInfo] {
+        &self.models
+    }
+
+    pub fn model_names(&self) -> Vec<&str> {
+        self.models
+            .iter()
+            .map(|model| model.name.as_str())
+            .collect()
+    }
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
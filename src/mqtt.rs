@@ -1,4 +1,5 @@
-use crate::configuration::MqttConfig;
+use crate::{configuration::MqttConfig, stream_display::ChatStreamDisplay};
+use async_trait::async_trait;
 use rumqttc::{AsyncClient, ConnAck, Event, Incoming, MqttOptions, Publish, QoS, SubscribeFilter};
 use std::time::Duration;
 use tokio::sync::mpsc::{channel, Receiver};
@@ -87,3 +88,81 @@ pub async fn start_mqtt_service_with_subs(
 
     Ok((client, receiver))
 }
+
+/// Publishes the whole response so far on every update
+///
+/// `{topic}/status` gets `started` and `finished` so subscribers know when the answer is complete
+pub struct MqttChatStreamDisplay {
+    buffer: String,
+    topic: String,
+    client: AsyncClient,
+}
+
+impl MqttChatStreamDisplay {
+    pub fn new(topic: &str, client: AsyncClient) -> Self {
+        Self {
+            buffer: String::new(),
+            topic: topic.to_owned(),
+            client,
+        }
+    }
+
+    async fn publish_status(&self, status: &str) -> anyhow::Result<()> {
+        self.client
+            .publish(
+                format!("{}/status", self.topic),
+                QoS::AtMostOnce,
+                false,
+                status,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ChatStreamDisplay for MqttChatStreamDisplay {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        self.buffer.clear();
+        self.publish_status("started").await
+    }
+
+    async fn push_message(&mut self, text: &str) -> anyhow::Result<()> {
+        self.buffer.push_str(text);
+        self.client
+            .publish(&self.topic, QoS::AtMostOnce, false, self.buffer.as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> anyhow::Result<()> {
+        self.publish_status("finished").await
+    }
+}
+
+/// Publishes every update as a separate message
+///
+/// Wrap in [crate::stream_display::SentenceChunker] to speak sentences as soon as they are complete
+pub struct MqttMessageDisplay {
+    topic: String,
+    client: AsyncClient,
+}
+
+impl MqttMessageDisplay {
+    pub fn new(topic: &str, client: AsyncClient) -> Self {
+        Self {
+            topic: topic.to_owned(),
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl ChatStreamDisplay for MqttMessageDisplay {
+    async fn push_message(&mut self, text: &str) -> anyhow::Result<()> {
+        self.client
+            .publish(&self.topic, QoS::AtMostOnce, false, text)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+use crate::{
+    configuration::{get_project_dirs, SamplingParameters},
+    models::ModelInfo,
+    utils::now_rfc3339,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+const PERSONAS_DIR_NAME: &str = "personas";
+
+/// Persona used for new conversations when none is selected
+pub const DEFAULT_PERSONA_NAME: &str = "joi";
+
+/// System prompt together with the model and parameters it works best with
+///
+/// Stored as YAML files in the `personas` directory next to the user config
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Persona {
+    /// defaults to the file name
+    #[serde(default)]
+    pub name: String,
+    /// template for the system message, see [render_template]
+    pub system_prompt: String,
+    /// model for new conversations unless one is picked explicitly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "SamplingParameters::is_empty")]
+    pub sampling: SamplingParameters,
+}
+
+impl Persona {
+    fn builtin(name: &str, system_prompt: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            system_prompt: system_prompt.to_owned(),
+            model: None,
+            sampling: SamplingParameters::default(),
+        }
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut persona: Persona = serde_yaml::from_reader(file)
+            .with_context(|| format!("Failed to parse persona {}", path.display()))?;
+        if persona.name.is_empty() {
+            persona.name = path
+                .file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+                .context("Persona file has no name")?;
+        }
+        persona.sampling.validate()?;
+        Ok(persona)
+    }
+
+    /// System message for conversation with `model`
+    pub fn system_message(&self, model: &ModelInfo) -> Result<String> {
+        render_template(&self.system_prompt, model)
+            .with_context(|| format!("Invalid system prompt of persona {}", self.name))
+    }
+}
+
+/// Known personas
+///
+/// Built in personas can be overridden or extended by files in [personas_dir]
+#[derive(Debug, Clone)]
+pub struct PersonaRegistry {
+    personas: Vec<Persona>,
+}
+
+impl PersonaRegistry {
+    pub fn builtin() -> Self {
+        Self {
+            personas: vec![
+                Persona::builtin(
+                    "default",
+                    "You are ChatGPT, a large language model trained by OpenAI.
+Answer as concisely as possible. Knowledge cutoff year {{knowledge_cutoff}} Current date and time: {{now}}",
+                ),
+                Persona::builtin(
+                    DEFAULT_PERSONA_NAME,
+                    "You are Joi. The cheerful and helpful AI assistant.
+Knowledge cutoff year {{knowledge_cutoff}} Current date and time: {{now}}",
+                ),
+            ],
+        }
+    }
+
+    /// Built in personas and every `.yaml` file in `dir`
+    ///
+    /// Files that can't be parsed are skipped with a warning
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut registry = Self::builtin();
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(registry),
+            Err(error) => {
+                return Err(error).with_context(|| format!("Failed to read {}", dir.display()))
+            }
+        };
+        let mut paths = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            let is_yaml = path
+                .extension()
+                .map(|extension| extension == "yaml" || extension == "yml")
+                .unwrap_or(false);
+            if is_yaml {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        for path in paths {
+            match Persona::load_from_file(&path) {
+                Ok(persona) => registry.insert(persona),
+                Err(error) => warn!("Skipping persona {:?}", error),
+            }
+        }
+        Ok(registry)
+    }
+
+    pub fn load_default() -> Result<Self> {
+        Self::load(&personas_dir()?)
+    }
+
+    /// Add persona replacing one with the same name
+    pub fn insert(&mut self, persona: Persona) {
+        match self
+            .personas
+            .iter_mut()
+            .find(|existing| existing.name == persona.name)
+        {
+            Some(existing) => *existing = persona,
+            None => self.personas.push(persona),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Persona> {
+        self.personas
+            .iter()
+            .find(|persona| persona.name == name)
+            .with_context(|| {
+                format!(
+                    "Unknown persona {name}, known personas are: {}",
+                    self.persona_names().join(", ")
+                )
+            })
+    }
+
+    pub fn personas(&self) -> &[Persona] {
+        &self.personas
+    }
+
+    pub fn persona_names(&self) -> Vec<&str> {
+        self.personas
+            .iter()
+            .map(|persona| persona.name.as_str())
+            .collect()
+    }
+}
+
+/// Directory with user persona files
+pub fn personas_dir() -> Result<PathBuf> {
+    Ok(get_project_dirs()?.config_dir().join(PERSONAS_DIR_NAME))
+}
+
+/// Replace `{{variable}}` placeholders in system prompt template
+///
+/// Supported variables:
+/// - `now` current date and time
+/// - `knowledge_cutoff` of `model`
+/// - `model` name
+/// - `cwd` current working directory
+/// - `env.NAME` value of environment variable `NAME`
+///
+/// Write `\{{` for a literal `{{`.
+/// Unknown variables and unset environment variables are errors
+/// so that typos don't end up in the prompt
+pub fn render_template(template: &str, model: &ModelInfo) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        if let Some(before_escape) = rest[..start].strip_suffix('\\') {
+            rendered.push_str(before_escape);
+            rendered.push_str("{{");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        rendered.push_str(&rest[..start]);
+        let after_open = &rest[start + 2..];
+        let end = after_open
+            .find("}}")
+            .with_context(|| format!("Unclosed {{{{ in template at \"{}\"", &rest[start..]))?;
+        let variable = after_open[..end].trim();
+        rendered.push_str(&template_variable(variable, model)?);
+        rest = &after_open[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+fn template_variable(variable: &str, model: &ModelInfo) -> Result<String> {
+    if let Some(name) = variable.strip_prefix("env.") {
+        return std::env::var(name)
+            .with_context(|| format!("Environment variable {name} used in template is not set"));
+    }
+    match variable {
+        "now" => Ok(now_rfc3339()),
+        "knowledge_cutoff" => Ok(model.knowledge_cutoff.clone()),
+        "model" => Ok(model.name.clone()),
+        "cwd" => Ok(std::env::current_dir()
+            .context("Failed to get current directory")?
+            .display()
+            .to_string()),
+        _ => anyhow::bail!("Unknown template variable \"{variable}\""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> ModelInfo {
+        ModelInfo {
+            name: "gpt-4".to_owned(),
+            knowledge_cutoff: "September 2021".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn variables_are_replaced() {
+        assert_eq!(
+            render_template("{{model}} knows until {{ knowledge_cutoff }}.", &model()).unwrap(),
+            "gpt-4 knows until September 2021."
+        );
+        assert_eq!(
+            render_template("no variables", &model()).unwrap(),
+            "no variables"
+        );
+    }
+
+    #[test]
+    fn escaped_braces_are_literal() {
+        assert_eq!(
+            render_template(r"Use \{{name}} placeholders with {{model}}", &model()).unwrap(),
+            "Use {{name}} placeholders with gpt-4"
+        );
+        assert_eq!(
+            render_template(r"\{{ unclosed", &model()).unwrap(),
+            "{{ unclosed"
+        );
+        // only the backslash right before the braces escapes them
+        assert_eq!(
+            render_template(r"path\ {{model}}", &model()).unwrap(),
+            r"path\ gpt-4"
+        );
+    }
+
+    #[test]
+    fn template_errors() {
+        let error = render_template("{{modle}}", &model()).unwrap_err();
+        assert!(
+            error.to_string().contains("Unknown template variable"),
+            "{error}"
+        );
+        let error = render_template("{{model", &model()).unwrap_err();
+        assert!(error.to_string().contains("Unclosed"), "{error}");
+        let error = render_template("{{env.CHATTY_TEST_UNSET_VARIABLE}}", &model()).unwrap_err();
+        assert!(error.to_string().contains("is not set"), "{error}");
+    }
+}
@@ -0,0 +1,420 @@
+use crate::{chat_backend::ChatBackend, configuration::RetryConfig};
+use anyhow::Result;
+use async_openai::{
+    error::OpenAIError,
+    types::{
+        ChatCompletionResponseStream, CreateChatCompletionRequest, CreateChatCompletionResponse,
+        CreateTranscriptionRequest,
+    },
+    Client,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use rand::Rng;
+use std::{future::Future, time::Duration};
+use tracing::warn;
+
+/// Exponential backoff with jitter for transient API failures
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryPolicy {
+    config: RetryConfig,
+}
+
+impl RetryPolicy {
+    pub fn new(config: RetryConfig) -> Self {
+        Self { config }
+    }
+
+    /// policy that never retries
+    pub fn disabled() -> Self {
+        Self {
+            config: RetryConfig {
+                max_retries: 0,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Run operation until it succeeds, fails with non transient error or runs out of retries
+    pub async fn run<T, F, Fut>(&self, operation_name: &str, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match operation().await {
+                Ok(value) => return Ok(value),
+                Err(error) if attempt < self.config.max_retries && is_transient(&error) => {
+                    let delay = self.delay(attempt, retry_after(&error));
+                    attempt += 1;
+                    warn!(
+                        "{} failed with {:#}. Retry {}/{} in {:?}",
+                        operation_name, error, attempt, self.config.max_retries, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Delay before retry number `attempt`
+    ///
+    /// Delay requested by the server takes precedence over the backoff
+    /// but is still capped at `max_backoff_ms`
+    fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let max_backoff = Duration::from_millis(self.config.max_backoff_ms);
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(max_backoff);
+        }
+        let backoff = Duration::from_millis(self.config.initial_backoff_ms)
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(max_backoff);
+        // jitter between half and full backoff so that parallel clients don't retry in lockstep
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Transcribe audio with Whisper and return the text
+pub async fn transcribe(
+    client: &Client,
+    policy: &RetryPolicy,
+    request: CreateTranscriptionRequest,
+) -> Result<String> {
+    let response = policy
+        .run("Transcription", || async {
+            Ok(client.audio().transcribe(request.clone()).await?)
+        })
+        .await?;
+    Ok(response.text)
+}
+
+/// Errors worth retrying: rate limits, server errors, timeouts and connection problems
+///
+/// API errors are classified by their `type` and `code` rather than the human readable message
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    let Some(error) = error.downcast_ref::<OpenAIError>() else {
+        return false;
+    };
+    match error {
+        OpenAIError::Reqwest(error) => {
+            error.is_timeout()
+                || error.is_connect()
+                || error
+                    .status()
+                    .map(|status| is_transient_status(status.as_u16()))
+                    .unwrap_or(false)
+        }
+        OpenAIError::ApiError(api_error) => {
+            let code = api_error.code.as_ref().and_then(|code| code.as_str());
+            // running out of quota is also reported as 429 but retrying won't help
+            if code == Some("insufficient_quota") || api_error.r#type == "insufficient_quota" {
+                return false;
+            }
+            code == Some("rate_limit_exceeded")
+                || ["requests", "tokens", "server_error"].contains(&api_error.r#type.as_str())
+        }
+        // streams only report errors of the event source as text
+        OpenAIError::StreamError(message) => {
+            if let Some(status) = message.strip_prefix("Invalid status code: ") {
+                status
+                    .split_whitespace()
+                    .next()
+                    .and_then(|code| code.parse().ok())
+                    .map(is_transient_status)
+                    .unwrap_or(false)
+            } else {
+                message.starts_with("error sending request") || message.contains("timed out")
+            }
+        }
+        _ => false,
+    }
+}
+
+fn is_transient_status(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
+
+/// OpenAI client without its built in backoff
+///
+/// async-openai retries rate limited requests on its own which would stack
+/// with [RetryPolicy], so the client gives up right away and leaves retrying to the policy
+pub fn openai_client(api_key: &str) -> Client {
+    let no_backoff = backoff::ExponentialBackoffBuilder::new()
+        .with_max_elapsed_time(Some(Duration::ZERO))
+        .build();
+    Client::new().with_api_key(api_key).with_backoff(no_backoff)
+}
+
+/// Delay requested by the API
+///
+/// The OpenAI client doesn't expose response headers so Retry-After is read from
+/// the error message instead ("Please try again in 20s")
+pub fn retry_after(error: &anyhow::Error) -> Option<Duration> {
+    let message = match error.downcast_ref::<OpenAIError>()? {
+        OpenAIError::ApiError(api_error) => api_error.message.clone(),
+        OpenAIError::StreamError(message) => message.clone(),
+        _ => return None,
+    };
+    let (_, after) = message.split_once("try again in ")?;
+    let number_len = after
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .count();
+    let value: f64 = after[..number_len].parse().ok()?;
+    let unit = &after[number_len..];
+    if unit.starts_with("ms") {
+        Some(Duration::from_secs_f64(value / 1000.0))
+    } else if unit.starts_with('s') {
+        Some(Duration::from_secs_f64(value))
+    } else {
+        None
+    }
+}
+
+/// [ChatBackend] that retries transient failures of the wrapped backend
+#[derive(Clone)]
+pub struct RetryingChatBackend<B> {
+    inner: B,
+    policy: RetryPolicy,
+}
+
+impl<B: ChatBackend> RetryingChatBackend<B> {
+    pub fn new(inner: B, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+}
+
+#[async_trait]
+impl<B: ChatBackend> ChatBackend for RetryingChatBackend<B> {
+    async fn complete(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse> {
+        self.policy
+            .run("Chat completion", || self.inner.complete(request.clone()))
+            .await
+    }
+
+    /// Stream is only restarted if it fails before any content arrived
+    ///
+    /// Chunks received before the first content are buffered and replayed
+    async fn complete_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<ChatCompletionResponseStream> {
+        self.policy
+            .run("Chat completion stream", || async {
+                let mut stream = self.inner.complete_stream(request.clone()).await?;
+                let mut buffered = vec![];
+                while let Some(chunk) = stream.next().await {
+                    let chunk = chunk?;
+                    let has_content = chunk
+                        .choices
+                        .iter()
+                        .any(|choice| choice.delta.content.is_some());
+                    buffered.push(Ok(chunk));
+                    if has_content {
+                        break;
+                    }
+                }
+                let stream: ChatCompletionResponseStream =
+                    Box::pin(futures::stream::iter(buffered).chain(stream));
+                Ok(stream)
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::error::ApiError;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn api_error(message: &str, r#type: &str, code: Option<&str>) -> anyhow::Error {
+        OpenAIError::ApiError(ApiError {
+            message: message.to_owned(),
+            r#type: r#type.to_owned(),
+            param: None,
+            code: code.map(|code| code.into()),
+        })
+        .into()
+    }
+
+    fn rate_limit(message: &str) -> anyhow::Error {
+        api_error(message, "requests", Some("rate_limit_exceeded"))
+    }
+
+    fn policy(max_retries: u32, initial_backoff_ms: u64, max_backoff_ms: u64) -> RetryPolicy {
+        RetryPolicy::new(RetryConfig {
+            max_retries,
+            initial_backoff_ms,
+            max_backoff_ms,
+        })
+    }
+
+    #[test]
+    fn retry_after_parsing() {
+        let cases = [
+            (
+                "Rate limit reached. Please try again in 20s.",
+                Some(Duration::from_secs(20)),
+            ),
+            (
+                "Please try again in 1.5s",
+                Some(Duration::from_millis(1500)),
+            ),
+            (
+                "Please try again in 500ms.",
+                Some(Duration::from_millis(500)),
+            ),
+            ("Please try again in 2m.", None),
+            ("Please try again later", None),
+            ("Rate limit reached", None),
+        ];
+        for (message, expected) in cases {
+            assert_eq!(retry_after(&rate_limit(message)), expected, "{message}");
+        }
+        let stream_error = OpenAIError::StreamError("Please try again in 3s".to_owned()).into();
+        assert_eq!(retry_after(&stream_error), Some(Duration::from_secs(3)));
+        assert_eq!(
+            retry_after(&anyhow::anyhow!("Please try again in 3s")),
+            None
+        );
+    }
+
+    #[test]
+    fn transient_errors() {
+        let cases = [
+            (rate_limit("Rate limit reached"), true),
+            (api_error("Too many tokens", "tokens", None), true),
+            (
+                api_error("The server had an error", "server_error", None),
+                true,
+            ),
+            (
+                api_error("That model is overloaded", "server_error", None),
+                true,
+            ),
+            (
+                api_error(
+                    "You exceeded your current quota",
+                    "insufficient_quota",
+                    None,
+                ),
+                false,
+            ),
+            (
+                api_error("Quota exceeded", "requests", Some("insufficient_quota")),
+                false,
+            ),
+            (
+                api_error("Invalid model", "invalid_request_error", None),
+                false,
+            ),
+            // message text alone doesn't make an error transient
+            (
+                api_error("Please try again", "invalid_request_error", None),
+                false,
+            ),
+            (
+                OpenAIError::StreamError("Invalid status code: 429 Too Many Requests".to_owned())
+                    .into(),
+                true,
+            ),
+            (
+                OpenAIError::StreamError("Invalid status code: 503 Service Unavailable".to_owned())
+                    .into(),
+                true,
+            ),
+            (
+                OpenAIError::StreamError("Invalid status code: 401 Unauthorized".to_owned()).into(),
+                false,
+            ),
+            (
+                OpenAIError::StreamError("error sending request for url".to_owned()).into(),
+                true,
+            ),
+            (
+                OpenAIError::InvalidArgument("missing model".to_owned()).into(),
+                false,
+            ),
+            (anyhow::anyhow!("Rate limit reached"), false),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(is_transient(&error), expected, "{error:?}");
+        }
+    }
+
+    #[test]
+    fn retry_after_is_capped_at_max_backoff() {
+        let policy = policy(3, 100, 1000);
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(20))),
+            Duration::from_millis(1000)
+        );
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_millis(300))),
+            Duration::from_millis(300)
+        );
+    }
+
+    #[test]
+    fn backoff_grows_with_jitter_up_to_max() {
+        let policy = policy(10, 100, 1000);
+        for _ in 0..20 {
+            let first = policy.delay(0, None);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let third = policy.delay(2, None);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+            assert!(policy.delay(9, None) <= Duration::from_millis(1000));
+        }
+    }
+
+    #[tokio::test]
+    async fn run_retries_transient_errors_only() {
+        let attempts = AtomicU32::new(0);
+        let result = policy(3, 1, 1)
+            .run("Test", || async {
+                if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    Err(rate_limit("Rate limit reached"))
+                } else {
+                    Ok("done")
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(result, "done");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = policy(3, 1, 1)
+            .run("Test", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(api_error("Invalid model", "invalid_request_error", None))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = policy(2, 1, 1)
+            .run("Test", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(rate_limit("Rate limit reached"))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
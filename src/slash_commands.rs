@@ -0,0 +1,395 @@
+use crate::export::ExportFormat;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use dialoguer::Completion;
+use std::{collections::HashMap, path::PathBuf};
+
+/// Whether a command takes an argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Argument {
+    None,
+    Optional,
+    Required,
+}
+
+/// Command that can be typed in chat
+#[derive(Debug, Clone)]
+pub struct CommandSpec {
+    /// name without the leading `/`
+    pub name: &'static str,
+    pub argument: Argument,
+    /// shown in help, for example `[name]`
+    pub usage: &'static str,
+    pub description: &'static str,
+}
+
+impl CommandSpec {
+    const fn new(
+        name: &'static str,
+        argument: Argument,
+        usage: &'static str,
+        description: &'static str,
+    ) -> Self {
+        Self {
+            name,
+            argument,
+            usage,
+            description,
+        }
+    }
+}
+
+/// Commands known to gpt-cli
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec::new(
+        "model",
+        Argument::Optional,
+        "[name]",
+        "switch model, picker without name",
+    ),
+    CommandSpec::new(
+        "system",
+        Argument::Optional,
+        "[prompt]",
+        "replace system prompt, show it without prompt",
+    ),
+    CommandSpec::new(
+        "persona",
+        Argument::Optional,
+        "[name]",
+        "switch persona, picker without name",
+    ),
+    CommandSpec::new(
+        "title",
+        Argument::Optional,
+        "[title]",
+        "set title, generate a new one without title",
+    ),
+    CommandSpec::new(
+        "save",
+        Argument::Required,
+        "<name>",
+        "save conversation under name",
+    ),
+    CommandSpec::new(
+        "clear",
+        Argument::None,
+        "",
+        "start new conversation with the same settings",
+    ),
+    CommandSpec::new(
+        "undo",
+        Argument::None,
+        "",
+        "remove last question and its response",
+    ),
+    CommandSpec::new(
+        "tokens",
+        Argument::None,
+        "",
+        "show token usage of the conversation",
+    ),
+    CommandSpec::new(
+        "export",
+        Argument::Optional,
+        "[markdown|html|jsonl] [file]",
+        "render conversation, printed without file",
+    ),
+    CommandSpec::new("help", Argument::None, "", "list commands"),
+    CommandSpec::new("?", Argument::None, "", "open action menu"),
+];
+
+/// Parsed `/command`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlashCommand {
+    Model(Option<String>),
+    System(Option<String>),
+    Persona(Option<String>),
+    Title(Option<String>),
+    Save(String),
+    Clear,
+    Undo,
+    Tokens,
+    Export {
+        format: ExportFormat,
+        output: Option<PathBuf>,
+    },
+    Help,
+    Menu,
+}
+
+/// What the user typed in chat
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatInput {
+    /// message for the model
+    Message(String),
+    Command(SlashCommand),
+}
+
+/// Split user input into message or command
+///
+/// Input starting with `//` is a message starting with `/`.
+/// Unknown commands and wrong arguments are errors so that typos aren't sent to the model
+pub fn parse_input(input: &str) -> Result<ChatInput> {
+    let trimmed = input.trim();
+    if let Some(message) = trimmed.strip_prefix("//") {
+        return Ok(ChatInput::Message(format!("/{message}")));
+    }
+    let Some(command) = trimmed.strip_prefix('/') else {
+        return Ok(ChatInput::Message(input.to_owned()));
+    };
+    let (name, argument) = match command.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, Some(argument.trim()).filter(|a| !a.is_empty())),
+        None => (command, None),
+    };
+    let spec = COMMANDS
+        .iter()
+        .find(|spec| spec.name == name)
+        .with_context(|| format!("Unknown command /{name}, type /help to list commands"))?;
+    match (spec.argument, &argument) {
+        (Argument::None, Some(_)) => anyhow::bail!("/{name} doesn't take arguments"),
+        (Argument::Required, None) => {
+            anyhow::bail!("/{name} needs an argument: /{name} {}", spec.usage)
+        }
+        _ => (),
+    }
+    let argument = argument.map(|argument| argument.to_owned());
+    let command = match spec.name {
+        "model" => SlashCommand::Model(argument),
+        "system" => SlashCommand::System(argument),
+        "persona" => SlashCommand::Persona(argument),
+        "title" => SlashCommand::Title(argument),
+        "save" => SlashCommand::Save(argument.unwrap_or_default()),
+        "clear" => SlashCommand::Clear,
+        "undo" => SlashCommand::Undo,
+        "tokens" => SlashCommand::Tokens,
+        "export" => parse_export(argument.as_deref())?,
+        "help" => SlashCommand::Help,
+        "?" => SlashCommand::Menu,
+        _ => unreachable!("Command {name} is registered but not parsed"),
+    };
+    Ok(ChatInput::Command(command))
+}
+
+/// `[format] [file]`, format defaults to markdown
+fn parse_export(argument: Option<&str>) -> Result<SlashCommand> {
+    let argument = argument.unwrap_or_default();
+    let (first, rest) = argument
+        .split_once(char::is_whitespace)
+        .unwrap_or((argument, ""));
+    let (format, output) = match ExportFormat::from_str(first, true) {
+        Ok(format) => (format, rest.trim()),
+        Err(_) => (ExportFormat::Markdown, argument),
+    };
+    Ok(SlashCommand::Export {
+        format,
+        output: Some(output)
+            .filter(|output| !output.is_empty())
+            .map(PathBuf::from),
+    })
+}
+
+/// Help text listing every command
+pub fn help() -> String {
+    COMMANDS
+        .iter()
+        .map(|spec| {
+            let command = format!("/{} {}", spec.name, spec.usage);
+            format!("{:<38} {}", command.trim_end(), spec.description)
+        })
+        .chain(std::iter::once(format!(
+            "{:<38} {}",
+            "//message", "send message starting with /"
+        )))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Tab completion of command names and their arguments
+#[derive(Debug, Clone, Default)]
+pub struct CommandCompletion {
+    /// possible arguments per command name
+    arguments: HashMap<&'static str, Vec<String>>,
+}
+
+impl CommandCompletion {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Complete arguments of command `name` from `values`
+    pub fn with_arguments(mut self, name: &'static str, values: Vec<String>) -> Self {
+        self.arguments.insert(name, values);
+        self
+    }
+}
+
+impl Completion for CommandCompletion {
+    fn get(&self, input: &str) -> Option<String> {
+        let command = input.strip_prefix('/')?;
+        match command.split_once(' ') {
+            None => {
+                let names = COMMANDS.iter().map(|spec| spec.name);
+                let name = complete(command, names)?;
+                let takes_argument = COMMANDS
+                    .iter()
+                    .any(|spec| spec.name == name && spec.argument != Argument::None);
+                if takes_argument && name != command {
+                    Some(format!("/{name} "))
+                } else {
+                    Some(format!("/{name}"))
+                }
+            }
+            Some((name, argument)) => {
+                let values = self.arguments.get(name)?;
+                let argument = complete(argument, values.iter().map(|value| value.as_str()))?;
+                Some(format!("/{name} {argument}"))
+            }
+        }
+    }
+}
+
+/// Only candidate starting with `prefix` or the longest prefix they have in common
+fn complete<'a>(prefix: &str, candidates: impl Iterator<Item = &'a str>) -> Option<String> {
+    let mut matching = candidates.filter(|candidate| candidate.starts_with(prefix));
+    let mut common = matching.next()?.to_owned();
+    for candidate in matching {
+        let length = common
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map(|((index, _), _)| index)
+            .unwrap_or_else(|| common.len().min(candidate.len()));
+        common.truncate(length);
+    }
+    Some(common)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(input: &str) -> SlashCommand {
+        match parse_input(input).unwrap() {
+            ChatInput::Command(command) => command,
+            ChatInput::Message(message) => panic!("{input} parsed as message {message}"),
+        }
+    }
+
+    fn export(format: ExportFormat, output: Option<&str>) -> SlashCommand {
+        SlashCommand::Export {
+            format,
+            output: output.map(PathBuf::from),
+        }
+    }
+
+    #[test]
+    fn messages_pass_through() {
+        assert_eq!(
+            parse_input("  hello there ").unwrap(),
+            ChatInput::Message("  hello there ".to_owned())
+        );
+        assert_eq!(
+            parse_input("path a/b").unwrap(),
+            ChatInput::Message("path a/b".to_owned())
+        );
+    }
+
+    #[test]
+    fn double_slash_escapes_message() {
+        assert_eq!(
+            parse_input("//model is a command").unwrap(),
+            ChatInput::Message("/model is a command".to_owned())
+        );
+        assert_eq!(
+            parse_input("  //etc/hosts").unwrap(),
+            ChatInput::Message("/etc/hosts".to_owned())
+        );
+    }
+
+    #[test]
+    fn commands_with_arguments() {
+        assert_eq!(command("/model"), SlashCommand::Model(None));
+        assert_eq!(command("/model   "), SlashCommand::Model(None));
+        assert_eq!(
+            command("/model gpt-4"),
+            SlashCommand::Model(Some("gpt-4".to_owned()))
+        );
+        assert_eq!(
+            command("/system  You are a pirate  "),
+            SlashCommand::System(Some("You are a pirate".to_owned()))
+        );
+        assert_eq!(
+            command("/save notes"),
+            SlashCommand::Save("notes".to_owned())
+        );
+        assert_eq!(command("/clear"), SlashCommand::Clear);
+        assert_eq!(command("/?"), SlashCommand::Menu);
+    }
+
+    #[test]
+    fn argument_checks() {
+        let error = parse_input("/save").unwrap_err().to_string();
+        assert!(error.contains("needs an argument"), "{error}");
+        let error = parse_input("/undo everything").unwrap_err().to_string();
+        assert!(error.contains("doesn't take arguments"), "{error}");
+        let error = parse_input("/modle gpt-4").unwrap_err().to_string();
+        assert!(error.contains("Unknown command /modle"), "{error}");
+    }
+
+    #[test]
+    fn export_arguments() {
+        assert_eq!(command("/export"), export(ExportFormat::Markdown, None));
+        assert_eq!(command("/export html"), export(ExportFormat::Html, None));
+        assert_eq!(
+            command("/export JSONL train.jsonl"),
+            export(ExportFormat::Jsonl, Some("train.jsonl"))
+        );
+        // file without format is markdown
+        assert_eq!(
+            command("/export notes.md"),
+            export(ExportFormat::Markdown, Some("notes.md"))
+        );
+        assert_eq!(
+            parse_export(Some("markdown  my notes.md")).unwrap(),
+            export(ExportFormat::Markdown, Some("my notes.md"))
+        );
+    }
+
+    #[test]
+    fn complete_common_prefix() {
+        let candidates = ["gpt-4", "gpt-4-0314", "gpt-3.5-turbo"];
+        assert_eq!(
+            complete("gpt-3", candidates.into_iter()),
+            Some("gpt-3.5-turbo".to_owned())
+        );
+        assert_eq!(
+            complete("gpt-4", candidates.into_iter()),
+            Some("gpt-4".to_owned())
+        );
+        assert_eq!(
+            complete("g", candidates.into_iter()),
+            Some("gpt-".to_owned())
+        );
+        assert_eq!(complete("claude", candidates.into_iter()), None);
+    }
+
+    #[test]
+    fn completion_of_commands_and_arguments() {
+        let completion = CommandCompletion::new().with_arguments(
+            "model",
+            vec!["gpt-4".to_owned(), "gpt-3.5-turbo".to_owned()],
+        );
+        // argument commands get a trailing space
+        assert_eq!(completion.get("/mo"), Some("/model ".to_owned()));
+        assert_eq!(completion.get("/un"), Some("/undo".to_owned()));
+        // /save and /system share a prefix
+        assert_eq!(completion.get("/s"), Some("/s".to_owned()));
+        assert_eq!(
+            completion.get("/model gpt-3"),
+            Some("/model gpt-3.5-turbo".to_owned())
+        );
+        assert_eq!(completion.get("/persona x"), None);
+        assert_eq!(completion.get("hello"), None);
+    }
+}
@@ -0,0 +1,368 @@
+use crate::{
+    chat_manager::ChatHistory,
+    configuration::get_project_dirs,
+    conversation_files::{ConversationLocation, ConversationMetadata, ConversationSummary},
+};
+use anyhow::{Context, Result};
+use chrono::prelude::{DateTime, Local};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::{Path, PathBuf};
+
+const DATABASE_FILE_NAME: &str = "conversations.sqlite3";
+
+const SCHEMA: &str = "
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE IF NOT EXISTS conversations (
+    id INTEGER PRIMARY KEY,
+    key TEXT NOT NULL UNIQUE,
+    title TEXT,
+    start TEXT,
+    model TEXT,
+    source_path TEXT,
+    document TEXT NOT NULL,
+    updated TEXT NOT NULL,
+    starred INTEGER NOT NULL DEFAULT 0,
+    message_count INTEGER NOT NULL DEFAULT 0,
+    first_question TEXT
+);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY,
+    conversation_id INTEGER NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    node_id INTEGER NOT NULL,
+    parent_node_id INTEGER,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    timestamp TEXT
+);
+
+CREATE INDEX IF NOT EXISTS messages_conversation ON messages(conversation_id);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+    content,
+    content = 'messages',
+    content_rowid = 'id'
+);
+
+CREATE TRIGGER IF NOT EXISTS messages_after_insert AFTER INSERT ON messages BEGIN
+    INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_after_delete AFTER DELETE ON messages BEGIN
+    INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+END;
+";
+
+/// Columns added after the first release, created on open for older databases
+const ADDED_CONVERSATION_COLUMNS: &[(&str, &str)] = &[
+    ("starred", "INTEGER NOT NULL DEFAULT 0"),
+    ("message_count", "INTEGER NOT NULL DEFAULT 0"),
+    ("first_question", "TEXT"),
+];
+
+/// Conversation found by [ConversationStore::search]
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub conversation_id: i64,
+    pub title: Option<String>,
+    pub start: Option<DateTime<Local>>,
+    /// matching part of the message with matches wrapped in `[` `]`
+    pub snippet: String,
+}
+
+/// Conversations stored in an embedded SQLite database
+///
+/// The full conversation is stored as a document in the same format as the YAML files.
+/// Messages are also stored in a separate table with a full-text index for search
+pub struct ConversationStore {
+    connection: Connection,
+}
+
+impl ConversationStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open database {}", path.display()))?;
+        connection.execute_batch(SCHEMA)?;
+        let mut store = Self { connection };
+        if add_missing_columns(&store.connection)? {
+            store.backfill_metadata()?;
+        }
+        Ok(store)
+    }
+
+    /// Fill metadata columns of conversations saved before they existed
+    fn backfill_metadata(&mut self) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        let documents = {
+            let mut statement = transaction.prepare("SELECT id, document FROM conversations")?;
+            let documents = statement
+                .query_map(params![], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            documents
+        };
+        for (conversation_id, document) in documents {
+            let metadata = ChatHistory::from_yaml(&document)
+                .with_context(|| format!("Invalid conversation {conversation_id}"))?
+                .metadata();
+            transaction.execute(
+                "UPDATE conversations SET starred = ?1, message_count = ?2, first_question = ?3
+                 WHERE id = ?4",
+                params![
+                    metadata.starred,
+                    metadata.message_count as i64,
+                    metadata.first_question,
+                    conversation_id
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Open database in the user data directory
+    pub fn open_default() -> Result<Self> {
+        Self::open(&Self::default_path()?)
+    }
+
+    pub fn default_path() -> Result<PathBuf> {
+        let project_dirs = get_project_dirs()?;
+        let data_dir = project_dirs.data_dir();
+        std::fs::create_dir_all(data_dir).context("failed to create user data directory")?;
+        Ok(data_dir.join(DATABASE_FILE_NAME))
+    }
+
+    /// Insert or replace conversation
+    ///
+    /// Conversations are identified by their start time or by the file they were loaded from
+    pub fn save(&mut self, chat_history: &ChatHistory) -> Result<i64> {
+        let key = conversation_key(chat_history)?;
+        let document = chat_history.to_yaml()?;
+        let metadata = chat_history.metadata();
+        let source_path = chat_history
+            .file_path()
+            .map(|path| path.display().to_string());
+
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO conversations (key, title, start, model, source_path, document, updated,
+                                        starred, message_count, first_question)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+             ON CONFLICT(key) DO UPDATE SET
+                title = excluded.title,
+                start = excluded.start,
+                model = excluded.model,
+                source_path = excluded.source_path,
+                document = excluded.document,
+                updated = excluded.updated,
+                starred = excluded.starred,
+                message_count = excluded.message_count,
+                first_question = excluded.first_question",
+            params![
+                key,
+                metadata.title,
+                metadata.start.map(|start| start.to_rfc3339()),
+                metadata.model,
+                source_path,
+                document,
+                Local::now().to_rfc3339(),
+                metadata.starred,
+                metadata.message_count as i64,
+                metadata.first_question,
+            ],
+        )?;
+        let conversation_id: i64 = transaction.query_row(
+            "SELECT id FROM conversations WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        )?;
+
+        transaction.execute(
+            "DELETE FROM messages WHERE conversation_id = ?1",
+            params![conversation_id],
+        )?;
+        {
+            let mut insert_message = transaction.prepare(
+                "INSERT INTO messages (conversation_id, node_id, parent_node_id, role, content, timestamp)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for node in chat_history.message_tree().nodes() {
+                insert_message.execute(params![
+                    conversation_id,
+                    node.id as i64,
+                    node.parent.map(|parent| parent as i64),
+                    node.message.role.to_string(),
+                    node.message.content,
+                    node.timestamp.map(|timestamp| timestamp.to_rfc3339()),
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(conversation_id)
+    }
+
+    /// Import conversation from YAML file
+    pub fn import_file(&mut self, path: &Path) -> Result<i64> {
+        let chat_history = ChatHistory::load_from_file(path)?;
+        self.save(&chat_history)
+    }
+
+    /// Load conversation by id
+    ///
+    /// Conversations imported from files keep writing to their file when saved with [ChatHistory::save_to_file]
+    pub fn load(&self, conversation_id: i64) -> Result<ChatHistory> {
+        let (document, source_path): (String, Option<String>) = self
+            .connection
+            .query_row(
+                "SELECT document, source_path FROM conversations WHERE id = ?1",
+                params![conversation_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .with_context(|| format!("Conversation {conversation_id} not found"))?;
+        let mut chat_history = ChatHistory::from_yaml(&document)?;
+        if let Some(source_path) = source_path {
+            chat_history.set_file_path(PathBuf::from(source_path));
+        }
+        Ok(chat_history)
+    }
+
+    /// Summaries of all conversations, most recently updated first
+    ///
+    /// Only metadata columns are read, documents stay in the database
+    pub fn list(&self) -> Result<Vec<ConversationSummary>> {
+        let mut statement = self.connection.prepare(
+            "SELECT id, title, start, model, starred, message_count, first_question, updated,
+                    length(document)
+             FROM conversations
+             ORDER BY updated DESC",
+        )?;
+        let mut rows = statement.query(params![])?;
+        let mut summaries = vec![];
+        while let Some(row) = rows.next()? {
+            let start: Option<String> = row.get(2)?;
+            let model: Option<String> = row.get(3)?;
+            let message_count: i64 = row.get(5)?;
+            let updated: String = row.get(7)?;
+            let size: i64 = row.get(8)?;
+            let metadata = ConversationMetadata {
+                title: row.get(1)?,
+                start: start.as_deref().and_then(parse_time),
+                model: model.unwrap_or_default(),
+                starred: row.get(4)?,
+                message_count: message_count as usize,
+                first_question: row.get(6)?,
+            };
+            summaries.push(ConversationSummary::new(
+                ConversationLocation::Database(row.get(0)?),
+                metadata,
+                parse_time(&updated).with_context(|| format!("Invalid update time {updated}"))?,
+                size as u64,
+            ));
+        }
+        Ok(summaries)
+    }
+
+    /// Remove conversation and its messages
+    pub fn delete(&mut self, conversation_id: i64) -> Result<()> {
+        let deleted = self.connection.execute(
+            "DELETE FROM conversations WHERE id = ?1",
+            params![conversation_id],
+        )?;
+        if deleted == 0 {
+            anyhow::bail!("Conversation {conversation_id} not found");
+        }
+        Ok(())
+    }
+
+    /// Full-text search over all messages
+    ///
+    /// Every word of the query has to appear in the matched message.
+    /// Returns best match per conversation ordered by relevance
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchResult>> {
+        let query = fts_query(query);
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
+        // snippet can't be used in aggregate queries so best match per conversation is picked here
+        let mut statement = self.connection.prepare(
+            "SELECT conversations.id, conversations.title, conversations.start,
+                    snippet(messages_fts, 0, '[', ']', '...', 12)
+             FROM messages_fts
+             JOIN messages ON messages.id = messages_fts.rowid
+             JOIN conversations ON conversations.id = messages.conversation_id
+             WHERE messages_fts MATCH ?1
+             ORDER BY messages_fts.rank",
+        )?;
+        let mut rows = statement.query(params![query])?;
+        let mut results: Vec<SearchResult> = vec![];
+        while let Some(row) = rows.next()? {
+            if results.len() >= limit {
+                break;
+            }
+            let conversation_id: i64 = row.get(0)?;
+            if results
+                .iter()
+                .any(|result| result.conversation_id == conversation_id)
+            {
+                continue;
+            }
+            let start: Option<String> = row.get(2)?;
+            results.push(SearchResult {
+                conversation_id,
+                title: row.get(1)?,
+                start: start.as_deref().and_then(parse_time),
+                snippet: row.get(3)?,
+            });
+        }
+        Ok(results)
+    }
+}
+
+/// Stable identifier of conversation used to update it in place
+fn conversation_key(chat_history: &ChatHistory) -> Result<String> {
+    if let Some(conversation_id) = chat_history.conversation_id() {
+        return Ok(conversation_id);
+    }
+    chat_history
+        .file_path()
+        .map(|path| path.display().to_string())
+        .context("Conversation has neither start time nor file")
+}
+
+/// Quote every word so that user input can't contain FTS5 query syntax
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Local>> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.with_timezone(&Local))
+}
+
+/// `CREATE TABLE IF NOT EXISTS` leaves tables of older databases as they were
+///
+/// Returns true if any column was added
+fn add_missing_columns(connection: &Connection) -> Result<bool> {
+    let mut statement = connection.prepare("PRAGMA table_info(conversations)")?;
+    let existing = statement
+        .query_map(params![], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut added = false;
+    for (name, definition) in ADDED_CONVERSATION_COLUMNS {
+        if !existing.iter().any(|column| column == name) {
+            connection.execute_batch(&format!(
+                "ALTER TABLE conversations ADD COLUMN {name} {definition};"
+            ))?;
+            added = true;
+        }
+    }
+    Ok(added)
+}
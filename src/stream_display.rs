@@ -0,0 +1,294 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::warn;
+
+/// Output that shows a response while it's streamed
+///
+/// Every response is wrapped in [ChatStreamDisplay::start] and [ChatStreamDisplay::finish]
+#[async_trait]
+pub trait ChatStreamDisplay: Send {
+    /// new response is about to be streamed
+    async fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn push_message(&mut self, text: &str) -> Result<()>;
+
+    /// response is complete, also called when it was cancelled or failed
+    async fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+enum SinkMessage {
+    Start,
+    Text(String),
+    Finish,
+    Flush(oneshot::Sender<()>),
+}
+
+/// Streamed responses fanned out to any number of [ChatStreamDisplay]s
+///
+/// Every display runs in its own task with its own queue
+/// so a slow display doesn't hold back the others or the terminal.
+/// A display that fails is skipped until the next response starts
+#[derive(Default)]
+pub struct ChatStreamSinks {
+    senders: Vec<mpsc::UnboundedSender<SinkMessage>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ChatStreamSinks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start task for display, has to be called inside tokio runtime
+    pub fn add(&mut self, display: impl ChatStreamDisplay + 'static) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.senders.push(sender);
+        self.tasks
+            .push(tokio::spawn(run_display(Box::new(display), receiver)));
+    }
+
+    pub fn with(mut self, display: impl ChatStreamDisplay + 'static) -> Self {
+        self.add(display);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    /// Start response, it's finished when the returned guard is dropped
+    pub fn start_response(&self) -> ResponseGuard<'_> {
+        self.send(|| SinkMessage::Start);
+        ResponseGuard { sinks: self }
+    }
+
+    pub fn push(&self, text: &str) {
+        self.send(|| SinkMessage::Text(text.to_owned()));
+    }
+
+    /// Wait until every display handled everything sent so far
+    pub async fn flush(&self) {
+        let mut pending = vec![];
+        for sender in &self.senders {
+            let (done_sender, done_receiver) = oneshot::channel();
+            if sender.send(SinkMessage::Flush(done_sender)).is_ok() {
+                pending.push(done_receiver);
+            }
+        }
+        for done_receiver in pending {
+            _ = done_receiver.await;
+        }
+    }
+
+    /// Wait for displays to handle everything sent so far and stop them
+    pub async fn close(self) {
+        drop(self.senders);
+        for task in self.tasks {
+            _ = task.await;
+        }
+    }
+
+    fn send(&self, message: impl Fn() -> SinkMessage) {
+        for sender in &self.senders {
+            // task only stops when display panicked
+            _ = sender.send(message());
+        }
+    }
+}
+
+/// Finishes response on every exit path
+pub struct ResponseGuard<'a> {
+    sinks: &'a ChatStreamSinks,
+}
+
+impl Drop for ResponseGuard<'_> {
+    fn drop(&mut self) {
+        self.sinks.send(|| SinkMessage::Finish);
+    }
+}
+
+async fn run_display(
+    mut display: Box<dyn ChatStreamDisplay>,
+    mut receiver: mpsc::UnboundedReceiver<SinkMessage>,
+) {
+    let mut failed = false;
+    while let Some(message) = receiver.recv().await {
+        let result = match message {
+            SinkMessage::Start => {
+                failed = false;
+                display.start().await
+            }
+            SinkMessage::Flush(done) => {
+                _ = done.send(());
+                continue;
+            }
+            _ if failed => continue,
+            SinkMessage::Text(text) => display.push_message(&text).await,
+            SinkMessage::Finish => display.finish().await,
+        };
+        if let Err(error) = result {
+            warn!(
+                "Stream display failed, skipping rest of response {:?}",
+                error
+            );
+            failed = true;
+        }
+    }
+}
+
+/// Appends every response to a file
+pub struct FileTeeDisplay {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl FileTeeDisplay {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            file: None,
+        }
+    }
+
+    fn file(&mut self) -> Result<&mut File> {
+        if self.file.is_none() {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .with_context(|| format!("Failed to open {}", self.path.display()))?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().expect("File was just opened"))
+    }
+}
+
+#[async_trait]
+impl ChatStreamDisplay for FileTeeDisplay {
+    async fn push_message(&mut self, text: &str) -> Result<()> {
+        self.file()?.write_all(text.as_bytes())?;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.write_all(b"\n")?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Passes whole sentences to the wrapped display, for example for text to speech
+pub struct SentenceChunker<D> {
+    inner: D,
+    buffer: String,
+}
+
+impl<D: ChatStreamDisplay> SentenceChunker<D> {
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            buffer: String::new(),
+        }
+    }
+
+    async fn push_sentence(&mut self, sentence: &str) -> Result<()> {
+        let sentence = sentence.trim();
+        if sentence.is_empty() {
+            return Ok(());
+        }
+        self.inner.push_message(sentence).await
+    }
+}
+
+/// Byte index after the first sentence in `text`
+///
+/// Sentence ends with a line break or with `.`, `!` or `?` followed by whitespace.
+/// Returns None while the end might still be coming
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\n' => return Some(index + 1),
+            '.' | '!' | '?' => {
+                if let Some((next_index, next)) = chars.peek() {
+                    if next.is_whitespace() {
+                        return Some(*next_index);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+#[async_trait]
+impl<D: ChatStreamDisplay> ChatStreamDisplay for SentenceChunker<D> {
+    async fn start(&mut self) -> Result<()> {
+        self.buffer.clear();
+        self.inner.start().await
+    }
+
+    async fn push_message(&mut self, text: &str) -> Result<()> {
+        self.buffer.push_str(text);
+        while let Some(end) = sentence_end(&self.buffer) {
+            let sentence: String = self.buffer.drain(..end).collect();
+            self.push_sentence(&sentence).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        let rest = std::mem::take(&mut self.buffer);
+        self.push_sentence(&rest).await?;
+        self.inner.finish().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingDisplay {
+        messages: Vec<String>,
+    }
+
+    #[async_trait]
+    impl ChatStreamDisplay for RecordingDisplay {
+        async fn push_message(&mut self, text: &str) -> Result<()> {
+            self.messages.push(text.to_owned());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn chunker_passes_whole_sentences() {
+        let mut chunker = SentenceChunker::new(RecordingDisplay::default());
+        chunker.start().await.unwrap();
+        for token in ["Lights", " are on.", " It costs 3.5", "€! Done?", "\nBye"] {
+            chunker.push_message(token).await.unwrap();
+        }
+        assert_eq!(
+            chunker.inner.messages,
+            vec!["Lights are on.", "It costs 3.5€!", "Done?"]
+        );
+        chunker.finish().await.unwrap();
+        assert_eq!(chunker.inner.messages.last().unwrap(), "Bye");
+    }
+}
@@ -0,0 +1,248 @@
+use crate::{message_tree::MessageId, models::ModelInfo};
+use anyhow::Result;
+use async_openai::types::ChatCompletionRequestMessage;
+use std::{collections::HashMap, sync::Mutex};
+use tiktoken_rs::CoreBPE;
+
+/// How the API counts tokens of chat messages on top of their content
+///
+/// Every message follows `<|start|>{role/name}\n{content}<|end|>\n`
+/// and the reply is primed with `<|start|>assistant<|message|>`.
+/// Based on <https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenCountingRules {
+    /// added for every message
+    pub tokens_per_message: i64,
+    /// added when message has a name
+    pub tokens_per_name: i64,
+    /// added once per prompt for the start of the reply
+    pub reply_priming: i64,
+}
+
+impl TokenCountingRules {
+    pub fn for_model(model_name: &str) -> Self {
+        if model_name == "gpt-3.5-turbo-0301" {
+            // name replaces the role
+            Self {
+                tokens_per_message: 4,
+                tokens_per_name: -1,
+                reply_priming: 3,
+            }
+        } else {
+            Self {
+                tokens_per_message: 3,
+                tokens_per_name: 1,
+                reply_priming: 3,
+            }
+        }
+    }
+}
+
+/// Counts tokens the way the API does for a given model
+///
+/// Prompt counts match `prompt_tokens` reported by the API, tests list the known values
+#[derive(Clone, Copy)]
+pub struct TokenCounter {
+    bpe: &'static CoreBPE,
+    rules: TokenCountingRules,
+}
+
+impl TokenCounter {
+    pub fn for_model(model: &ModelInfo) -> Result<Self> {
+        Ok(Self {
+            bpe: model.tokenizer.shared()?,
+            rules: TokenCountingRules::for_model(&model.name),
+        })
+    }
+
+    pub fn rules(&self) -> TokenCountingRules {
+        self.rules
+    }
+
+    /// Tokens of plain text such as a response
+    pub fn count_text(&self, text: &str) -> i64 {
+        self.bpe.encode_with_special_tokens(text).len() as i64
+    }
+
+    /// Tokens of single message including per message overhead
+    pub fn count_message(&self, message: &ChatCompletionRequestMessage) -> i64 {
+        let mut token_count = self.rules.tokens_per_message
+            + self.count_text(&message.role.to_string())
+            + self.count_text(&message.content);
+        if let Some(name) = &message.name {
+            token_count += self.count_text(name) + self.rules.tokens_per_name;
+        }
+        token_count
+    }
+
+    /// Tokens of prompt made of `messages`
+    pub fn count_prompt<'a>(
+        &self,
+        messages: impl Iterator<Item = &'a ChatCompletionRequestMessage>,
+    ) -> i64 {
+        self.rules.reply_priming
+            + messages
+                .map(|message| self.count_message(message))
+                .sum::<i64>()
+    }
+}
+
+/// Token counts of messages in a conversation
+///
+/// Messages never change their content without going through [MessageTokenCache::invalidate]
+/// so only new messages are encoded when the history grows
+#[derive(Debug, Default)]
+pub struct MessageTokenCache {
+    counts: Mutex<HashMap<MessageId, i64>>,
+}
+
+impl MessageTokenCache {
+    pub fn get_or_count(
+        &self,
+        id: MessageId,
+        message: &ChatCompletionRequestMessage,
+        counter: &TokenCounter,
+    ) -> i64 {
+        *self
+            .counts
+            .lock()
+            .expect("Token cache lock poisoned")
+            .entry(id)
+            .or_insert_with(|| counter.count_message(message))
+    }
+
+    /// Forget message that was changed or removed
+    pub fn invalidate(&self, id: MessageId) {
+        self.counts
+            .lock()
+            .expect("Token cache lock poisoned")
+            .remove(&id);
+    }
+
+    /// Forget all messages, needed when model changes
+    pub fn clear(&self) {
+        self.counts
+            .lock()
+            .expect("Token cache lock poisoned")
+            .clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ModelRegistry;
+    use async_openai::types::Role;
+
+    fn message(role: Role, name: Option<&str>, content: &str) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestMessage {
+            role,
+            content: content.to_owned(),
+            name: name.map(|name| name.to_owned()),
+        }
+    }
+
+    fn counter(model_name: &str) -> TokenCounter {
+        TokenCounter::for_model(ModelRegistry::builtin().get(model_name).unwrap()).unwrap()
+    }
+
+    /// Example from the OpenAI cookbook, uses `name` on most messages
+    fn cookbook_conversation() -> Vec<ChatCompletionRequestMessage> {
+        vec![
+            message(Role::System, None, "You are a helpful, pattern-following assistant that translates corporate jargon into plain English."),
+            message(Role::System, Some("example_user"), "New synergies will help drive top-line growth."),
+            message(Role::System, Some("example_assistant"), "Things working well together will increase revenue."),
+            message(Role::System, Some("example_user"), "Let's circle back when we have more bandwidth to touch base on opportunities for increased leverage."),
+            message(Role::System, Some("example_assistant"), "Let's talk later when we're less busy about how to do better."),
+            message(Role::User, None, "This late pivot means we don't have time to boil the ocean for the client deliverable."),
+        ]
+    }
+
+    #[test]
+    fn prompt_tokens_match_api_usage() {
+        let hello = vec![message(Role::User, None, "Hello!")];
+        let assistant_hello = vec![
+            message(Role::System, None, "You are a helpful assistant."),
+            message(Role::User, None, "Hello!"),
+        ];
+        // prompt_tokens reported by the API
+        let known_usage = [
+            ("gpt-3.5-turbo", &hello, 9),
+            ("gpt-4", &hello, 9),
+            ("gpt-3.5-turbo", &assistant_hello, 19),
+            ("gpt-4", &assistant_hello, 19),
+            ("gpt-3.5-turbo-0301", &cookbook_conversation(), 127),
+            ("gpt-3.5-turbo", &cookbook_conversation(), 129),
+            ("gpt-4-0314", &cookbook_conversation(), 129),
+            ("gpt-4", &cookbook_conversation(), 129),
+        ];
+        for (model_name, messages, prompt_tokens) in known_usage {
+            assert_eq!(
+                counter(model_name).count_prompt(messages.iter()),
+                prompt_tokens,
+                "{model_name} {messages:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn name_replaces_role_on_gpt_3_5_turbo_0301() {
+        let without_name = message(
+            Role::System,
+            None,
+            "New synergies will help drive top-line growth.",
+        );
+        let with_name = message(
+            Role::System,
+            Some("example_user"),
+            "New synergies will help drive top-line growth.",
+        );
+        let name_tokens = counter("gpt-4").count_text("example_user");
+
+        // role is still counted, name costs its tokens minus one
+        let old = counter("gpt-3.5-turbo-0301");
+        assert_eq!(
+            old.count_message(&with_name) - old.count_message(&without_name),
+            name_tokens - 1
+        );
+
+        // name costs its tokens plus one
+        let new = counter("gpt-3.5-turbo");
+        assert_eq!(
+            new.count_message(&with_name) - new.count_message(&without_name),
+            name_tokens + 1
+        );
+    }
+
+    #[test]
+    fn per_message_overhead_depends_on_model() {
+        let hello = message(Role::User, None, "Hello!");
+        assert_eq!(
+            counter("gpt-3.5-turbo-0301").count_message(&hello)
+                - counter("gpt-3.5-turbo").count_message(&hello),
+            1
+        );
+        assert_eq!(counter("gpt-3.5-turbo").count_prompt(std::iter::empty()), 3);
+    }
+
+    #[test]
+    fn cache_counts_message_once_until_invalidated() {
+        let counter = counter("gpt-3.5-turbo");
+        let cache = MessageTokenCache::default();
+        let short = message(Role::User, None, "Hello!");
+        let long = message(Role::User, None, "Hello there, how are you doing today?");
+
+        let short_tokens = cache.get_or_count(1, &short, &counter);
+        // same id is not encoded again
+        assert_eq!(cache.get_or_count(1, &long, &counter), short_tokens);
+
+        cache.invalidate(1);
+        assert_eq!(
+            cache.get_or_count(1, &long, &counter),
+            counter.count_message(&long)
+        );
+
+        cache.clear();
+        assert_eq!(cache.get_or_count(1, &short, &counter), short_tokens);
+    }
+}
@@ -0,0 +1,290 @@
+use anyhow::{Context, Result};
+use async_openai::types::{ChatCompletionRequestMessage, Role};
+use futures::{future::BoxFuture, FutureExt};
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{future::Future, sync::Arc};
+
+/// Fence used by the model to mark a tool call
+const TOOL_CALL_FENCE: &str = "```tool_call";
+
+/// Prefix of messages that carry tool results back to the model
+pub const TOOL_RESULT_PREFIX: &str = "TOOL_RESULT";
+
+/// Name of messages that carry tool results, tells them apart from system prompts
+pub const TOOL_RESULT_NAME: &str = "tool_result";
+
+type ToolHandler =
+    dyn Fn(serde_json::Value) -> BoxFuture<'static, Result<serde_json::Value>> + Send + Sync;
+
+/// Local function that the model can ask to run
+///
+/// The pinned OpenAI client predates native function calling so tools are described
+/// to the model in a system message and calls are exchanged as fenced JSON
+#[derive(Clone)]
+pub struct Tool {
+    name: String,
+    description: String,
+    parameters: RootSchema,
+    handler: Arc<ToolHandler>,
+}
+
+impl std::fmt::Debug for Tool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tool")
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .finish()
+    }
+}
+
+impl Tool {
+    /// Create tool with arguments described by `Args`
+    ///
+    /// Arguments sent by the model are deserialized into `Args` before calling `handler`
+    pub fn new<Args, F, Fut>(name: &str, description: &str, handler: F) -> Self
+    where
+        Args: JsonSchema + DeserializeOwned,
+        F: Fn(Args) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<serde_json::Value>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let name_owned = name.to_owned();
+        Self {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            parameters: schema_for!(Args),
+            handler: Arc::new(move |arguments: serde_json::Value| {
+                let handler = handler.clone();
+                let name = name_owned.clone();
+                async move {
+                    let arguments: Args = serde_json::from_value(arguments)
+                        .with_context(|| format!("Invalid arguments for tool {name}"))?;
+                    (*handler)(arguments).await
+                }
+                .boxed()
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn parameters(&self) -> &RootSchema {
+        &self.parameters
+    }
+
+    pub async fn call(&self, arguments: serde_json::Value) -> Result<serde_json::Value> {
+        (self.handler)(arguments).await
+    }
+}
+
+/// Tool call requested by the model
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ToolCall {
+    pub tool: String,
+    #[serde(default)]
+    pub arguments: serde_json::Value,
+}
+
+impl ToolCall {
+    /// Find fenced `tool_call` block in model response
+    ///
+    /// Plain json answers are never treated as calls
+    pub fn parse(content: &str) -> Option<Self> {
+        let start = content.find(TOOL_CALL_FENCE)?;
+        let body = &content[start + TOOL_CALL_FENCE.len()..];
+        let body = body.split("```").next().unwrap_or_default();
+        serde_json::from_str(body.trim()).ok()
+    }
+}
+
+/// Run tool call against the registered tools
+///
+/// Failures are reported as json so that the model can recover from them
+pub async fn run_tool_call(tools: &[Tool], call: &ToolCall) -> serde_json::Value {
+    let Some(tool) = tools.iter().find(|tool| tool.name == call.tool) else {
+        return serde_json::json!({ "error": format!("Unknown tool {}", call.tool) });
+    };
+    match tool.call(call.arguments.clone()).await {
+        Ok(result) => result,
+        Err(error) => serde_json::json!({ "error": format!("{error:#}") }),
+    }
+}
+
+/// Message carrying tool result back to the model
+pub fn tool_result_message(
+    call: &ToolCall,
+    result: &serde_json::Value,
+) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestMessage {
+        role: Role::System,
+        content: format!("{TOOL_RESULT_PREFIX} {}:\n{result}", call.tool),
+        name: Some(TOOL_RESULT_NAME.to_owned()),
+    }
+}
+
+/// Message was added by [tool_result_message]
+pub fn is_tool_result(message: &ChatCompletionRequestMessage) -> bool {
+    message.role == Role::System && message.name.as_deref() == Some(TOOL_RESULT_NAME)
+}
+
+/// Holds back streamed response text that might be a tool call
+///
+/// Text before a `tool_call` fence is passed through,
+/// everything after it is held until the response is complete
+#[derive(Debug, Default)]
+pub struct ToolCallFilter {
+    pending: String,
+    holding: bool,
+}
+
+impl ToolCallFilter {
+    /// Part of response that can be shown once `delta` arrived
+    pub fn push(&mut self, delta: &str) -> String {
+        self.pending.push_str(delta);
+        if self.holding {
+            return String::new();
+        }
+        let shown_length = match self.pending.find(TOOL_CALL_FENCE) {
+            Some(start) => {
+                self.holding = true;
+                start
+            }
+            // end of text might be the beginning of a fence
+            None => (1..TOOL_CALL_FENCE.len())
+                .rev()
+                .find(|length| self.pending.ends_with(&TOOL_CALL_FENCE[..*length]))
+                .map(|length| self.pending.len() - length)
+                .unwrap_or(self.pending.len()),
+        };
+        self.pending.drain(..shown_length).collect()
+    }
+
+    /// Rest of held back text once the response is complete, nothing when it was a tool call
+    pub fn finish(&mut self, is_tool_call: bool) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        if is_tool_call {
+            String::new()
+        } else {
+            rest
+        }
+    }
+}
+
+/// System instructions describing available tools
+pub fn tool_instructions(tools: &[Tool]) -> Result<String> {
+    let mut instructions = format!(
+        "You can call the following tools. To call a tool respond with only a {TOOL_CALL_FENCE} \
+code block containing json like {{\"tool\": \"<name>\", \"arguments\": {{...}}}}.
+Tool results will be sent back in a message starting with {TOOL_RESULT_PREFIX}. \
+Once you have everything you need answer the user normally.
+Tools:"
+    );
+    for tool in tools {
+        let schema = serde_json::to_string(&tool.parameters)?;
+        instructions.push_str(&format!(
+            "\n- {}: {}\n  arguments schema: {}",
+            tool.name, tool.description, schema
+        ));
+    }
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_fenced_call() {
+        let content = "Let me check.\n```tool_call\n{\"tool\": \"get_time\", \"arguments\": {\"zone\": \"UTC\"}}\n```\n";
+        assert_eq!(
+            ToolCall::parse(content),
+            Some(ToolCall {
+                tool: String::from("get_time"),
+                arguments: json!({"zone": "UTC"}),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_fenced_call_without_arguments() {
+        assert_eq!(
+            ToolCall::parse("```tool_call\n{\"tool\": \"get_time\"}\n```"),
+            Some(ToolCall {
+                tool: String::from("get_time"),
+                arguments: serde_json::Value::Null,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_rejects_plain_answers() {
+        assert_eq!(ToolCall::parse("It is noon."), None);
+        assert_eq!(ToolCall::parse("{not json}"), None);
+        assert_eq!(ToolCall::parse("{\"answer\": 42}"), None);
+        assert_eq!(ToolCall::parse("{\"tool\": \"get_time\"}"), None);
+        assert_eq!(ToolCall::parse("```tool_call\n{\"tool\": \n```"), None);
+        assert_eq!(ToolCall::parse("```json\n{\"answer\": 42}\n```"), None);
+    }
+
+    fn filter_all(deltas: &[&str], is_tool_call: bool) -> String {
+        let mut filter = ToolCallFilter::default();
+        let mut shown: String = deltas.iter().map(|delta| filter.push(delta)).collect();
+        shown.push_str(&filter.finish(is_tool_call));
+        shown
+    }
+
+    #[test]
+    fn filter_passes_plain_text() {
+        assert_eq!(
+            filter_all(&["Hello ", "there, use ``", "code``."], false),
+            "Hello there, use ``code``."
+        );
+    }
+
+    #[test]
+    fn filter_hides_fenced_call_split_across_deltas() {
+        let deltas = [
+            "Checking.\n``",
+            "`tool",
+            "_call\n{\"tool\": ",
+            "\"get_time\"}\n```",
+        ];
+        let mut filter = ToolCallFilter::default();
+        let shown: String = deltas.iter().map(|delta| filter.push(delta)).collect();
+        assert_eq!(shown, "Checking.\n");
+        assert_eq!(filter.finish(true), "");
+    }
+
+    #[test]
+    fn filter_passes_json_answers() {
+        let mut filter = ToolCallFilter::default();
+        assert_eq!(filter.push("{\"tool\""), "{\"tool\"");
+        assert_eq!(filter.push(": \"get_time\"}"), ": \"get_time\"}");
+        assert_eq!(filter.finish(false), "");
+    }
+
+    #[test]
+    fn tool_result_is_marked() {
+        let call = ToolCall {
+            tool: String::from("get_time"),
+            arguments: json!({}),
+        };
+        let message = tool_result_message(&call, &json!({"time": "noon"}));
+        assert!(is_tool_result(&message));
+        assert!(message.content.starts_with(TOOL_RESULT_PREFIX));
+        let system_prompt = ChatCompletionRequestMessage {
+            role: Role::System,
+            content: String::from("You are a test"),
+            name: None,
+        };
+        assert!(!is_tool_result(&system_prompt));
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{configuration::get_project_dirs, models::ModelInfo};
+use anyhow::{Context, Result};
+use chrono::{
+    prelude::{DateTime, Local},
+    Duration, NaiveDate,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+const LEDGER_FILE_NAME: &str = "usage.jsonl";
+
+/// Whisper price in dollars
+pub const WHISPER_PRICE_PER_MINUTE: f64 = 0.006;
+
+/// Single API request recorded in the [UsageLedger]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UsageEntry {
+    pub timestamp: DateTime<Local>,
+    /// binary that made the request
+    pub binary: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<String>,
+    pub model: String,
+    #[serde(default)]
+    pub prompt_tokens: u32,
+    #[serde(default)]
+    pub completion_tokens: u32,
+    /// tokens were counted locally because the API didn't report usage
+    #[serde(default)]
+    pub estimated: bool,
+    /// length of transcribed audio
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_seconds: Option<f64>,
+    /// cost in dollars
+    pub cost: f64,
+}
+
+impl UsageEntry {
+    pub fn chat(
+        model: &ModelInfo,
+        conversation_id: Option<String>,
+        prompt_tokens: u32,
+        completion_tokens: u32,
+        estimated: bool,
+    ) -> Self {
+        Self {
+            timestamp: Local::now(),
+            binary: binary_name(),
+            conversation_id,
+            model: model.name.clone(),
+            prompt_tokens,
+            completion_tokens,
+            estimated,
+            audio_seconds: None,
+            cost: model.cost(prompt_tokens, completion_tokens),
+        }
+    }
+
+    pub fn transcription(model: &str, audio_seconds: f64) -> Self {
+        Self {
+            timestamp: Local::now(),
+            binary: binary_name(),
+            conversation_id: None,
+            model: model.to_owned(),
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            estimated: false,
+            audio_seconds: Some(audio_seconds),
+            cost: audio_seconds / 60.0 * WHISPER_PRICE_PER_MINUTE,
+        }
+    }
+}
+
+/// Name of the running binary
+pub fn binary_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|path| {
+            path.file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+/// Append only log of API usage shared by all binaries
+///
+/// Stored as JSON lines in the user data directory
+#[derive(Debug, Clone)]
+pub struct UsageLedger {
+    path: PathBuf,
+}
+
+impl UsageLedger {
+    pub fn open(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+        }
+    }
+
+    pub fn open_default() -> Result<Self> {
+        let project_dirs = get_project_dirs()?;
+        let data_dir = project_dirs.data_dir();
+        std::fs::create_dir_all(data_dir).context("failed to create user data directory")?;
+        Ok(Self::open(&data_dir.join(LEDGER_FILE_NAME)))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, entry: &UsageEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open usage ledger {}", self.path.display()))?;
+        // single write so that lines from parallel processes don't interleave
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Record Whisper transcription of WAV file
+    ///
+    /// Failures are only logged so that they don't interrupt the conversation
+    #[cfg(feature = "audio")]
+    pub fn record_transcription(&self, model: &str, audio_path: &Path) {
+        let result = wav_duration_seconds(audio_path)
+            .and_then(|seconds| self.record(&UsageEntry::transcription(model, seconds)));
+        if let Err(error) = result {
+            warn!("Failed to record transcription usage {:?}", error);
+        }
+    }
+
+    /// Entries recorded after `since`
+    ///
+    /// Lines that can't be parsed are skipped
+    pub fn entries_since(&self, since: DateTime<Local>) -> Result<Vec<UsageEntry>> {
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => return Err(error.into()),
+        };
+        let mut entries = vec![];
+        for (line_number, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<UsageEntry>(&line) {
+                Ok(entry) if entry.timestamp >= since => entries.push(entry),
+                Ok(_) => (),
+                Err(error) => warn!(
+                    "Skipping invalid usage ledger line {}: {}",
+                    line_number + 1,
+                    error
+                ),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Totals for one day and model
+#[derive(Debug, Clone, Default)]
+pub struct UsageTotals {
+    pub requests: u32,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub audio_seconds: f64,
+    pub cost: f64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, entry: &UsageEntry) {
+        self.requests += 1;
+        self.prompt_tokens += entry.prompt_tokens as u64;
+        self.completion_tokens += entry.completion_tokens as u64;
+        self.audio_seconds += entry.audio_seconds.unwrap_or_default();
+        self.cost += entry.cost;
+    }
+}
+
+/// Sum entries per day and model
+pub fn totals_per_day_and_model(
+    entries: &[UsageEntry],
+) -> BTreeMap<(NaiveDate, String), UsageTotals> {
+    let mut totals: BTreeMap<(NaiveDate, String), UsageTotals> = BTreeMap::new();
+    for entry in entries {
+        totals
+            .entry((entry.timestamp.date_naive(), entry.model.clone()))
+            .or_default()
+            .add(entry);
+    }
+    totals
+}
+
+/// Parse durations like `30m`, `12h`, `7d` or `2w`
+pub fn parse_duration(text: &str) -> Result<Duration> {
+    let text = text.trim();
+    let unit_start = text
+        .find(|c: char| !c.is_ascii_digit())
+        .context("Duration is missing unit, use m, h, d or w")?;
+    let value: i64 = text[..unit_start]
+        .parse()
+        .with_context(|| format!("Invalid duration \"{text}\""))?;
+    match &text[unit_start..] {
+        "m" => Ok(Duration::minutes(value)),
+        "h" => Ok(Duration::hours(value)),
+        "d" => Ok(Duration::days(value)),
+        "w" => Ok(Duration::weeks(value)),
+        unit => anyhow::bail!("Unknown duration unit \"{unit}\", use m, h, d or w"),
+    }
+}
+
+/// Length of WAV recording used to price transcriptions
+#[cfg(feature = "audio")]
+pub fn wav_duration_seconds(path: &Path) -> Result<f64> {
+    let reader = hound::WavReader::open(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(reader.duration() as f64 / reader.spec().sample_rate as f64)
+}